@@ -1,225 +1,2817 @@
 use futures::future::join_all;
-use quinn::Endpoint;
-use std::{collections::HashMap, error::Error, sync::Arc};
-use tokio::sync::Mutex;
-use tracing::{info, warn};
+use libp2p::PeerId;
+use quinn::{Endpoint, Incoming};
+use serde::Serialize;
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::{BinaryHeap, HashMap, HashSet},
+    error::Error,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, RwLock as StdRwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use thiserror::Error;
+use tokio::{
+    sync::{broadcast::error::RecvError, Mutex, Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, info, info_span, warn, Instrument};
 
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjector;
 use crate::{
-    connection::{open_receiver_endpoint, open_sender_endpoint, receive, send},
-    deal::{Deal, BYTES_PER_MEBIBYTE},
-    discovery::DiscoveryService,
-    peer_info::PeerInfo,
+    connection::{
+        client_config, next_incoming, open_receiver_endpoint, open_sender_endpoint,
+        read_deal_within, receive_error_kind, send, server_config, Encoding, EndpointLimits,
+    },
+    deal::{Deal, DecodeLimits, Priority, BYTES_PER_MEBIBYTE},
+    discovery::{Discovery, DiscoveryEvent, DiscoveryService, DiscoveryStats},
+    market::{Demand, PriceHistory},
+    metrics::{
+        DeliveryRecord, ErrorCounters, ErrorCounts, ErrorKind, RejectionCounts, TransportStats,
+    },
+    overrides::{PeerOverride, PeerOverrides, Trust},
+    peer_info::{Features, PeerInfo},
+    placement::{optimize, plan, priced, unit_deal, Constraints, Placement, PlacementError, Plan},
+    plugin::{ApprovalHook, Event, EventSink, MatchPolicy},
+    pricing::PricingStrategy,
+    recorder::{Direction, Message, Record, ReplayStats, TrafficRecorder},
 };
 
-pub struct Agent {
-    discovery: Arc<DiscoveryService>,
-    receiver_endpoint: Endpoint,
+/// Matches, sends and receives deals over QUIC, finding peers through `D`:
+/// the multicast [`DiscoveryService`] unless another [`Discovery`] backend
+/// is given.
+pub struct Agent<D: Discovery = DiscoveryService> {
+    discovery: Arc<D>,
+    /// from the discovery backend, shared so announcements and deals land
+    /// in one recording
+    recorder: Option<Arc<TrafficRecorder>>,
+    #[cfg(feature = "chaos")]
+    faults: Arc<FaultInjector>,
+    /// swapped out by the watchdog if the endpoint dies; `None` while the
+    /// replacement is being bound
+    receiver_endpoint: StdMutex<Option<Endpoint>>,
+    /// address the receiver endpoint is bound to, port resolved
+    listen_addr: SocketAddr,
     sender_endpoint: Endpoint,
-    incoming_deals: Arc<Mutex<HashMap<String, Deal>>>,
+    endpoint_limits: EndpointLimits,
+    /// encoding offered when sending deals
+    encoding: Encoding,
+    /// one permit per deal connection open across all peers
+    outgoing: Arc<Semaphore>,
+    /// received deals with their arrival time, dropped after `proposal_ttl`
+    incoming_deals: Arc<Mutex<HashMap<String, (Deal, Instant)>>>,
+    proposal_ttl: Duration,
+    decode_limits: DecodeLimits,
+    expired_deals: AtomicU64,
+    suspends: AtomicU64,
+    /// smoothed ingest throughput per peer, in bytes per second
+    peer_throughput: Mutex<HashMap<PeerId, f64>>,
+    /// QUIC path stats from the last deal delivered to each peer
+    peer_transport: Arc<Mutex<HashMap<PeerId, TransportStats>>>,
+    /// delivered and failed deal sends per peer, for [`Agent::plan_placement`]
+    peer_delivery: Arc<Mutex<HashMap<PeerId, DeliveryRecord>>>,
+    errors: Arc<ErrorCounters>,
+    accept_filter: StdRwLock<AcceptFilter>,
+    /// add vetted proposers to the peer table, see
+    /// [`Agent::with_proposer_backfill`]
+    backfill_proposers: bool,
+    /// operator settings per peer, ahead of the agent's own judgement
+    peer_overrides: StdRwLock<PeerOverrides>,
+    refused_connections: AtomicU64,
+    /// proposals waiting to be placed and sent, see [`Agent::queue_deal`]
+    send_queue: StdMutex<BinaryHeap<QueuedDeal>>,
+    queue_seq: AtomicU64,
+    queue_ready: Notify,
+    send_limits: SendLimits,
+    /// stream and byte permits for deals being delivered to each peer
+    send_slots: StdMutex<HashMap<PeerId, Arc<SendSlots>>>,
+    send_overflows: Arc<AtomicU64>,
+    /// advertised and received deal prices, and proposal counts, over the
+    /// last week
+    market: StdMutex<PriceHistory>,
+    /// reprices our offer every `REPRICE_INTERVAL`, if set
+    pricing: Option<Box<dyn PricingStrategy>>,
+    match_policies: Vec<Box<dyn MatchPolicy>>,
+    approval_hooks: Vec<Box<dyn ApprovalHook>>,
+    event_sinks: Arc<Vec<Box<dyn EventSink>>>,
+    /// set by `shutdown` so the watchdog stops rebuilding components
+    shutting_down: AtomicBool,
+    /// wakes tasks waiting on discovery events at shutdown
+    stopped: Notify,
+}
+
+/// What to do with a deal when its peer is already at its [`SendLimits`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// wait for an earlier send to the peer to finish
+    #[default]
+    Queue,
+    /// give up on the peer right away, counted in `Health::send_overflows`
+    FailFast,
+}
+
+/// Caps on deals being delivered to a single peer at once, so a slow
+/// provider can't tie up unbounded streams and buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendLimits {
+    pub max_streams_per_peer: usize,
+    /// encoded deal bytes in flight
+    pub max_bytes_per_peer: u32,
+    pub overflow: Overflow,
+}
+
+impl Default for SendLimits {
+    fn default() -> Self {
+        SendLimits {
+            max_streams_per_peer: 4,
+            max_bytes_per_peer: 64 * 1024,
+            overflow: Overflow::Queue,
+        }
+    }
+}
+
+struct SendSlots {
+    streams: Arc<Semaphore>,
+    bytes: Arc<Semaphore>,
+    /// capacity of `bytes`, so a single oversized deal can still go alone
+    max_bytes: u32,
+}
+
+impl SendSlots {
+    fn new(limits: &SendLimits) -> Self {
+        SendSlots {
+            streams: Arc::new(Semaphore::new(limits.max_streams_per_peer)),
+            bytes: Arc::new(Semaphore::new(limits.max_bytes_per_peer as usize)),
+            max_bytes: limits.max_bytes_per_peer,
+        }
+    }
+
+    /// permits to send a deal of `bytes` encoded bytes, or `None` if the peer
+    /// is full and `overflow` says not to wait
+    async fn acquire(
+        &self,
+        bytes: u32,
+        overflow: Overflow,
+    ) -> Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)> {
+        let bytes = bytes.min(self.max_bytes);
+        match overflow {
+            Overflow::Queue => {
+                let stream = self.streams.clone().acquire_owned().await.ok()?;
+                let bytes = self.bytes.clone().acquire_many_owned(bytes).await.ok()?;
+                Some((stream, bytes))
+            }
+            Overflow::FailFast => {
+                let stream = self.streams.clone().try_acquire_owned().ok()?;
+                let bytes = self.bytes.clone().try_acquire_many_owned(bytes).ok()?;
+                Some((stream, bytes))
+            }
+        }
+    }
+}
+
+/// Which hosts may open deal connections. The default accepts anyone;
+/// otherwise a connection is accepted if its source IP is allowlisted or,
+/// with `known_peers`, belongs to a peer in the discovery table. Checked
+/// before the QUIC handshake, so strangers never reach the deal parser.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptFilter {
+    pub known_peers: bool,
+    pub allowlist: HashSet<IpAddr>,
+}
+
+impl AcceptFilter {
+    fn is_open(&self) -> bool {
+        !self.known_peers && self.allowlist.is_empty()
+    }
+}
+
+/// Why a peer was passed over for a deal, see [`Agent::explain_match`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    /// the peer offers no space of the requested storage class
+    NoOffer,
+    /// bytes the peer has (spare or burst) versus bytes the deal needs
+    Capacity { available: u64, needed: u64 },
+    /// per-MiB price the peer asks versus what the deal offers
+    Price { asking: f32, offered: f32 },
+    /// ingest time estimated from the peer's measured throughput
+    Deadline {
+        estimated_secs: f64,
+        deadline_secs: u64,
+    },
+    /// features the deal needs that the peer doesn't announce
+    Features { missing: Features },
+    /// reason given by a [`MatchPolicy`]
+    Policy(String),
+    /// the operator blocked the peer, see [`Agent::set_peer_override`]
+    Blocked,
+    /// the deal share is over the operator's cap for the peer
+    OverQuota { max_deal_bytes: u64, needed: u64 },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::NoOffer => write!(f, "no offer for the storage class"),
+            Mismatch::Capacity { available, needed } => {
+                write!(f, "has {available} bytes, deal needs {needed}")
+            }
+            Mismatch::Price { asking, offered } => {
+                write!(f, "asks {asking} per MiB, deal offers {offered}")
+            }
+            Mismatch::Deadline {
+                estimated_secs,
+                deadline_secs,
+            } => write!(
+                f,
+                "needs ~{estimated_secs:.0}s to ingest, deadline is {deadline_secs}s"
+            ),
+            Mismatch::Features { missing } => write!(f, "missing features {:#x}", missing.0),
+            Mismatch::Policy(reason) => f.write_str(reason),
+            Mismatch::Blocked => f.write_str("blocked by operator"),
+            Mismatch::OverQuota {
+                max_deal_bytes,
+                needed,
+            } => write!(
+                f,
+                "capped at {max_deal_bytes} bytes per deal, deal needs {needed}"
+            ),
+        }
+    }
+}
+
+/// One known peer checked against a deal by [`Agent::explain_match`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchExplanation {
+    pub peer_id: PeerId,
+    pub addr: SocketAddr,
+    /// every reason the peer was excluded; empty if it matches
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl MatchExplanation {
+    pub fn matches(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// A proposal in the send queue: highest priority first, then first in.
+struct QueuedDeal {
+    priority: Priority,
+    seq: u64,
+    deal: Deal,
+}
+
+impl Ord for QueuedDeal {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedDeal {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedDeal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for QueuedDeal {}
+
+/// Summary of an agent's state for health checks.
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    /// peers currently in the discovery table
+    pub peers: usize,
+    /// deals received and stored
+    pub incoming_deals: usize,
+    /// proposals waiting in the send queue
+    pub queued_deals: usize,
+    /// received deals dropped after outliving the proposal TTL
+    pub expired_deals: u64,
+    /// host suspends detected by the watchdog
+    pub suspends: u64,
+    /// deal connections refused by the accept filter
+    pub refused_connections: u64,
+    /// deals not sent because their peer was at its send limits
+    pub send_overflows: u64,
+    /// whether the deal receiver endpoint is bound
+    pub receiver_up: bool,
+    pub announcements: DiscoveryStats,
+    /// datagrams heard on the discovery socket and not used, by reason
+    pub rejected_announcements: RejectionCounts,
+    /// proposals received over the last day
+    pub demand: Demand,
+    /// discovery and control-plane errors combined
+    pub errors: ErrorCounts,
+}
+
+/// Weight given to each new throughput sample in the moving average.
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+/// How often the watchdog checks that discovery and the receiver still run.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+/// Clock disagreement beyond which a watchdog tick is taken to have spanned
+/// a suspend.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(5);
+/// How often a pricing strategy is consulted.
+const REPRICE_INTERVAL: Duration = Duration::from_secs(30);
+/// Window [`Health::demand`] covers.
+const DEMAND_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a received deal is kept awaiting a decision before it lapses.
+pub const DEFAULT_PROPOSAL_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Why an advertised address was rejected at startup.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AdvertisedAddrError {
+    #[error("advertised address {0} is unspecified; set the address peers should dial")]
+    Unspecified(SocketAddr),
+    #[error("advertised address {0} is not a unicast address")]
+    NotUnicast(SocketAddr),
+}
+
+/// reject advertised addresses no peer could dial and warn about ones that
+/// only work from this host
+fn check_advertised_addr(
+    advertised: SocketAddr,
+    listen: SocketAddr,
+) -> Result<(), AdvertisedAddrError> {
+    let ip = advertised.ip();
+    if ip.is_unspecified() {
+        return Err(AdvertisedAddrError::Unspecified(advertised));
+    }
+    let broadcast = matches!(ip, IpAddr::V4(v4) if v4.is_broadcast());
+    if ip.is_multicast() || broadcast {
+        return Err(AdvertisedAddrError::NotUnicast(advertised));
+    }
+    if ip.is_loopback() && !listen.ip().is_loopback() {
+        warn!("advertising loopback address {advertised}; only local peers can reach it");
+    }
+    if listen.ip().is_loopback() && !ip.is_loopback() {
+        warn!("listening on loopback {listen} but advertising {advertised}; remote peers cannot connect");
+    }
+    Ok(())
+}
+
+/// every reason `peer_info` can't take `deal`, given its measured ingest
+/// rate in bytes per second, if any
+fn mismatches(peer_info: &PeerInfo, deal: &Deal, throughput: Option<f64>) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+    let (spare_mbs, price) = peer_info.capacity_for(deal.storage_class, deal.burst);
+    match price {
+        None => mismatches.push(Mismatch::NoOffer),
+        Some(asking) => {
+            let available = spare_mbs.saturating_mul(BYTES_PER_MEBIBYTE);
+            if available < deal.file_len {
+                mismatches.push(Mismatch::Capacity {
+                    available,
+                    needed: deal.file_len,
+                });
+            }
+            if asking > deal.price_per_mb {
+                mismatches.push(Mismatch::Price {
+                    asking,
+                    offered: deal.price_per_mb,
+                });
+            }
+        }
+    }
+    if let (Some(deadline_secs), Some(rate)) = (deal.deadline_secs, throughput) {
+        let estimated_secs = if rate > 0.0 {
+            deal.file_len as f64 / rate
+        } else {
+            f64::INFINITY
+        };
+        if estimated_secs > deadline_secs as f64 {
+            mismatches.push(Mismatch::Deadline {
+                estimated_secs,
+                deadline_secs,
+            });
+        }
+    }
+    let required = deal.required_features();
+    if !peer_info.features.contains(required) {
+        mismatches.push(Mismatch::Features {
+            missing: Features(required.0 & !peer_info.features.0),
+        });
+    }
+    mismatches
+}
+
+fn emit(sinks: &[Box<dyn EventSink>], event: Event) {
+    for sink in sinks {
+        sink.event(&event);
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Time since boot counting any suspend (`CLOCK_BOOTTIME`), where the OS
+/// has such a clock. Unlike the wall clock it is never stepped, so an NTP
+/// correction or `date -s` can't pass for a suspend.
+#[cfg(target_os = "linux")]
+fn boot_clock() -> Option<Duration> {
+    use nix::time::{clock_gettime, ClockId};
+    clock_gettime(ClockId::CLOCK_BOOTTIME)
+        .ok()
+        .map(Duration::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boot_clock() -> Option<Duration> {
+    None
+}
+
+/// Both clocks read at one watchdog tick.
+#[derive(Debug, Clone, Copy)]
+struct ClockReading {
+    monotonic: Instant,
+    boot: Option<Duration>,
+}
+
+impl ClockReading {
+    fn now() -> Self {
+        ClockReading {
+            monotonic: Instant::now(),
+            boot: boot_clock(),
+        }
+    }
+
+    /// how long the host was suspended between `earlier` and this reading
+    fn suspended_since(&self, earlier: &ClockReading) -> Option<Duration> {
+        let boot = self
+            .boot
+            .zip(earlier.boot)
+            .map(|(now, then)| now.saturating_sub(then));
+        suspended_for(self.monotonic - earlier.monotonic, boot)
+    }
+}
+
+/// how long the host was suspended between two watchdog ticks, given the
+/// monotonic and boot-clock time that passed. the monotonic clock stops
+/// while suspended and the boot clock doesn't; without a boot clock, or
+/// where the monotonic clock runs on, the tick itself arrives late.
+fn suspended_for(monotonic: Duration, boot: Option<Duration>) -> Option<Duration> {
+    let missed = boot.map_or(Duration::ZERO, |boot| boot.saturating_sub(monotonic));
+    let late = monotonic.saturating_sub(WATCHDOG_INTERVAL);
+    let slept = missed.max(late);
+    (slept > SUSPEND_THRESHOLD).then_some(slept)
 }
 
 impl Agent {
     pub async fn new(peer_info: PeerInfo) -> Result<Self, Box<dyn Error>> {
-        let listen_addr = peer_info.clone().addr;
-        let dsvc = Arc::new(DiscoveryService::new(peer_info).await?);
-        let rep = open_receiver_endpoint(listen_addr).await?;
-        let sep = open_sender_endpoint().await?;
-        Ok(Agent {
-            discovery: dsvc,
-            receiver_endpoint: rep,
-            sender_endpoint: sep,
-            incoming_deals: Arc::new(Mutex::new(HashMap::new())),
-        })
+        Self::with_discovery(DiscoveryService::new(peer_info).await?).await
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     pub async fn test_with_addr(
         peer_info: PeerInfo,
         bind_addr: &str,
         dest_addr: &str,
     ) -> Result<Self, Box<dyn Error>> {
-        let listen_addr = peer_info.clone().addr;
-        let dsvc =
-            Arc::new(DiscoveryService::test_with_addr(peer_info, bind_addr, dest_addr).await?);
-        let ep = open_receiver_endpoint(listen_addr).await?;
-        let sep = open_sender_endpoint().await?;
+        Self::with_discovery(
+            DiscoveryService::test_with_addr(peer_info, bind_addr, dest_addr).await?,
+        )
+        .await
+    }
+
+    /// feed recorded inbound messages through the handlers that first saw
+    /// them: announcements into discovery, deals into the proposal store
+    /// (approval hooks and backfill included). outbound records are skipped.
+    /// nothing is sent, so an offline agent (see
+    /// [`DiscoveryService::offline`]) reproduces a recording without peers
+    pub async fn replay(&self, records: impl IntoIterator<Item = Record>) -> ReplayStats {
+        let mut stats = ReplayStats::default();
+        let mut digests = HashMap::new();
+        for record in records {
+            match (record.direction, record.message) {
+                (Direction::Out, _) => stats.skipped += 1,
+                (Direction::In, Message::Announcement { payload }) => {
+                    self.discovery
+                        .handle_announcement(record.peer, &payload, &mut digests)
+                        .await;
+                    stats.announcements += 1;
+                }
+                (Direction::In, Message::Deal { deal }) => {
+                    self.store_deal(*deal, record.peer).await;
+                    stats.deals += 1;
+                }
+            }
+        }
+        stats
+    }
+}
 
+impl<D: Discovery> Agent<D> {
+    /// build an agent around an already configured discovery service,
+    /// listening for deals on its advertised address. an advertised port of 0
+    /// binds an ephemeral port, which is then advertised instead.
+    pub async fn with_discovery(dsvc: D) -> Result<Self, Box<dyn Error>> {
+        let listen_addr = dsvc.get_peer_info().addr;
+        Self::with_listen_addr(dsvc, listen_addr).await
+    }
+
+    /// like [`Agent::with_discovery`], but bind the deal receiver to
+    /// `listen_addr` (e.g. `0.0.0.0:5334` behind a port forward) while
+    /// advertising `PeerInfo.addr`. an advertised port of 0 takes the bound
+    /// port. fails if the advertised address can never be reached.
+    pub async fn with_listen_addr(
+        dsvc: D,
+        listen_addr: SocketAddr,
+    ) -> Result<Self, Box<dyn Error>> {
+        let endpoint_limits = EndpointLimits::default();
+        let rep = open_receiver_endpoint(listen_addr, &endpoint_limits).await?;
+        let listen_addr = rep.local_addr()?;
+        let mut advertised = dsvc.get_peer_info();
+        if advertised.addr.port() == 0 {
+            advertised.addr.set_port(listen_addr.port());
+            dsvc.announce(advertised.clone());
+        }
+        check_advertised_addr(advertised.addr, listen_addr)?;
+        let sep = open_sender_endpoint(&endpoint_limits).await?;
         Ok(Agent {
-            discovery: dsvc,
-            receiver_endpoint: ep,
+            recorder: dsvc.recorder(),
+            #[cfg(feature = "chaos")]
+            faults: dsvc.faults().unwrap_or_default(),
+            discovery: Arc::new(dsvc),
+            listen_addr,
+            receiver_endpoint: StdMutex::new(Some(rep)),
             sender_endpoint: sep,
+            endpoint_limits,
+            encoding: Encoding::default(),
+            outgoing: Arc::new(Semaphore::new(endpoint_limits.max_outgoing)),
             incoming_deals: Arc::new(Mutex::new(HashMap::new())),
+            proposal_ttl: DEFAULT_PROPOSAL_TTL,
+            decode_limits: DecodeLimits::default(),
+            expired_deals: AtomicU64::new(0),
+            suspends: AtomicU64::new(0),
+            peer_throughput: Mutex::new(HashMap::new()),
+            peer_transport: Arc::new(Mutex::new(HashMap::new())),
+            peer_delivery: Arc::new(Mutex::new(HashMap::new())),
+            errors: Arc::new(ErrorCounters::default()),
+            accept_filter: StdRwLock::new(AcceptFilter::default()),
+            backfill_proposers: false,
+            peer_overrides: StdRwLock::default(),
+            refused_connections: AtomicU64::new(0),
+            send_queue: StdMutex::new(BinaryHeap::new()),
+            queue_seq: AtomicU64::new(0),
+            queue_ready: Notify::new(),
+            send_limits: SendLimits::default(),
+            send_slots: StdMutex::new(HashMap::new()),
+            send_overflows: Arc::new(AtomicU64::new(0)),
+            market: StdMutex::new(PriceHistory::default()),
+            pricing: None,
+            match_policies: vec![],
+            approval_hooks: vec![],
+            event_sinks: Arc::new(vec![]),
+            shutting_down: AtomicBool::new(false),
+            stopped: Notify::new(),
         })
     }
 
-    pub async fn run(self: Arc<Self>) {
-        let dsvc = self.discovery.clone();
-        let self_clone = self.clone();
-        let _ = tokio::spawn(async move {
-            dsvc.start().await;
-        });
-        let _ = tokio::spawn(async move {
-            self_clone.receive_deals().await;
-        });
+    /// keep received deals for `ttl` instead of [`DEFAULT_PROPOSAL_TTL`]
+    pub fn with_proposal_ttl(mut self, ttl: Duration) -> Self {
+        self.proposal_ttl = ttl;
+        self
     }
 
-    fn deal_match(&self, peer_info: &PeerInfo, deal: &Deal) -> bool {
-        let spare_bytes = peer_info.spare_mbs.saturating_mul(BYTES_PER_MEBIBYTE);
-        (spare_bytes >= deal.file_len) && (peer_info.price <= deal.price_per_mb)
+    /// add proposers discovery never heard from to the peer table, for
+    /// peers that can't announce to us (e.g. bootstrapped over unicast).
+    /// the proposer's peer id, capacity and prices come from its deal
+    /// unverified, so only hosts the accept filter lets in by name (an
+    /// allowlisted IP, or the IP of a peer already in the table) are added
+    pub fn with_proposer_backfill(mut self, on: bool) -> Self {
+        self.backfill_proposers = on;
+        self
     }
 
-    pub async fn send_matched_deals(&self, deal: Deal) {
-        let matched_peers = self
-            .discovery
-            .with_peers(|peers| {
-                peers
-                    .values()
-                    .filter_map(|(peer, _instant)| {
-                        if self.deal_match(peer, &deal) {
-                            Some(peer.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .await;
+    /// cap deliveries to each peer at `limits` instead of the defaults
+    pub fn with_send_limits(mut self, limits: SendLimits) -> Self {
+        self.send_limits = limits;
+        self
+    }
 
-        let send_tasks = matched_peers.into_iter().map(|peer| {
-            let deal = deal.clone();
-            let sep = self.sender_endpoint.clone();
-            async move {
-                info!(
-                    "sending matched deal to peer {} at {}",
-                    peer.peer_id, peer.addr
-                );
-                if let Err(err) = send(&sep, peer.addr, deal).await {
-                    warn!("failed to send deal to {}: {err}", peer.peer_id);
-                }
-            }
-        });
-        join_all(send_tasks).await;
+    /// let `strategy` set our advertised price from competitors' prices
+    pub fn with_pricing(mut self, strategy: impl PricingStrategy + 'static) -> Self {
+        self.pricing = Some(Box::new(strategy));
+        self
     }
 
-    pub async fn receive_deals(&self) {
-        let peer_info = self.get_peer_info().clone();
-        info!(
-            "agent {} listening for deals on {}",
-            peer_info.peer_id, peer_info.addr
-        );
+    /// bound idle and open QUIC connections, see [`EndpointLimits`]. fails
+    /// if a new receiver certificate can't be minted
+    pub fn with_endpoint_limits(mut self, limits: EndpointLimits) -> Result<Self, Box<dyn Error>> {
+        if let Some(ep) = self.receiver_endpoint() {
+            ep.set_server_config(Some(server_config(&limits)?));
+        }
+        self.sender_endpoint
+            .set_default_client_config(client_config(&limits, self.encoding));
+        self.outgoing = Arc::new(Semaphore::new(limits.max_outgoing));
+        self.endpoint_limits = limits;
+        Ok(self)
+    }
+
+    /// send deals in `encoding`, e.g. [`Encoding::Json`] to debug the wire
+    /// with generic tools. receivers accept either, per connection
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.sender_endpoint
+            .set_default_client_config(client_config(&self.endpoint_limits, encoding));
+        self.encoding = encoding;
+        self
+    }
+
+    /// also require matched providers to pass `policy`
+    pub fn with_match_policy(mut self, policy: impl MatchPolicy + 'static) -> Self {
+        self.match_policies.push(Box::new(policy));
+        self
+    }
+
+    /// only keep received deals that `hook` approves
+    pub fn with_approval_hook(mut self, hook: impl ApprovalHook + 'static) -> Self {
+        self.approval_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// report agent events to `sink`
+    pub fn with_event_sink(mut self, sink: impl EventSink + 'static) -> Self {
+        Arc::get_mut(&mut self.event_sinks)
+            .expect("event sinks are registered before the agent runs")
+            .push(Box::new(sink));
+        self
+    }
+
+    /// continue a price history saved from [`Agent::price_history`]
+    pub fn with_price_history(self, history: PriceHistory) -> Self {
+        *self.market.lock().expect("market lock poisoned") = history;
+        self
+    }
+
+    /// decode incoming deals within `limits` instead of the defaults
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.decode_limits = limits;
+        self
+    }
+
+    /// start discovery and the deal receiver in the background, supervised
+    /// by a watchdog that rebuilds whichever of them stops
+    pub async fn run(self: Arc<Self>) {
+        tokio::spawn(self.clone().send_queued());
+        tokio::spawn(self.clone().follow_discovery());
+        tokio::spawn(self.watchdog());
+    }
+
+    /// drop per-peer send state as peers time out of the table, re-checking
+    /// the whole table if events were missed
+    async fn follow_discovery(self: Arc<Self>) {
+        let mut events = self.discovery.subscribe();
         loop {
-            match receive(&self.receiver_endpoint).await {
-                Ok(deal) => {
-                    info!(
-                        "agent {} received deal from {}",
-                        self.get_peer_info().peer_id,
-                        deal.peer_info_wire.addr
-                    );
-                    // insert into incoming deals
-                    self.incoming_deals
-                        .lock()
-                        .await
-                        .insert(deal.peer_info_wire.addr.to_string(), deal);
+            let stopped = self.stopped.notified();
+            if self.shutting_down.load(Ordering::SeqCst) {
+                return;
+            }
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = stopped => return,
+            };
+            match event {
+                Ok(DiscoveryEvent::PeerExpired(peer_id)) => {
+                    debug!("peer {peer_id} left the table");
+                    self.prune_peer_state().await;
                 }
-                Err(e) => {
-                    warn!("failed to receive deal: {e}");
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("missed {missed} discovery events");
+                    self.prune_peer_state().await;
                 }
+                Err(RecvError::Closed) => return,
             }
         }
     }
 
-    fn get_peer_info(&self) -> &PeerInfo {
-        self.discovery.get_peer_info()
+    fn spawn_discovery(&self) -> JoinHandle<()> {
+        let span = info_span!("discovery", peer_id = %self.get_peer_info().peer_id);
+        tokio::spawn(self.discovery.clone().start().instrument(span))
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use libp2p::PeerId;
-    use std::{sync::Arc, time::Duration};
-    use tokio::time;
-
-    use crate::{deal::BYTES_PER_MEBIBYTE, peer_info::PeerInfoWire};
 
-    use super::*;
+    fn spawn_receiver(self: &Arc<Self>) -> JoinHandle<()> {
+        let agent = self.clone();
+        let span = info_span!("receiver", peer_id = %self.get_peer_info().peer_id);
+        tokio::spawn(agent.receive_deals().instrument(span))
+    }
 
-    #[tokio::test]
-    /// two agents discover each other over loopback sockets
-    /// agents will succeed in matching a deal with one another
-    /// peer1's deal will be matched with peer2 based on its
-    /// `spare_mbs` and `price`.
-    async fn two_agents_communicate() {
-        let peer_info1 = PeerInfo {
-            addr: "127.0.0.1:6101".parse().unwrap(),
-            peer_id: PeerId::random(),
-            spare_mbs: 14,
-            price: 15.0,
-        };
+    /// discovery only returns once its socket keeps failing and the receiver
+    /// once its endpoint is closed; either way the loop has stopped making
+    /// progress, so rebuild the component and start it again
+    async fn watchdog(self: Arc<Self>) {
+        let mut discovery = self.spawn_discovery();
+        let mut receiver = self.spawn_receiver();
+        let mut interval = time::interval(WATCHDOG_INTERVAL);
+        let mut last_tick = ClockReading::now();
+        let mut last_reprice: Option<Instant> = None;
+        loop {
+            interval.tick().await;
+            if self.shutting_down.load(Ordering::SeqCst) {
+                return;
+            }
+            let now = ClockReading::now();
+            if let Some(slept) = now.suspended_since(&last_tick) {
+                self.resume(slept).await;
+            }
+            last_tick = now;
+            self.expire_proposals().await;
+            let prices = self.sample_prices().await;
+            if last_reprice.is_none_or(|at| at.elapsed() >= REPRICE_INTERVAL) {
+                last_reprice = Some(Instant::now());
+                self.reprice(&prices);
+            }
+            if discovery.is_finished() {
+                warn!("discovery stopped, rebinding its socket");
+                match self.discovery.restart().await {
+                    Ok(()) => discovery = self.spawn_discovery(),
+                    Err(e) => warn!("failed to rebind discovery socket: {e}"),
+                }
+            }
+            if receiver.is_finished() {
+                warn!("deal receiver stopped, reopening its endpoint");
+                // release the old socket before binding the same address again
+                let old = self
+                    .receiver_endpoint
+                    .lock()
+                    .expect("endpoint lock poisoned")
+                    .take();
+                if let Some(old) = old {
+                    old.wait_idle().await;
+                }
+                match open_receiver_endpoint(self.listen_addr, &self.endpoint_limits).await {
+                    Ok(ep) => {
+                        *self
+                            .receiver_endpoint
+                            .lock()
+                            .expect("endpoint lock poisoned") = Some(ep);
+                        receiver = self.spawn_receiver();
+                    }
+                    Err(e) => warn!("failed to reopen receiver endpoint: {e}"),
+                }
+            }
+        }
+    }
 
-        let deal1 = Deal {
-            peer_info_wire: PeerInfoWire::from(peer_info1.clone()),
-            file_len: 40 * BYTES_PER_MEBIBYTE,
-            price_per_mb: 10.0,
-        };
+    /// catch up after the host slept for about `slept`: age peers and
+    /// proposals by the time the monotonic clock missed and re-announce
+    /// right away rather than waiting for timeouts
+    async fn resume(&self, slept: Duration) {
+        info!("resumed after about {}s suspended", slept.as_secs());
+        self.suspends.fetch_add(1, Ordering::Relaxed);
+        self.discovery.age_peers(slept).await;
+        self.discovery.announce(self.discovery.get_peer_info());
+        {
+            let mut deals = self.incoming_deals.lock().await;
+            let before = deals.len();
+            // a deal that can't be moved back that far has lapsed in any case
+            deals.retain(|_, (_, received)| match received.checked_sub(slept) {
+                Some(earlier) => {
+                    *received = earlier;
+                    true
+                }
+                None => false,
+            });
+            self.expired_deals
+                .fetch_add((before - deals.len()) as u64, Ordering::Relaxed);
+        }
+        self.expire_proposals().await;
+    }
 
-        let peer_info2 = PeerInfo {
-            addr: "127.0.0.1:6103".parse().unwrap(),
-            peer_id: PeerId::random(),
-            spare_mbs: 50,
-            price: 1.0,
-        };
+    /// drop received deals older than the proposal TTL, returning how many
+    /// lapsed. the sender is not told: deals travel one way only.
+    async fn expire_proposals(&self) -> usize {
+        let ttl = self.proposal_ttl;
+        let mut deals = self.incoming_deals.lock().await;
+        let before = deals.len();
+        deals.retain(|from, (deal, received)| {
+            let live = received.elapsed() <= ttl;
+            if !live {
+                info!("deal from {from} expired after {ttl:?}");
+                emit(&self.event_sinks, Event::DealExpired { deal_id: deal.id() });
+            }
+            live
+        });
+        let expired = before - deals.len();
+        self.expired_deals
+            .fetch_add(expired as u64, Ordering::Relaxed);
+        expired
+    }
 
-        // connect with Agent2's discovery address
-        let agent1 = Arc::new(
-            Agent::test_with_addr(peer_info1.clone(), "127.0.0.1:6100", "127.0.0.1:6102")
-                .await
-                .unwrap(),
-        );
-        // connect with Agent1's discovery address
-        let agent2 = Arc::new(
-            Agent::test_with_addr(peer_info2.clone(), "127.0.0.1:6102", "127.0.0.1:6100")
-                .await
-                .unwrap(),
-        );
+    /// drop per-peer send state for peers that left the peer table and have
+    /// nothing in flight
+    async fn prune_peer_state(&self) {
+        let known: HashSet<PeerId> = self
+            .discovery
+            .get_peers()
+            .await
+            .into_iter()
+            .map(|peer| peer.peer_id)
+            .collect();
+        self.send_slots
+            .lock()
+            .expect("send slots lock poisoned")
+            .retain(|peer_id, slots| known.contains(peer_id) || Arc::strong_count(slots) > 1);
+        self.peer_transport
+            .lock()
+            .await
+            .retain(|peer_id, _| known.contains(peer_id));
+    }
 
-        let _ = tokio::spawn(agent1.clone().run());
-        let _ = tokio::spawn(agent2.clone().run());
+    /// stop accepting deals and close both QUIC endpoints, letting open
+    /// connections drain. the watchdog stops rebuilding components.
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        // wake the queue sender and discovery follower so they see the flag
+        self.queue_ready.notify_one();
+        self.stopped.notify_waiters();
+        let receiver = self
+            .receiver_endpoint
+            .lock()
+            .expect("endpoint lock poisoned")
+            .take();
+        if let Some(ep) = receiver {
+            ep.close(0u32.into(), b"shutdown");
+            ep.wait_idle().await;
+        }
+        self.sender_endpoint.close(0u32.into(), b"shutdown");
+        self.sender_endpoint.wait_idle().await;
+        info!("agent {} shut down", self.get_peer_info().peer_id);
+    }
 
-        time::sleep(Duration::from_secs(2)).await;
+    fn receiver_endpoint(&self) -> Option<Endpoint> {
+        self.receiver_endpoint
+            .lock()
+            .expect("endpoint lock poisoned")
+            .clone()
+    }
 
-        let peers_agent1 = agent1.discovery.get_peers().await;
-        let peers_agent2 = agent2.discovery.get_peers().await;
+    /// `throughput` is the peer's measured ingest rate in bytes per second,
+    /// if known. peers without a measurement are not held to the deadline.
+    fn deal_match(&self, peer_info: &PeerInfo, deal: &Deal, throughput: Option<f64>) -> bool {
+        self.mismatches(peer_info, deal, throughput).is_empty()
+    }
 
-        assert!(
-            peers_agent1
-                .iter()
-                .any(|peer| peer.peer_id == peer_info2.peer_id),
-            "agent1 should see agent2"
-        );
-        assert!(
-            peers_agent2
+    /// the built-in [`mismatches`] against the peer with its override
+    /// applied, then the override's own limits, then any from match policies
+    fn mismatches(
+        &self,
+        peer_info: &PeerInfo,
+        deal: &Deal,
+        throughput: Option<f64>,
+    ) -> Vec<Mismatch> {
+        let settings = self.peer_override(&peer_info.peer_id);
+        let mut found = mismatches(&settings.apply(peer_info), deal, throughput);
+        if settings.blocked() {
+            found.push(Mismatch::Blocked);
+        }
+        if let Some(max_deal_bytes) = settings.max_deal_bytes {
+            if deal.file_len > max_deal_bytes {
+                found.push(Mismatch::OverQuota {
+                    max_deal_bytes,
+                    needed: deal.file_len,
+                });
+            }
+        }
+        found.extend(
+            self.match_policies
                 .iter()
-                .any(|peer| peer.peer_id == peer_info1.peer_id),
-            "agent2 should see agent1"
+                .filter_map(|policy| policy.reject(peer_info, deal))
+                .map(Mismatch::Policy),
         );
+        found
+    }
 
-        let expected_deal = deal1.clone();
-        agent1.send_matched_deals(deal1).await;
+    /// check every known peer against the per-provider share of `deal`, as
+    /// [`Agent::send_matched_deals`] would, and say why each one that doesn't
+    /// match was excluded
+    pub async fn explain_match(&self, deal: &Deal) -> Vec<MatchExplanation> {
+        let unit = unit_deal(deal);
+        let throughput = self.peer_throughput.lock().await.clone();
+        self.discovery
+            .get_peers()
+            .await
+            .iter()
+            .map(|peer| MatchExplanation {
+                peer_id: peer.peer_id,
+                addr: peer.addr,
+                mismatches: self.mismatches(peer, &unit, throughput.get(&peer.peer_id).copied()),
+            })
+            .collect()
+    }
 
-        time::sleep(Duration::from_secs(1)).await;
-        let received_deal = agent2
-            .incoming_deals
+    /// record an observed transfer (or probe) of `bytes` to `peer_id` taking
+    /// `elapsed`, folding it into the peer's smoothed throughput
+    pub async fn record_throughput(&self, peer_id: PeerId, bytes: u64, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64();
+        self.peer_throughput
             .lock()
             .await
-            .get(&agent1.get_peer_info().addr.to_string())
-            .expect("not found")
-            .clone();
+            .entry(peer_id)
+            .and_modify(|rate| {
+                *rate = THROUGHPUT_SMOOTHING * sample + (1.0 - THROUGHPUT_SMOOTHING) * *rate
+            })
+            .or_insert(sample);
+    }
 
-        assert_eq!(
-            received_deal.peer_info_wire.addr,
-            agent1.get_peer_info().addr
-        );
-        assert_eq!(received_deal.file_len, expected_deal.file_len);
-        assert_eq!(received_deal.price_per_mb, expected_deal.price_per_mb);
+    /// peers that match `deal`, fastest measured ingest first; peers without
+    /// a measurement come last. ties are broken by lowest QUIC round-trip
+    /// time, with peers never connected to after those
+    pub async fn matched_peers(&self, deal: &Deal) -> Vec<PeerInfo> {
+        let throughput = self.peer_throughput.lock().await.clone();
+        let transport = self.peer_transport.lock().await.clone();
+        let mut matched: Vec<_> = self
+            .discovery
+            .get_peers()
+            .await
+            .iter()
+            .filter_map(|peer| {
+                let rate = throughput.get(&peer.peer_id).copied();
+                if self.deal_match(peer, deal, rate) {
+                    Some(self.peer_override(&peer.peer_id).apply(peer))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let rate = |peer: &PeerInfo| throughput.get(&peer.peer_id).copied().unwrap_or(0.0);
+        let rtt = |peer: &PeerInfo| {
+            transport
+                .get(&peer.peer_id)
+                .map_or(f64::INFINITY, |stats| stats.rtt_ms)
+        };
+        matched.sort_by(|a, b| {
+            rate(b)
+                .total_cmp(&rate(a))
+                .then_with(|| rtt(a).total_cmp(&rtt(b)))
+        });
+        matched
+    }
+
+    /// match peers against the per-provider share of `deal`, place it
+    /// according to its redundancy scheme, and send each assignment.
+    /// returns the placement so callers can record it for restore.
+    pub async fn send_matched_deals(&self, deal: Deal) -> Result<Placement, PlacementError> {
+        let span = info_span!("place_deal", deal_id = %deal.id(), file_len = deal.file_len);
+        self.place_and_send(deal).instrument(span).await
+    }
+
+    async fn place_and_send(&self, deal: Deal) -> Result<Placement, PlacementError> {
+        let plan = self.simulate_deal(&deal).await?;
+        self.send_assignments(&plan.placement).await;
+        Ok(plan.placement)
+    }
+
+    /// dry run of [`Agent::send_matched_deals`]: match and place `deal` the
+    /// same way and price each assignment at the provider's asking price,
+    /// but send nothing
+    pub async fn simulate_deal(&self, deal: &Deal) -> Result<Plan, PlacementError> {
+        let candidates = self.matched_peers(&unit_deal(deal)).await;
+        let placement = plan(deal, candidates);
+        if let Err(PlacementError::NotEnoughPeers { .. }) = placement {
+            self.log_exclusions(deal).await;
+        }
+        placement.map(priced)
+    }
+
+    async fn log_exclusions(&self, deal: &Deal) {
+        for peer in self.explain_match(deal).await {
+            if !peer.matches() {
+                let reasons: Vec<_> = peer.mismatches.iter().map(|m| m.to_string()).collect();
+                debug!("peer {} excluded: {}", peer.peer_id, reasons.join("; "));
+            }
+        }
+    }
+
+    /// cheapest placement of `deal` on matching peers within `constraints`,
+    /// judging reliability by past deliveries. nothing is sent; pass the
+    /// plan to [`Agent::send_plan`] to go ahead
+    pub async fn plan_placement(
+        &self,
+        deal: &Deal,
+        constraints: &Constraints,
+    ) -> Result<Plan, PlacementError> {
+        let delivery = self.peer_delivery.lock().await.clone();
+        let candidates = self
+            .matched_peers(&unit_deal(deal))
+            .await
+            .into_iter()
+            .map(|peer| {
+                // trusted peers are not held to past deliveries
+                let reliability = delivery
+                    .get(&peer.peer_id)
+                    .and_then(|r| r.reliability())
+                    .filter(|_| !self.peer_override(&peer.peer_id).trusted());
+                (peer, reliability)
+            })
+            .collect();
+        let plan = optimize(deal, candidates, constraints);
+        if let Err(PlacementError::NotEnoughPeers { .. }) = plan {
+            self.log_exclusions(deal).await;
+        }
+        plan
+    }
+
+    /// send each assignment of a plan from [`Agent::plan_placement`]
+    pub async fn send_plan(&self, plan: &Plan) {
+        self.send_assignments(&plan.placement).await
+    }
+
+    async fn send_assignments(&self, placement: &Placement) {
+        let send_tasks = placement.assignments.iter().cloned().map(|(peer, deal)| {
+            let sep = self.sender_endpoint.clone();
+            let errors = self.errors.clone();
+            let transport = self.peer_transport.clone();
+            let delivery = self.peer_delivery.clone();
+            let sinks = self.event_sinks.clone();
+            let outgoing = self.outgoing.clone();
+            let recorder = self.recorder.clone();
+            let slots = self.send_slots(peer.peer_id);
+            let overflow = self.send_limits.overflow;
+            let overflows = self.send_overflows.clone();
+            #[cfg(feature = "chaos")]
+            let faults = self.faults.clone();
+            let span = info_span!("send_deal", deal_id = %deal.id(), peer_id = %peer.peer_id);
+            async move {
+                #[cfg(feature = "chaos")]
+                if faults.fail_send() {
+                    errors.record(ErrorKind::Send);
+                    delivery
+                        .lock()
+                        .await
+                        .entry(peer.peer_id)
+                        .or_default()
+                        .record(false);
+                    emit(
+                        &sinks,
+                        Event::SendFailed {
+                            deal_id: deal.id(),
+                            peer_id: peer.peer_id,
+                        },
+                    );
+                    warn!("injected failure sending deal to {}", peer.peer_id);
+                    return;
+                }
+                let size = bincode::serialized_size(&deal).map_or(u32::MAX, |n| n as u32);
+                let Some(_permits) = slots.acquire(size, overflow).await else {
+                    overflows.fetch_add(1, Ordering::Relaxed);
+                    warn!("too many deals in flight to {}, not sending", peer.peer_id);
+                    return;
+                };
+                info!(
+                    "sending matched deal to peer {} at {}",
+                    peer.peer_id, peer.addr
+                );
+                let deal_id = deal.id();
+                let Ok(_connection) = outgoing.acquire().await else {
+                    return;
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record(
+                        Direction::Out,
+                        peer.addr,
+                        Message::Deal {
+                            deal: Box::new(deal.clone()),
+                        },
+                    );
+                }
+                match send(&sep, peer.addr, deal).await {
+                    Ok(stats) => {
+                        transport.lock().await.insert(peer.peer_id, stats);
+                        delivery
+                            .lock()
+                            .await
+                            .entry(peer.peer_id)
+                            .or_default()
+                            .record(true);
+                        emit(
+                            &sinks,
+                            Event::DealSent {
+                                deal_id,
+                                peer_id: peer.peer_id,
+                            },
+                        );
+                    }
+                    Err(err) => {
+                        errors.record(ErrorKind::Send);
+                        delivery
+                            .lock()
+                            .await
+                            .entry(peer.peer_id)
+                            .or_default()
+                            .record(false);
+                        emit(
+                            &sinks,
+                            Event::SendFailed {
+                                deal_id,
+                                peer_id: peer.peer_id,
+                            },
+                        );
+                        warn!("failed to send deal to {}: {err}", peer.peer_id);
+                    }
+                }
+            }
+            .instrument(span)
+        });
+        join_all(send_tasks).await;
+    }
+
+    /// record the base prices providers currently advertise, and return them
+    async fn sample_prices(&self) -> Vec<f32> {
+        let prices: Vec<_> = self
+            .discovery
+            .get_peers()
+            .await
+            .iter()
+            .filter(|p| p.spare_mbs > 0 || p.burst_mbs > 0)
+            .map(|p| p.price)
+            .collect();
+        self.market
+            .lock()
+            .expect("market lock poisoned")
+            .record_advertised(unix_secs(), prices.clone());
+        prices
+    }
+
+    /// apply the pricing strategy, if any, to the competitors' `prices`
+    fn reprice(&self, prices: &[f32]) {
+        let Some(strategy) = &self.pricing else {
+            return;
+        };
+        let current = self.get_peer_info().price;
+        let price = strategy.price(current, prices);
+        if price != current {
+            info!("repricing from {current} to {price} per MiB");
+            let mut peer_info = self.discovery.get_peer_info();
+            peer_info.price = price;
+            self.discovery.announce(peer_info);
+            emit(
+                &self.event_sinks,
+                Event::Repriced {
+                    from: current,
+                    to: price,
+                },
+            );
+        }
+    }
+
+    /// copy of the price history, e.g. to save across restarts
+    pub fn price_history(&self) -> PriceHistory {
+        self.market.lock().expect("market lock poisoned").clone()
+    }
+
+    /// median base price per MiB providers currently advertise
+    pub fn median_price(&self) -> Option<f32> {
+        self.market
+            .lock()
+            .expect("market lock poisoned")
+            .median_price()
+    }
+
+    /// relative change in the median advertised price over the last
+    /// `window`, e.g. [`crate::market::RETENTION`] for the 7-day trend
+    pub fn price_trend(&self, window: Duration) -> Option<f32> {
+        self.market
+            .lock()
+            .expect("market lock poisoned")
+            .trend(unix_secs(), window)
+    }
+
+    /// proposals received over the `window` before now, for capacity
+    /// planning
+    pub fn demand(&self, window: Duration) -> Demand {
+        self.market
+            .lock()
+            .expect("market lock poisoned")
+            .demand(unix_secs(), window)
+    }
+
+    fn send_slots(&self, peer_id: PeerId) -> Arc<SendSlots> {
+        self.send_slots
+            .lock()
+            .expect("send slots lock poisoned")
+            .entry(peer_id)
+            .or_insert_with(|| Arc::new(SendSlots::new(&self.send_limits)))
+            .clone()
+    }
+
+    async fn store_deal(&self, deal: Deal, remote: SocketAddr) {
+        #[cfg(feature = "chaos")]
+        time::sleep(self.faults.receive_delay()).await;
+        let deal_id = deal.id();
+        let cost = deal.cost();
+        let claimed = PeerId::from_bytes(&deal.peer_info_wire.peer_id_bytes).ok();
+        let settings = self.sender_override(claimed, remote).await;
+        let refusal = if settings.blocked() {
+            Some("peer is blocked")
+        } else if settings
+            .max_deal_bytes
+            .is_some_and(|max| deal.file_len > max)
+        {
+            Some("deal is over the peer's cap")
+        } else if !settings.trusted()
+            && !self
+                .approval_hooks
+                .iter()
+                .all(|hook| hook.approve(&deal, remote))
+        {
+            Some("refused by approval hook")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            info!("deal from {remote} refused: {reason}");
+            emit(&self.event_sinks, Event::DealRejected { deal_id, remote });
+            return;
+        }
+        info!(
+            "agent {} received deal from {}",
+            self.get_peer_info().peer_id,
+            deal.peer_info_wire.addr
+        );
+        self.backfill_peer(&deal, remote).await;
+        let fits = !mismatches(&self.get_peer_info(), &deal, None)
+            .iter()
+            .any(|m| matches!(m, Mismatch::NoOffer | Mismatch::Capacity { .. }));
+        self.market
+            .lock()
+            .expect("market lock poisoned")
+            .record_deal(unix_secs(), &deal, fits);
+        // insert into incoming deals
+        self.incoming_deals
+            .lock()
+            .await
+            .insert(deal.peer_info_wire.addr.to_string(), (deal, Instant::now()));
+        emit(
+            &self.event_sinks,
+            Event::DealReceived {
+                deal_id,
+                remote,
+                cost,
+            },
+        );
+    }
+
+    /// overrides for a deal from `remote` whose payload claims to come from
+    /// `claimed`. nothing authenticates that claim, so the claimed peer's
+    /// trust only counts if discovery has it at the remote IP, while the
+    /// block and cap of every peer discovered at that IP apply as well
+    async fn sender_override(&self, claimed: Option<PeerId>, remote: SocketAddr) -> PeerOverride {
+        let at_remote: Vec<_> = self
+            .discovery
+            .get_peers()
+            .await
+            .into_iter()
+            .filter(|peer| peer.addr.ip() == remote.ip())
+            .map(|peer| peer.peer_id)
+            .collect();
+        let vouched = claimed.is_some_and(|peer_id| at_remote.contains(&peer_id));
+        let settings: Vec<_> = claimed
+            .into_iter()
+            .chain(at_remote)
+            .map(|peer_id| self.peer_override(&peer_id))
+            .collect();
+        let trust = if settings.iter().any(PeerOverride::blocked) {
+            Some(Trust::Blocked)
+        } else if vouched && settings[0].trusted() {
+            Some(Trust::Trusted)
+        } else {
+            None
+        };
+        PeerOverride {
+            trust,
+            max_deal_bytes: settings.iter().filter_map(|s| s.max_deal_bytes).min(),
+            ..PeerOverride::default()
+        }
+    }
+
+    /// add a proposer discovery never heard from (e.g. one that bootstrapped
+    /// over unicast) to the peer table, so deals can be sent back to it, if
+    /// enabled and the accept filter vets `remote`. the identity is the
+    /// deal's own, unverified claim. the sender's QUIC source port is
+    /// ephemeral, so it is reached on the source IP at the port it
+    /// advertises.
+    async fn backfill_peer(&self, deal: &Deal, remote: SocketAddr) {
+        if !self.backfill_proposers || !self.vetted(remote).await {
+            return;
+        }
+        let mut peer_info = match PeerInfo::try_from(deal.peer_info_wire.clone()) {
+            Ok(peer_info) => peer_info,
+            Err(e) => {
+                debug!("not adding proposer at {remote} to peer table: {e}");
+                return;
+            }
+        };
+        peer_info.addr = SocketAddr::new(remote.ip(), peer_info.addr.port());
+        let addr = peer_info.addr;
+        let peer_id = peer_info.peer_id;
+        if self.discovery.learn_peer(peer_info).await {
+            info!("added proposer {peer_id} at {addr} to peer table");
+        }
+    }
+
+    /// queue `deal` to be placed and sent by the background sender, after
+    /// any queued proposals of higher priority
+    pub fn queue_deal(&self, deal: Deal) {
+        let seq = self.queue_seq.fetch_add(1, Ordering::Relaxed);
+        self.send_queue
+            .lock()
+            .expect("send queue lock poisoned")
+            .push(QueuedDeal {
+                priority: deal.priority,
+                seq,
+                deal,
+            });
+        self.queue_ready.notify_one();
+    }
+
+    fn next_queued(&self) -> Option<Deal> {
+        self.send_queue
+            .lock()
+            .expect("send queue lock poisoned")
+            .pop()
+            .map(|queued| queued.deal)
+    }
+
+    /// place and send queued proposals one at a time until shutdown
+    async fn send_queued(self: Arc<Self>) {
+        while !self.shutting_down.load(Ordering::SeqCst) {
+            let Some(deal) = self.next_queued() else {
+                self.queue_ready.notified().await;
+                continue;
+            };
+            let id = deal.id();
+            match self.send_matched_deals(deal).await {
+                Ok(placement) => debug!(
+                    "queued deal {id} sent to {} peers",
+                    placement.assignments.len()
+                ),
+                Err(e) => warn!("queued deal {id} not placed: {e}"),
+            }
+        }
+    }
+
+    /// operator settings for `peer_id`, or defaults if none were set
+    pub fn peer_override(&self, peer_id: &PeerId) -> PeerOverride {
+        self.peer_overrides
+            .read()
+            .expect("peer overrides lock poisoned")
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// pin `settings` on `peer_id`, replacing any earlier ones; they take
+    /// effect for the next match and the next deal received
+    pub fn set_peer_override(&self, peer_id: PeerId, settings: PeerOverride) {
+        self.peer_overrides
+            .write()
+            .expect("peer overrides lock poisoned")
+            .set(peer_id, settings);
+    }
+
+    /// replace every peer override, e.g. after the file they are kept in
+    /// changed
+    pub fn set_peer_overrides(&self, overrides: PeerOverrides) {
+        *self
+            .peer_overrides
+            .write()
+            .expect("peer overrides lock poisoned") = overrides;
+    }
+
+    /// copy of the peer overrides, to persist with [`PeerOverrides::save`]
+    pub fn peer_overrides(&self) -> PeerOverrides {
+        self.peer_overrides
+            .read()
+            .expect("peer overrides lock poisoned")
+            .clone()
+    }
+
+    /// replace the filter applied to incoming deal connections
+    pub fn set_accept_filter(&self, filter: AcceptFilter) {
+        *self
+            .accept_filter
+            .write()
+            .expect("accept filter lock poisoned") = filter;
+    }
+
+    async fn accepts(&self, remote: SocketAddr) -> bool {
+        let open = self
+            .accept_filter
+            .read()
+            .expect("accept filter lock poisoned")
+            .is_open();
+        open || self.vetted(remote).await
+    }
+
+    /// whether the accept filter lets `remote` in by name: its IP is
+    /// allowlisted or, with `known_peers`, a discovered peer's
+    async fn vetted(&self, remote: SocketAddr) -> bool {
+        let (known_peers, allowed) = {
+            let filter = self
+                .accept_filter
+                .read()
+                .expect("accept filter lock poisoned");
+            (filter.known_peers, filter.allowlist.contains(&remote.ip()))
+        };
+        allowed
+            || (known_peers
+                && self
+                    .discovery
+                    .get_peers()
+                    .await
+                    .iter()
+                    .any(|peer| peer.addr.ip() == remote.ip()))
+    }
+
+    pub async fn receive_deals(self: Arc<Self>) {
+        let peer_info = self.get_peer_info();
+        info!(
+            "agent {} listening for deals on {}",
+            peer_info.peer_id, peer_info.addr
+        );
+        let Some(endpoint) = self.receiver_endpoint() else {
+            warn!("no receiver endpoint, not accepting deals");
+            return;
+        };
+        // each connection is read on its own task, so one that stalls
+        // can't hold up the others
+        let reading = Arc::new(Semaphore::new(self.endpoint_limits.max_reading));
+        loop {
+            let incoming = match next_incoming(&endpoint).await {
+                Ok(incoming) => incoming,
+                Err(e) => match receive_error_kind(&e) {
+                    None => {
+                        if !self.shutting_down.load(Ordering::SeqCst) {
+                            warn!("receiver endpoint closed, no longer accepting deals");
+                        }
+                        return;
+                    }
+                    Some(kind) => {
+                        self.errors.record(kind);
+                        debug!("failed to receive deal ({kind}): {e:#}");
+                        continue;
+                    }
+                },
+            };
+            let remote = incoming.remote_address();
+            if !self.accepts(remote).await {
+                debug!("refusing deal connection from {remote}");
+                self.refused_connections.fetch_add(1, Ordering::Relaxed);
+                incoming.refuse();
+                continue;
+            }
+            let Ok(permit) = reading.clone().try_acquire_owned() else {
+                debug!("already reading the most connections, refusing {remote}");
+                self.refused_connections.fetch_add(1, Ordering::Relaxed);
+                incoming.refuse();
+                continue;
+            };
+            let agent = self.clone();
+            tokio::spawn(
+                async move {
+                    agent.receive_deal(incoming, remote).await;
+                    drop(permit);
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    /// read, record and store the deal on one accepted connection
+    async fn receive_deal(&self, incoming: Incoming, remote: SocketAddr) {
+        let read = read_deal_within(
+            incoming,
+            &self.decode_limits,
+            self.endpoint_limits.read_timeout,
+        );
+        let deal = match read.await {
+            Ok(deal) => deal,
+            Err(e) => {
+                let kind = receive_error_kind(&e).unwrap_or(ErrorKind::Stream);
+                self.errors.record(kind);
+                debug!("failed to receive deal ({kind}): {e:#}");
+                return;
+            }
+        };
+        if let Some(recorder) = &self.recorder {
+            recorder.record(
+                Direction::In,
+                remote,
+                Message::Deal {
+                    deal: Box::new(deal.clone()),
+                },
+            );
+        }
+        let span = info_span!(
+            "receive_deal",
+            deal_id = %deal.id(),
+            from = %deal.peer_info_wire.addr
+        );
+        self.store_deal(deal, remote).instrument(span).await;
+    }
+
+    pub fn get_peer_info(&self) -> PeerInfo {
+        self.discovery.get_peer_info()
+    }
+
+    /// faults injected into this agent, see [`crate::chaos`]
+    #[cfg(feature = "chaos")]
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+
+    /// address the deal receiver endpoint is bound to
+    pub fn receiver_addr(&self) -> std::io::Result<SocketAddr> {
+        self.receiver_endpoint()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "receiver endpoint is being rebuilt",
+                )
+            })?
+            .local_addr()
+    }
+
+    /// local address of the endpoint used to dial peers
+    pub fn sender_addr(&self) -> std::io::Result<SocketAddr> {
+        self.sender_endpoint.local_addr()
+    }
+
+    /// counts and error totals for health checks
+    pub async fn health(&self) -> Health {
+        Health {
+            peers: self.discovery.get_peers().await.len(),
+            incoming_deals: self.incoming_deals.lock().await.len(),
+            queued_deals: self
+                .send_queue
+                .lock()
+                .expect("send queue lock poisoned")
+                .len(),
+            expired_deals: self.expired_deals.load(Ordering::Relaxed),
+            suspends: self.suspends.load(Ordering::Relaxed),
+            refused_connections: self.refused_connections.load(Ordering::Relaxed),
+            send_overflows: self.send_overflows.load(Ordering::Relaxed),
+            receiver_up: self.receiver_endpoint().is_some(),
+            announcements: self.discovery.stats(),
+            rejected_announcements: self.discovery.rejections(),
+            demand: self.demand(DEMAND_WINDOW),
+            errors: self.errors.snapshot() + self.discovery.errors(),
+        }
+    }
+
+    /// the discovery backend this agent runs on
+    pub fn discovery(&self) -> &Arc<D> {
+        &self.discovery
+    }
+
+    /// clone of the deals received so far, keyed by the sender's advertised address
+    pub async fn incoming_deals(&self) -> HashMap<String, Deal> {
+        let deals = self.incoming_deals.lock().await;
+        deals
+            .iter()
+            .map(|(k, (deal, _))| (k.clone(), deal.clone()))
+            .collect()
+    }
+
+    /// QUIC path stats (RTT, congestion window, losses, MTU) from the last
+    /// deal delivered to each peer
+    pub async fn transport_stats(&self) -> HashMap<PeerId, TransportStats> {
+        self.peer_transport.lock().await.clone()
+    }
+
+    /// received deals awaiting a decision, highest priority first and oldest
+    /// first within a priority, keyed like [`Agent::incoming_deals`]
+    pub async fn pending_deals(&self) -> Vec<(String, Deal)> {
+        let deals = self.incoming_deals.lock().await;
+        let mut pending: Vec<_> = deals.iter().collect();
+        pending.sort_by(|(_, (a, a_at)), (_, (b, b_at))| {
+            b.priority.cmp(&a.priority).then_with(|| a_at.cmp(b_at))
+        });
+        pending
+            .into_iter()
+            .map(|(k, (deal, _))| (k.clone(), deal.clone()))
+            .collect()
+    }
+
+    /// received deals carrying `label`, keyed like [`Agent::incoming_deals`]
+    pub async fn incoming_deals_labeled(&self, label: &str) -> HashMap<String, Deal> {
+        let deals = self.incoming_deals.lock().await;
+        deals
+            .iter()
+            .filter(|(_, (deal, _))| deal.has_label(label))
+            .map(|(k, (deal, _))| (k.clone(), deal.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+    use std::time::Duration;
+    use tokio::time;
+
+    use crate::{
+        deal::{Redundancy, BYTES_PER_MEBIBYTE},
+        peer_info::{encode_announcement, Capabilities, PeerInfoWire, StorageClass},
+        pricing::Undercut,
+        recorder::{read_recording, TrafficRecorder},
+        testing::{
+            agent_mesh, agent_mesh_with, deal_from, start_all, test_peer_info, wait_for_discovery,
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    /// two agents discover each other over loopback sockets
+    /// agents will succeed in matching a deal with one another
+    /// peer1's deal will be matched with peer2 based on its
+    /// `spare_mbs` and `price`.
+    async fn two_agents_communicate() {
+        let peer_info1 = PeerInfo {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            peer_id: PeerId::random(),
+            spare_mbs: 14,
+            price: 15.0,
+            egress_price_per_gib: 2.0,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: Default::default(),
+            region: None,
+            online_since: None,
+            capabilities: Capabilities::default(),
+        };
+
+        let peer_info2 = PeerInfo {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            peer_id: PeerId::random(),
+            spare_mbs: 50,
+            price: 1.0,
+            egress_price_per_gib: 0.5,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: Default::default(),
+            region: None,
+            online_since: None,
+            capabilities: Capabilities::default(),
+        };
+
+        // both agents get ephemeral ports and announce to each other
+        let agents = agent_mesh_with(vec![peer_info1.clone(), peer_info2.clone()])
+            .await
+            .unwrap();
+        let (agent1, agent2) = (agents[0].clone(), agents[1].clone());
+
+        tokio::spawn(agent1.clone().run());
+        tokio::spawn(agent2.clone().run());
+
+        time::sleep(Duration::from_secs(2)).await;
+
+        let peers_agent1 = agent1.discovery.get_peers().await;
+        let peers_agent2 = agent2.discovery.get_peers().await;
+
+        assert!(
+            peers_agent1
+                .iter()
+                .any(|peer| peer.peer_id == peer_info2.peer_id),
+            "agent1 should see agent2"
+        );
+        assert!(
+            peers_agent2
+                .iter()
+                .any(|peer| peer.peer_id == peer_info1.peer_id),
+            "agent2 should see agent1"
+        );
+
+        // the deal advertises agent1's real (ephemeral) control address
+        assert_eq!(agent1.get_peer_info().addr, agent1.receiver_addr().unwrap());
+        let deal1 = Deal {
+            price_per_mb: 10.0,
+            ..deal_from(agent1.get_peer_info(), 40)
+        };
+
+        let expected_deal = deal1.clone();
+        agent1.send_matched_deals(deal1).await.unwrap();
+
+        time::sleep(Duration::from_secs(1)).await;
+        let received_deal = agent2
+            .incoming_deals
+            .lock()
+            .await
+            .get(&agent1.get_peer_info().addr.to_string())
+            .expect("not found")
+            .0
+            .clone();
+
+        assert_eq!(
+            received_deal.peer_info_wire.addr,
+            agent1.get_peer_info().addr
+        );
+        assert_eq!(received_deal.file_len, expected_deal.file_len);
+        assert_eq!(received_deal.price_per_mb, expected_deal.price_per_mb);
+
+        let transport = agent1.transport_stats().await;
+        let stats = transport
+            .get(&peer_info2.peer_id)
+            .expect("no transport stats");
+        assert!(stats.sent_packets > 0 && stats.path_mtu > 0);
+    }
+
+    #[tokio::test]
+    /// with a deadline, peers too slow to ingest the file in time are
+    /// dropped and faster peers are preferred
+    async fn throughput_aware_matching() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let (fast, slow, unmeasured) = (test_peer_info(), test_peer_info(), test_peer_info());
+        for peer in [&fast, &slow, &unmeasured] {
+            agent.discovery.record_announcement(peer.clone()).await;
+        }
+        let mib = BYTES_PER_MEBIBYTE;
+        agent
+            .record_throughput(fast.peer_id, 100 * mib, Duration::from_secs(1))
+            .await;
+        agent
+            .record_throughput(slow.peer_id, mib, Duration::from_secs(1))
+            .await;
+
+        let mut deal = Deal {
+            price_per_mb: 10.0,
+            ..deal_from(agent.get_peer_info(), 50)
+        };
+        let ids = |peers: Vec<PeerInfo>| peers.into_iter().map(|p| p.peer_id).collect::<Vec<_>>();
+
+        let all = ids(agent.matched_peers(&deal).await);
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0], fast.peer_id);
+        assert_eq!(all[1], slow.peer_id);
+
+        deal.deadline_secs = Some(10);
+        let in_time = ids(agent.matched_peers(&deal).await);
+        assert_eq!(in_time, vec![fast.peer_id, unmeasured.peer_id]);
+    }
+
+    #[tokio::test]
+    /// burst deals match against burst space at the burst price, committed
+    /// deals against spare space at the regular price
+    async fn burst_capacity_matching() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let provider = PeerInfo {
+            spare_mbs: 10,
+            burst_mbs: 100,
+            burst_price: 0.5,
+            ..test_peer_info()
+        };
+        agent.discovery.record_announcement(provider.clone()).await;
+
+        let mut deal = Deal {
+            price_per_mb: 0.8,
+            ..deal_from(agent.get_peer_info(), 50)
+        };
+        assert!(agent.matched_peers(&deal).await.is_empty());
+
+        deal.burst = true;
+        assert_eq!(agent.matched_peers(&deal).await, vec![provider]);
+
+        deal.price_per_mb = 0.4;
+        assert!(agent.matched_peers(&deal).await.is_empty());
+    }
+
+    #[tokio::test]
+    /// erasure-coded deals skip providers that don't announce erasure
+    /// coding, and are refused before sending since this build can't encode
+    /// them
+    async fn features_prefilter_providers() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let legacy = PeerInfo {
+            features: Features::default(),
+            ..test_peer_info()
+        };
+        let coder = PeerInfo {
+            features: Features::ERASURE_CODING,
+            ..test_peer_info()
+        };
+        agent.discovery.record_announcement(legacy.clone()).await;
+        agent.discovery.record_announcement(coder.clone()).await;
+
+        let mut deal = deal_from(agent.get_peer_info(), 1);
+        assert_eq!(agent.matched_peers(&deal).await.len(), 2);
+
+        deal.redundancy = Some(Redundancy::ErasureCoded { k: 1, n: 1 });
+        assert_eq!(agent.matched_peers(&deal).await, vec![coder]);
+        assert_eq!(
+            agent.send_matched_deals(deal).await.unwrap_err(),
+            PlacementError::Unsupported {
+                missing: Features::ERASURE_CODING
+            }
+        );
+    }
+
+    #[tokio::test]
+    /// closing the receiver endpoint stops the receive loop; the watchdog
+    /// reopens it on the same address and deals flow again
+    async fn watchdog_reopens_closed_receiver() {
+        let agents = agent_mesh(2).await.unwrap();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        let (sender, receiver) = (&agents[0], &agents[1]);
+        let addr = receiver.receiver_addr().unwrap();
+
+        receiver
+            .receiver_endpoint()
+            .unwrap()
+            .close(0u32.into(), b"test");
+        time::sleep(WATCHDOG_INTERVAL * 3).await;
+        assert_eq!(receiver.receiver_addr().unwrap(), addr);
+
+        let deal = Deal {
+            price_per_mb: 10.0,
+            ..deal_from(sender.get_peer_info(), 1)
+        };
+        sender.send_matched_deals(deal).await.unwrap();
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(receiver.incoming_deals().await.len(), 1);
+    }
+
+    #[test]
+    /// unspecified and multicast addresses can't be advertised
+    fn advertised_addr_checks() {
+        let listen: SocketAddr = "0.0.0.0:5334".parse().unwrap();
+        for bad in ["0.0.0.0:5334", "[::]:5334"] {
+            let addr = bad.parse().unwrap();
+            assert_eq!(
+                check_advertised_addr(addr, listen),
+                Err(AdvertisedAddrError::Unspecified(addr))
+            );
+        }
+        for bad in ["224.0.0.251:5334", "255.255.255.255:5334"] {
+            let addr = bad.parse().unwrap();
+            assert_eq!(
+                check_advertised_addr(addr, listen),
+                Err(AdvertisedAddrError::NotUnicast(addr))
+            );
+        }
+        assert!(check_advertised_addr("203.0.113.7:5334".parse().unwrap(), listen).is_ok());
+    }
+
+    #[tokio::test]
+    /// the receiver binds the listen address while discovery advertises
+    /// the configured one
+    async fn listen_addr_separate_from_advertised() {
+        let advertised: SocketAddr = "127.0.0.2:7000".parse().unwrap();
+        let peer_info = PeerInfo {
+            addr: advertised,
+            ..test_peer_info()
+        };
+        let dsvc = DiscoveryService::test_with_addr(peer_info, "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+        let agent = Agent::with_listen_addr(dsvc, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(agent.get_peer_info().addr, advertised);
+        let bound = agent.receiver_addr().unwrap();
+        assert_eq!(bound.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_ne!(bound.port(), 0);
+    }
+
+    #[tokio::test]
+    /// queued proposals leave highest priority first, in order within a
+    /// priority, and pending deals are reviewed the same way
+    async fn deals_ordered_by_priority() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let deal = |file_len, priority| Deal {
+            file_len,
+            priority,
+            ..deal_from(test_peer_info(), 1)
+        };
+        agent.queue_deal(deal(1, Priority::Low));
+        agent.queue_deal(deal(2, Priority::Normal));
+        agent.queue_deal(deal(3, Priority::High));
+        agent.queue_deal(deal(4, Priority::Normal));
+        let order: Vec<_> = std::iter::from_fn(|| agent.next_queued())
+            .map(|d| d.file_len)
+            .collect();
+        assert_eq!(order, [3, 2, 4, 1]);
+
+        let now = Instant::now();
+        {
+            let mut incoming = agent.incoming_deals.lock().await;
+            incoming.insert("low".into(), (deal(1, Priority::Low), now));
+            incoming.insert("high".into(), (deal(2, Priority::High), now));
+            incoming.insert("old".into(), (deal(3, Priority::Normal), now));
+            let later = now + Duration::from_secs(1);
+            incoming.insert("new".into(), (deal(4, Priority::Normal), later));
+        }
+        let keys: Vec<_> = agent
+            .pending_deals()
+            .await
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["high", "old", "new", "low"]);
+    }
+
+    #[test]
+    /// a suspend shows up as boot-clock time the monotonic clock missed, or
+    /// as a late tick; ordinary jitter does not
+    fn suspend_detection() {
+        let secs = Duration::from_secs;
+        assert_eq!(suspended_for(secs(1), Some(secs(1))), None);
+        assert_eq!(suspended_for(secs(1), Some(secs(3))), None);
+        assert_eq!(suspended_for(secs(1), Some(secs(61))), Some(secs(60)));
+        assert_eq!(suspended_for(secs(31), None), Some(secs(30)));
+        assert_eq!(suspended_for(secs(1), None), None);
+    }
+
+    #[tokio::test]
+    /// stepping the wall clock forward, as NTP or `date -s` do, moves
+    /// neither clock the watchdog reads, so peers and proposals survive it;
+    /// a real suspend advances the boot clock alone and ages them out
+    async fn wall_clock_step_is_not_a_suspend() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        assert!(agent.discovery.learn_peer(test_peer_info()).await);
+        let before = ClockReading::now();
+        let tick = |boot_extra: Duration| ClockReading {
+            monotonic: before.monotonic + WATCHDOG_INTERVAL,
+            boot: Some(before.boot.unwrap_or_default() + WATCHDOG_INTERVAL + boot_extra),
+        };
+
+        // an hour's step shows only on the wall clock, which isn't read
+        assert_eq!(tick(Duration::ZERO).suspended_since(&before), None);
+        assert_eq!(agent.discovery.get_peers().await.len(), 1);
+        assert_eq!(agent.health().await.suspends, 0);
+
+        let slept = tick(Duration::from_secs(3600))
+            .suspended_since(&before)
+            .expect("a suspend");
+        agent.resume(slept).await;
+        assert!(agent.discovery.get_peers().await.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    /// the boot clock is available and keeps pace with the monotonic clock
+    /// while awake
+    fn boot_clock_tracks_monotonic() {
+        let before = ClockReading::now();
+        std::thread::sleep(Duration::from_millis(50));
+        let after = ClockReading::now();
+        let boot = after.boot.unwrap() - before.boot.unwrap();
+        assert!(boot >= Duration::from_millis(50));
+        assert_eq!(after.suspended_since(&before), None);
+    }
+
+    #[tokio::test]
+    /// deals older than the proposal TTL are dropped and counted
+    async fn stale_proposals_expire() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let deal = deal_from(test_peer_info(), 1);
+        let lapsed = Instant::now() - DEFAULT_PROPOSAL_TTL - Duration::from_secs(1);
+        {
+            let mut deals = agent.incoming_deals.lock().await;
+            deals.insert("old".into(), (deal.clone(), lapsed));
+            deals.insert("new".into(), (deal, Instant::now()));
+        }
+
+        assert_eq!(agent.expire_proposals().await, 1);
+        let remaining = agent.incoming_deals().await;
+        assert!(remaining.contains_key("new") && !remaining.contains_key("old"));
+        assert_eq!(agent.health().await.expired_deals, 1);
+    }
+
+    #[tokio::test]
+    /// connections from hosts outside the filter are refused before any deal
+    /// is read; known peers get through once allowed
+    async fn accept_filter_refuses_strangers() {
+        let agents = agent_mesh(2).await.unwrap();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        let (sender, receiver) = (&agents[0], &agents[1]);
+        let deal = Deal {
+            price_per_mb: 10.0,
+            ..deal_from(sender.get_peer_info(), 1)
+        };
+
+        receiver.set_accept_filter(AcceptFilter {
+            known_peers: false,
+            allowlist: HashSet::from(["127.0.0.2".parse().unwrap()]),
+        });
+        sender.send_matched_deals(deal.clone()).await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(receiver.incoming_deals().await.is_empty());
+        assert_eq!(receiver.health().await.refused_connections, 1);
+
+        receiver.set_accept_filter(AcceptFilter {
+            known_peers: true,
+            allowlist: HashSet::new(),
+        });
+        sender.send_matched_deals(deal).await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(receiver.incoming_deals().await.len(), 1);
+    }
+
+    #[tokio::test]
+    /// a garbage stream is counted as a deserialize error and a failed
+    /// delivery as a send error in the health summary
+    async fn health_counts_errors() {
+        let agents = agent_mesh(2).await.unwrap();
+        start_all(&agents).await;
+        let (sender, receiver) = (&agents[0], &agents[1]);
+
+        let conn = sender
+            .sender_endpoint
+            .connect(receiver.receiver_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let mut uni = conn.open_uni().await.unwrap();
+        uni.write_all(b"not a deal").await.unwrap();
+        uni.finish().unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+
+        // port 0 can't be dialed, so delivery to this peer fails
+        sender.discovery.record_announcement(test_peer_info()).await;
+        let deal = Deal {
+            price_per_mb: 10.0,
+            ..deal_from(sender.get_peer_info(), 1)
+        };
+        sender.send_matched_deals(deal).await.unwrap();
+
+        let health = receiver.health().await;
+        assert!(health.receiver_up);
+        assert_eq!(health.errors.deserialize, 1);
+        assert_eq!(sender.health().await.errors.send, 1);
+    }
+
+    #[tokio::test]
+    /// after shutdown the watchdog leaves the receiver closed
+    async fn shutdown_stops_receiver() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        agent.clone().run().await;
+        agent.shutdown().await;
+        time::sleep(WATCHDOG_INTERVAL * 2).await;
+        assert!(!agent.health().await.receiver_up);
+    }
+
+    #[tokio::test]
+    /// with backfill on, a proposer missing from the peer table is added at
+    /// its source IP and advertised port if the accept filter vets that IP;
+    /// known peers keep their announced entry
+    async fn backfills_unknown_proposer() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let agent = Arc::into_inner(agent).unwrap().with_proposer_backfill(true);
+        let proposer = PeerInfo {
+            addr: "0.0.0.0:7000".parse().unwrap(),
+            ..test_peer_info()
+        };
+        let deal = deal_from(proposer.clone(), 1);
+        let remote: SocketAddr = "10.0.0.5:53211".parse().unwrap();
+        agent.store_deal(deal.clone(), remote).await;
+        assert!(agent.discovery.get_peers().await.is_empty());
+
+        agent.set_accept_filter(AcceptFilter {
+            known_peers: false,
+            allowlist: [remote.ip()].into(),
+        });
+        agent.store_deal(deal.clone(), remote).await;
+        let peers = agent.discovery.get_peers().await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, proposer.peer_id);
+        assert_eq!(peers[0].addr, "10.0.0.5:7000".parse().unwrap());
+
+        agent.store_deal(deal, "10.0.0.6:1".parse().unwrap()).await;
+        assert_eq!(agent.discovery.get_peers().await, peers);
+    }
+
+    #[tokio::test]
+    /// every reason a peer is excluded is reported, against the per-provider
+    /// share of the deal
+    async fn explain_match_reasons() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let fits = PeerInfo {
+            spare_mbs: 10,
+            price: 1.0,
+            features: Features::ERASURE_CODING,
+            ..test_peer_info()
+        };
+        let small_and_dear = PeerInfo {
+            spare_mbs: 2,
+            price: 3.0,
+            features: Features::default(),
+            ..test_peer_info()
+        };
+        agent.discovery.record_announcement(fits.clone()).await;
+        agent
+            .discovery
+            .record_announcement(small_and_dear.clone())
+            .await;
+
+        // 8 MiB split into 2-of-2 shards: each provider stores 4 MiB
+        let deal = Deal {
+            price_per_mb: 2.0,
+            redundancy: Some(Redundancy::ErasureCoded { k: 2, n: 2 }),
+            ..deal_from(agent.get_peer_info(), 8)
+        };
+        let explanations = agent.explain_match(&deal).await;
+        let find = |peer: &PeerInfo| {
+            explanations
+                .iter()
+                .find(|e| e.peer_id == peer.peer_id)
+                .unwrap()
+                .mismatches
+                .clone()
+        };
+        assert_eq!(find(&fits), vec![]);
+        assert_eq!(
+            find(&small_and_dear),
+            vec![
+                Mismatch::Capacity {
+                    available: 2 * BYTES_PER_MEBIBYTE,
+                    needed: 4 * BYTES_PER_MEBIBYTE
+                },
+                Mismatch::Price {
+                    asking: 3.0,
+                    offered: 2.0
+                },
+                Mismatch::Features {
+                    missing: Features::ERASURE_CODING
+                },
+            ]
+        );
+
+        let cold = Deal {
+            storage_class: Some(StorageClass::Cold),
+            ..deal
+        };
+        assert!(agent
+            .explain_match(&cold)
+            .await
+            .iter()
+            .all(|e| e.mismatches.contains(&Mismatch::NoOffer)));
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    /// injected faults stop discovery and fail sends until they are lifted
+    async fn injected_faults() {
+        use crate::chaos::Faults;
+
+        let agents = agent_mesh(2).await.unwrap();
+        for agent in &agents {
+            agent.faults().set(Faults {
+                drop_announcements: 1.0,
+                fail_sends: 1.0,
+                ..Faults::default()
+            });
+        }
+        start_all(&agents).await;
+        assert!(!wait_for_discovery(&agents, Duration::from_secs(1)).await);
+
+        agents[0].faults().set(Faults {
+            fail_sends: 1.0,
+            ..Faults::default()
+        });
+        agents[1].faults().set(Faults::default());
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+
+        let deal = deal_from(agents[0].get_peer_info(), 1);
+        agents[0].send_matched_deals(deal).await.unwrap();
+        assert_eq!(agents[0].health().await.errors.send, 1);
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(agents[1].pending_deals().await.is_empty());
+    }
+
+    #[tokio::test]
+    /// a peer at its stream limit fails further sends fast under
+    /// `Overflow::FailFast` and makes them wait under `Overflow::Queue`
+    async fn per_peer_send_limits() {
+        let limits = SendLimits {
+            max_streams_per_peer: 1,
+            overflow: Overflow::FailFast,
+            ..SendLimits::default()
+        };
+        let mut agents = agent_mesh(2).await.unwrap();
+        let receiver = agents.pop().unwrap();
+        let sender = Arc::into_inner(agents.pop().unwrap()).unwrap();
+        let sender = Arc::new(sender.with_send_limits(limits));
+        let agents = [sender.clone(), receiver.clone()];
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+
+        let deal = deal_from(sender.get_peer_info(), 1);
+        let slots = sender.send_slots(receiver.get_peer_info().peer_id);
+        let held = slots.acquire(1, Overflow::Queue).await.unwrap();
+        sender.send_matched_deals(deal.clone()).await.unwrap();
+        assert_eq!(sender.health().await.send_overflows, 1);
+
+        let queued = tokio::spawn({
+            let sender = sender.clone();
+            let slots = slots.clone();
+            async move {
+                let _permits = slots.acquire(1, Overflow::Queue).await;
+                sender.health().await.send_overflows
+            }
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!queued.is_finished());
+        drop(held);
+        assert_eq!(queued.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    /// the watchdog samples advertised prices and received deals are logged
+    async fn market_prices_recorded() {
+        let agents = agent_mesh_with(vec![
+            PeerInfo {
+                price: 2.0,
+                ..test_peer_info()
+            },
+            PeerInfo {
+                price: 3.0,
+                ..test_peer_info()
+            },
+        ])
+        .await
+        .unwrap();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        time::sleep(WATCHDOG_INTERVAL * 2).await;
+        assert_eq!(agents[0].median_price(), Some(3.0));
+
+        let deal = Deal {
+            price_per_mb: 4.0,
+            ..deal_from(agents[0].get_peer_info(), 1)
+        };
+        agents[0].send_matched_deals(deal).await.unwrap();
+        time::sleep(Duration::from_millis(500)).await;
+        let history = agents[1].price_history();
+        let deals: Vec<f32> = history
+            .buckets()
+            .flat_map(|b| b.deal_prices.clone())
+            .collect();
+        assert_eq!(deals, vec![4.0]);
+        assert_eq!(
+            agents[1].health().await.demand,
+            Demand {
+                proposals: 1,
+                requested_bytes: BYTES_PER_MEBIBYTE,
+                over_capacity: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    /// with an undercutting strategy the agent reprices on its first tick and
+    /// announces the new price
+    async fn undercut_reprices_and_announces() {
+        let mut agents = agent_mesh_with(vec![
+            PeerInfo {
+                price: 4.0,
+                ..test_peer_info()
+            },
+            PeerInfo {
+                price: 2.0,
+                ..test_peer_info()
+            },
+        ])
+        .await
+        .unwrap();
+        let competitor = agents.pop().unwrap();
+        let provider = Arc::into_inner(agents.pop().unwrap()).unwrap();
+        let provider = Arc::new(provider.with_pricing(Undercut {
+            percent: 25.0,
+            floor: 0.5,
+            ceiling: 10.0,
+        }));
+        let agents = [provider.clone(), competitor.clone()];
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+
+        // the first tick may run before discovery; wait for a reprice with a
+        // competitor in view
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while competitor.discovery.get_peers().await[0].price != 3.0 {
+            assert!(Instant::now() < deadline, "never repriced");
+            provider.reprice(&[4.0]);
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(provider.get_peer_info().price, 3.0);
+    }
+
+    #[tokio::test]
+    /// a previewed plan picks the cheapest provider and is only sent on
+    /// request; a provider that failed deliveries is skipped once a minimum
+    /// reliability is set
+    async fn plan_then_send_cheapest() {
+        let agents = agent_mesh_with(vec![
+            test_peer_info(),
+            PeerInfo {
+                price: 2.0,
+                ..test_peer_info()
+            },
+            PeerInfo {
+                price: 1.0,
+                ..test_peer_info()
+            },
+        ])
+        .await
+        .unwrap();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        let (consumer, pricey, cheap) = (&agents[0], &agents[1], &agents[2]);
+
+        let deal = Deal {
+            price_per_mb: 5.0,
+            redundancy: Some(Redundancy::Replicas(1)),
+            ..deal_from(consumer.get_peer_info(), 4)
+        };
+        let plan = consumer
+            .plan_placement(&deal, &Constraints::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            plan.placement.assignments[0].0.peer_id,
+            cheap.get_peer_info().peer_id
+        );
+        assert_eq!(plan.total_cost, 4.0);
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(cheap.incoming_deals().await.is_empty());
+
+        consumer.send_plan(&plan).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while cheap.incoming_deals().await.is_empty() {
+            assert!(Instant::now() < deadline, "deal never arrived");
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(pricey.incoming_deals().await.is_empty());
+
+        consumer
+            .peer_delivery
+            .lock()
+            .await
+            .entry(cheap.get_peer_info().peer_id)
+            .or_default()
+            .record(false);
+        let constraints = Constraints {
+            min_reliability: Some(0.9),
+            ..Constraints::default()
+        };
+        let plan = consumer.plan_placement(&deal, &constraints).await.unwrap();
+        assert_eq!(
+            plan.placement.assignments[0].0.peer_id,
+            pricey.get_peer_info().peer_id
+        );
+    }
+
+    #[tokio::test]
+    /// a simulated deal reports the peers and cost a real send would use,
+    /// without anything reaching them
+    async fn simulate_sends_nothing() {
+        let agents = agent_mesh(2).await.unwrap();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        let (consumer, provider) = (&agents[0], &agents[1]);
+        let deal = deal_from(consumer.get_peer_info(), 3);
+        let plan = consumer.simulate_deal(&deal).await.unwrap();
+        assert_eq!(plan.placement.assignments.len(), 1);
+        assert_eq!(
+            plan.placement.assignments[0].0.peer_id,
+            provider.get_peer_info().peer_id
+        );
+        assert_eq!(plan.total_cost, 3.0);
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(provider.incoming_deals().await.is_empty());
+        assert_eq!(consumer.health().await.errors.send, 0);
+    }
+
+    /// a fixed peer list, standing in for a non-multicast backend
+    struct StaticPeers {
+        me: StdRwLock<PeerInfo>,
+        peers: Vec<PeerInfo>,
+        events: tokio::sync::broadcast::Sender<DiscoveryEvent>,
+    }
+
+    impl Discovery for StaticPeers {
+        fn get_peers(&self) -> futures::future::BoxFuture<'_, Vec<PeerInfo>> {
+            Box::pin(async { self.peers.clone() })
+        }
+
+        fn subscribe(&self) -> tokio::sync::broadcast::Receiver<DiscoveryEvent> {
+            self.events.subscribe()
+        }
+
+        fn get_peer_info(&self) -> PeerInfo {
+            self.me.read().unwrap().clone()
+        }
+
+        fn announce(&self, peer_info: PeerInfo) {
+            *self.me.write().unwrap() = peer_info;
+        }
+
+        fn start(self: Arc<Self>) -> futures::future::BoxFuture<'static, ()> {
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    /// an agent runs on any discovery backend: one that only knows a static
+    /// peer list advertises its bound port and delivers to a listed peer
+    async fn agent_on_static_discovery() {
+        let provider = agent_mesh(1).await.unwrap().remove(0);
+        provider.clone().run().await;
+        let consumer = Arc::new(
+            Agent::with_discovery(StaticPeers {
+                me: StdRwLock::new(test_peer_info()),
+                peers: vec![provider.get_peer_info()],
+                events: tokio::sync::broadcast::channel(1).0,
+            })
+            .await
+            .unwrap(),
+        );
+        consumer.clone().run().await;
+        assert_eq!(
+            consumer.get_peer_info().addr,
+            consumer.receiver_addr().unwrap()
+        );
+
+        let deal = deal_from(consumer.get_peer_info(), 1);
+        consumer.send_matched_deals(deal.clone()).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        let received: Vec<_> = provider.incoming_deals().await.into_values().collect();
+        assert_eq!(received, vec![deal]);
+        assert_eq!(consumer.health().await.peers, 1);
+    }
+
+    struct NeedsRegion;
+
+    impl MatchPolicy for NeedsRegion {
+        fn reject(&self, peer: &PeerInfo, _deal: &Deal) -> Option<String> {
+            (!peer.metadata.contains_key("region")).then(|| "no region announced".into())
+        }
+    }
+
+    struct NoSpam;
+
+    impl ApprovalHook for NoSpam {
+        fn approve(&self, deal: &Deal, _remote: SocketAddr) -> bool {
+            !deal.labels.iter().any(|label| label == "spam")
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<StdMutex<Vec<Event>>>);
+
+    impl EventSink for Recorder {
+        fn event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    /// registered plugins filter matches, gate received deals, and see events
+    async fn plugins_filter_approve_and_report() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let events = Recorder::default();
+        let agent = Arc::into_inner(agent)
+            .unwrap()
+            .with_match_policy(NeedsRegion)
+            .with_approval_hook(NoSpam)
+            .with_event_sink(events.clone());
+
+        let placed = PeerInfo {
+            metadata: [("region".to_string(), "eu".to_string())].into(),
+            ..test_peer_info()
+        };
+        let unplaced = test_peer_info();
+        agent.discovery.record_announcement(placed.clone()).await;
+        agent.discovery.record_announcement(unplaced.clone()).await;
+        let deal = deal_from(placed.clone(), 1);
+        assert_eq!(agent.matched_peers(&deal).await, vec![placed]);
+        let excluded = agent
+            .explain_match(&deal)
+            .await
+            .into_iter()
+            .find(|e| e.peer_id == unplaced.peer_id)
+            .unwrap();
+        assert_eq!(
+            excluded.mismatches,
+            [Mismatch::Policy("no region announced".into())]
+        );
+
+        let remote: SocketAddr = "10.0.0.5:53211".parse().unwrap();
+        let spam = Deal {
+            labels: vec!["spam".into()],
+            ..deal.clone()
+        };
+        agent.store_deal(spam.clone(), remote).await;
+        assert!(agent.incoming_deals().await.is_empty());
+        agent.store_deal(deal.clone(), remote).await;
+        assert_eq!(agent.incoming_deals().await.len(), 1);
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [
+                Event::DealRejected {
+                    deal_id: spam.id(),
+                    remote
+                },
+                Event::DealReceived {
+                    deal_id: deal.id(),
+                    remote,
+                    cost: 1.0
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    /// operator overrides decide matching, pricing and approval ahead of
+    /// the agent's own checks and hooks
+    async fn peer_overrides_take_precedence() {
+        let agent = agent_mesh(1).await.unwrap().remove(0);
+        let events = Recorder::default();
+        let agent = Arc::into_inner(agent)
+            .unwrap()
+            .with_approval_hook(NoSpam)
+            .with_event_sink(events.clone());
+
+        let (mut blocked, mut capped, mut discounted) =
+            (test_peer_info(), test_peer_info(), test_peer_info());
+        for (peer, addr) in [
+            (&mut blocked, "10.0.0.6:5334"),
+            (&mut capped, "10.0.0.7:5334"),
+            (&mut discounted, "10.0.0.5:5334"),
+        ] {
+            peer.addr = addr.parse().unwrap();
+            agent.discovery.record_announcement(peer.clone()).await;
+        }
+        agent.set_peer_override(
+            blocked.peer_id,
+            PeerOverride {
+                trust: Some(Trust::Blocked),
+                ..Default::default()
+            },
+        );
+        agent.set_peer_override(
+            capped.peer_id,
+            PeerOverride {
+                max_deal_bytes: Some(BYTES_PER_MEBIBYTE),
+                ..Default::default()
+            },
+        );
+        agent.set_peer_override(
+            discounted.peer_id,
+            PeerOverride {
+                trust: Some(Trust::Trusted),
+                price: Some(0.5),
+                ..Default::default()
+            },
+        );
+        let deal = Deal {
+            labels: vec!["spam".into()],
+            ..deal_from(discounted.clone(), 2)
+        };
+
+        let plan = agent.simulate_deal(&deal).await.unwrap();
+        assert_eq!(plan.placement.assignments.len(), 1);
+        assert_eq!(plan.placement.assignments[0].0.peer_id, discounted.peer_id);
+        assert_eq!(plan.total_cost, 1.0);
+        let reasons: HashMap<_, _> = agent
+            .explain_match(&deal)
+            .await
+            .into_iter()
+            .map(|e| (e.peer_id, e.mismatches))
+            .collect();
+        assert_eq!(reasons[&blocked.peer_id], [Mismatch::Blocked]);
+        assert_eq!(
+            reasons[&capped.peer_id],
+            [Mismatch::OverQuota {
+                max_deal_bytes: BYTES_PER_MEBIBYTE,
+                needed: 2 * BYTES_PER_MEBIBYTE
+            }]
+        );
+
+        // the trusted proposer skips the approval hook that refuses spam;
+        // the blocked and capped ones are refused
+        for proposer in [&blocked, &capped, &discounted] {
+            let deal = Deal {
+                peer_info_wire: PeerInfoWire::from(proposer.clone()),
+                ..deal.clone()
+            };
+            let remote = SocketAddr::new(proposer.addr.ip(), 53211);
+            agent.store_deal(deal, remote).await;
+        }
+        let received: Vec<_> = agent.incoming_deals().await.into_values().collect();
+        assert_eq!(received, vec![deal.clone()]);
+
+        // peer ids in deals are unverified: claiming the trusted id from
+        // elsewhere doesn't skip the hook, and the blocked host is refused
+        // under any id
+        let spoofer: SocketAddr = "10.0.0.9:53211".parse().unwrap();
+        agent.store_deal(deal.clone(), spoofer).await;
+        let evader = Deal {
+            peer_info_wire: PeerInfoWire::from(test_peer_info()),
+            labels: vec![],
+            ..deal
+        };
+        agent
+            .store_deal(evader, SocketAddr::new(blocked.addr.ip(), 53211))
+            .await;
+        assert_eq!(agent.incoming_deals().await.len(), 1);
+        let rejected = events
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| matches!(e, Event::DealRejected { .. }))
+            .count();
+        assert_eq!(rejected, 4);
+    }
+
+    #[tokio::test]
+    /// a connection that never opens a stream is closed after the idle
+    /// timeout instead of pinning the receiver, and send state for peers
+    /// that left the table is dropped
+    async fn idle_connections_closed_and_state_pruned() {
+        let agents = agent_mesh(2).await.unwrap();
+        let limits = EndpointLimits {
+            idle_timeout: Duration::from_millis(200),
+            ..EndpointLimits::default()
+        };
+        let agents: Vec<_> = agents
+            .into_iter()
+            .map(|agent| {
+                Arc::new(
+                    Arc::into_inner(agent)
+                        .unwrap()
+                        .with_endpoint_limits(limits)
+                        .unwrap(),
+                )
+            })
+            .collect();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        let (sender, receiver) = (&agents[0], &agents[1]);
+
+        let idle = sender
+            .sender_endpoint
+            .connect(receiver.receiver_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(2), idle.closed())
+            .await
+            .expect("idle connection was not closed");
+
+        let deal = deal_from(sender.get_peer_info(), 1);
+        sender.send_matched_deals(deal).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(receiver.incoming_deals().await.len(), 1);
+
+        let gone = PeerId::random();
+        sender.send_slots(gone);
+        sender.prune_peer_state().await;
+        let slots = sender.send_slots.lock().unwrap();
+        assert!(!slots.contains_key(&gone));
+        assert!(slots.contains_key(&receiver.get_peer_info().peer_id));
+    }
+
+    #[tokio::test]
+    /// a client that keeps its connection alive with pings but never opens
+    /// a stream doesn't hold up other deals, and is cut off after the read
+    /// timeout
+    async fn stalled_connections_dont_block_intake() {
+        let agents = agent_mesh(2).await.unwrap();
+        let limits = EndpointLimits {
+            read_timeout: Duration::from_millis(300),
+            ..EndpointLimits::default()
+        };
+        let agents: Vec<_> = agents
+            .into_iter()
+            .map(|agent| {
+                Arc::new(
+                    Arc::into_inner(agent)
+                        .unwrap()
+                        .with_endpoint_limits(limits)
+                        .unwrap(),
+                )
+            })
+            .collect();
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+        let (sender, receiver) = (&agents[0], &agents[1]);
+
+        let mut config = client_config(&EndpointLimits::default(), Encoding::default());
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(Duration::from_millis(50)));
+        config.transport_config(Arc::new(transport));
+        let stalled = sender
+            .sender_endpoint
+            .connect_with(config, receiver.receiver_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        let deal = deal_from(sender.get_peer_info(), 1);
+        sender.send_matched_deals(deal).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(receiver.incoming_deals().await.len(), 1);
+
+        time::timeout(Duration::from_secs(2), stalled.closed())
+            .await
+            .expect("stalled connection was not closed");
+    }
+
+    #[tokio::test]
+    /// a sender switched to JSON is understood by a default receiver
+    async fn json_encoding_per_connection() {
+        let mut agents = agent_mesh(2).await.unwrap();
+        let receiver = agents.pop().unwrap();
+        let sender = Arc::new(
+            Arc::into_inner(agents.pop().unwrap())
+                .unwrap()
+                .with_encoding(Encoding::Json),
+        );
+        let agents = [sender.clone(), receiver.clone()];
+        start_all(&agents).await;
+        assert!(wait_for_discovery(&agents, Duration::from_secs(5)).await);
+
+        let deal = Deal {
+            labels: vec!["debug".into()],
+            ..deal_from(sender.get_peer_info(), 1)
+        };
+        sender.send_matched_deals(deal.clone()).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        let received: Vec<_> = receiver.incoming_deals().await.into_values().collect();
+        assert_eq!(received, vec![deal]);
+        assert_eq!(receiver.health().await.errors.total(), 0);
+    }
+
+    #[tokio::test]
+    /// a recording of inbound traffic replayed into an offline agent
+    /// reproduces the peer table and proposals; outbound records are skipped
+    async fn replay_recorded_traffic() {
+        let path = std::env::temp_dir().join(format!("sparenet-replay-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let provider = PeerInfo {
+            addr: "10.0.0.7:7000".parse().unwrap(),
+            ..test_peer_info()
+        };
+        let deal = deal_from(provider.clone(), 1);
+        let from: SocketAddr = "10.0.0.7:5000".parse().unwrap();
+        {
+            let recorder = TrafficRecorder::create(&path).unwrap();
+            let payload = encode_announcement(&PeerInfoWire::from(provider.clone()));
+            recorder.record(Direction::In, from, Message::Announcement { payload });
+            recorder.record(
+                Direction::In,
+                from,
+                Message::Deal {
+                    deal: Box::new(deal.clone()),
+                },
+            );
+            recorder.record(
+                Direction::Out,
+                from,
+                Message::Deal {
+                    deal: Box::new(deal.clone()),
+                },
+            );
+        }
+
+        let records = read_recording(&path).unwrap().records;
+        std::fs::remove_file(&path).unwrap();
+        let discovery = DiscoveryService::offline(test_peer_info()).await.unwrap();
+        let agent = Agent::with_discovery(discovery).await.unwrap();
+        let stats = agent.replay(records).await;
+        assert_eq!((stats.announcements, stats.deals, stats.skipped), (1, 1, 1));
+        assert_eq!(agent.discovery.get_peers().await, vec![provider]);
+        let received: Vec<_> = agent.incoming_deals().await.into_values().collect();
+        assert_eq!(received, vec![deal]);
     }
 }
@@ -0,0 +1,48 @@
+//! Fault injection for chaos testing, compiled in with the `chaos` feature.
+//! Faults can be changed at runtime through [`FaultInjector::set`], so a
+//! test or operator can break a running agent and watch it recover.
+
+use std::{sync::RwLock, time::Duration};
+
+/// Faults to inject. The default injects none.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Faults {
+    /// fraction of received announcements discarded, from 0.0 to 1.0
+    pub drop_announcements: f64,
+    /// fraction of outgoing deals failed before dialing, from 0.0 to 1.0
+    pub fail_sends: f64,
+    /// added before each received deal is stored
+    pub receive_delay: Duration,
+}
+
+/// Current [`Faults`] of an agent, shared by its discovery service.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    faults: RwLock<Faults>,
+}
+
+impl FaultInjector {
+    pub fn set(&self, faults: Faults) {
+        *self.faults.write().expect("faults lock poisoned") = faults;
+    }
+
+    pub fn get(&self) -> Faults {
+        *self.faults.read().expect("faults lock poisoned")
+    }
+
+    pub(crate) fn drop_announcement(&self) -> bool {
+        roll(self.get().drop_announcements)
+    }
+
+    pub(crate) fn fail_send(&self) -> bool {
+        roll(self.get().fail_sends)
+    }
+
+    pub(crate) fn receive_delay(&self) -> Duration {
+        self.get().receive_delay
+    }
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::random::<f64>() < probability
+}
@@ -0,0 +1,416 @@
+//! Several [`Discovery`] backends run side by side as one, e.g. multicast on
+//! the LAN plus a static list of remote peers. Peer tables are merged by
+//! `PeerId`; when more than one backend knows a peer, the record from the
+//! earliest-added backend wins, and every backend that knows it is listed by
+//! [`CompositeDiscovery::sourced_peers`].
+
+use futures::future::{self, BoxFuture};
+use libp2p::PeerId;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+use tracing::{debug, warn};
+
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjector;
+use crate::{
+    discovery::{Discovery, DiscoveryEvent, DiscoveryStats, EVENT_CAPACITY},
+    metrics::{ErrorCounts, RejectionCounts},
+    peer_info::PeerInfo,
+    recorder::TrafficRecorder,
+};
+
+/// Pause before a backend whose `start` returned is restarted.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// A merged peer and the names of the backends that know it, first the one
+/// its record came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcedPeer {
+    pub peer: PeerInfo,
+    pub sources: Vec<String>,
+}
+
+/// Runs every backend concurrently and presents their merged peer tables.
+/// Our own announcement goes to all of them. A backend that stops is
+/// restarted on its own, so the others keep running and `start` never
+/// returns.
+pub struct CompositeDiscovery {
+    sources: Vec<(String, Arc<dyn Discovery>)>,
+    events: broadcast::Sender<DiscoveryEvent>,
+    /// peers subscribers have been told about and not yet told expired
+    known: Mutex<HashSet<PeerId>>,
+}
+
+impl CompositeDiscovery {
+    /// `primary` supplies our own peer info and takes peers learned
+    /// outside discovery.
+    pub fn new(name: impl Into<String>, primary: Arc<dyn Discovery>) -> Self {
+        CompositeDiscovery {
+            sources: vec![(name.into(), primary)],
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            known: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Add a backend, ranked below those added before it.
+    pub fn with_source(mut self, name: impl Into<String>, backend: Arc<dyn Discovery>) -> Self {
+        self.sources.push((name.into(), backend));
+        self
+    }
+
+    /// names of the backends, in rank order
+    pub fn source_names(&self) -> Vec<&str> {
+        self.sources.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// every known peer with the backends that know it
+    pub async fn sourced_peers(&self) -> Vec<SourcedPeer> {
+        let mut merged: Vec<SourcedPeer> = vec![];
+        for (name, source) in &self.sources {
+            for peer in source.get_peers().await {
+                match merged.iter_mut().find(|m| m.peer.peer_id == peer.peer_id) {
+                    Some(m) => m.sources.push(name.clone()),
+                    None => merged.push(SourcedPeer {
+                        peer,
+                        sources: vec![name.clone()],
+                    }),
+                }
+            }
+        }
+        merged
+    }
+
+    /// the winning record for `peer_id` and the rank of its backend
+    async fn lookup(&self, peer_id: PeerId) -> Option<(usize, PeerInfo)> {
+        for (rank, (_, source)) in self.sources.iter().enumerate() {
+            if let Some(peer) = source
+                .get_peers()
+                .await
+                .into_iter()
+                .find(|p| p.peer_id == peer_id)
+            {
+                return Some((rank, peer));
+            }
+        }
+        None
+    }
+
+    fn emit(&self, event: DiscoveryEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    /// run the backend ranked `rank`, restarting it whenever it stops
+    async fn run_source(&self, rank: usize) {
+        let (name, source) = &self.sources[rank];
+        loop {
+            source.clone().start().await;
+            warn!("discovery source {name} stopped, restarting it");
+            time::sleep(RESTART_DELAY).await;
+            if let Err(e) = source.restart().await {
+                warn!("failed to restart discovery source {name}: {e}");
+            }
+        }
+    }
+
+    /// pass on `events` from the backend ranked `rank`, as changes to the
+    /// merged table
+    async fn follow_source(&self, rank: usize, mut events: broadcast::Receiver<DiscoveryEvent>) {
+        loop {
+            let peer_id = match events.recv().await {
+                Ok(DiscoveryEvent::PeerDiscovered(peer) | DiscoveryEvent::PeerUpdated(peer)) => {
+                    peer.peer_id
+                }
+                Ok(DiscoveryEvent::PeerExpired(peer_id)) => peer_id,
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("missed {missed} events from {}", self.sources[rank].0);
+                    self.resync().await;
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            self.reconcile(peer_id, rank).await;
+        }
+    }
+
+    /// tell subscribers how `peer_id` changed in the merged table after the
+    /// backend ranked `rank` reported it
+    async fn reconcile(&self, peer_id: PeerId, rank: usize) {
+        match self.lookup(peer_id).await {
+            Some((winner, peer)) => {
+                let new = self
+                    .known
+                    .lock()
+                    .expect("known lock poisoned")
+                    .insert(peer_id);
+                if new {
+                    self.emit(DiscoveryEvent::PeerDiscovered(peer));
+                } else if winner >= rank {
+                    // a change to a copy a higher-ranked backend overrides is
+                    // invisible; anything else changed the record, or, if
+                    // this backend dropped its copy, handed the peer on
+                    self.emit(DiscoveryEvent::PeerUpdated(peer));
+                }
+            }
+            None => {
+                if self
+                    .known
+                    .lock()
+                    .expect("known lock poisoned")
+                    .remove(&peer_id)
+                {
+                    self.emit(DiscoveryEvent::PeerExpired(peer_id));
+                }
+            }
+        }
+    }
+
+    /// after missing events, compare the merged table with what subscribers
+    /// were told and report the difference
+    async fn resync(&self) {
+        let peers = self.get_peers().await;
+        let current: HashSet<PeerId> = peers.iter().map(|p| p.peer_id).collect();
+        let (gone, added) = {
+            let mut known = self.known.lock().expect("known lock poisoned");
+            let gone: Vec<PeerId> = known.difference(&current).copied().collect();
+            let added: Vec<PeerInfo> = peers
+                .into_iter()
+                .filter(|p| !known.contains(&p.peer_id))
+                .collect();
+            *known = current;
+            (gone, added)
+        };
+        for peer_id in gone {
+            self.emit(DiscoveryEvent::PeerExpired(peer_id));
+        }
+        for peer in added {
+            self.emit(DiscoveryEvent::PeerDiscovered(peer));
+        }
+    }
+}
+
+impl Discovery for CompositeDiscovery {
+    fn get_peers(&self) -> BoxFuture<'_, Vec<PeerInfo>> {
+        Box::pin(async {
+            self.sourced_peers()
+                .await
+                .into_iter()
+                .map(|sourced| sourced.peer)
+                .collect()
+        })
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.events.subscribe()
+    }
+
+    fn get_peer_info(&self) -> PeerInfo {
+        self.sources[0].1.get_peer_info()
+    }
+
+    fn announce(&self, peer_info: PeerInfo) {
+        for (_, source) in &self.sources {
+            source.announce(peer_info.clone());
+        }
+    }
+
+    fn start(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let receivers: Vec<_> = self
+                .sources
+                .iter()
+                .map(|(_, source)| source.subscribe())
+                .collect();
+            // report what the backends already know
+            self.resync().await;
+            future::join(
+                future::join_all((0..self.sources.len()).map(|rank| self.run_source(rank))),
+                future::join_all(
+                    receivers
+                        .into_iter()
+                        .enumerate()
+                        .map(|(rank, events)| self.follow_source(rank, events)),
+                ),
+            )
+            .await;
+        })
+    }
+
+    /// added to the primary backend, unless any backend already knows it
+    fn learn_peer(&self, peer_info: PeerInfo) -> BoxFuture<'_, bool> {
+        Box::pin(async move {
+            if self.lookup(peer_info.peer_id).await.is_some() {
+                return false;
+            }
+            self.sources[0].1.learn_peer(peer_info).await
+        })
+    }
+
+    fn age_peers(&self, by: Duration) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            future::join_all(self.sources.iter().map(|(_, source)| source.age_peers(by))).await;
+        })
+    }
+
+    fn stats(&self) -> DiscoveryStats {
+        self.sources
+            .iter()
+            .map(|(_, source)| source.stats())
+            .fold(DiscoveryStats::default(), |sum, stats| sum + stats)
+    }
+
+    fn rejections(&self) -> RejectionCounts {
+        self.sources
+            .iter()
+            .map(|(_, source)| source.rejections())
+            .fold(RejectionCounts::default(), |sum, counts| sum + counts)
+    }
+
+    fn errors(&self) -> ErrorCounts {
+        self.sources
+            .iter()
+            .map(|(_, source)| source.errors())
+            .fold(ErrorCounts::default(), |sum, counts| sum + counts)
+    }
+
+    fn recorder(&self) -> Option<Arc<TrafficRecorder>> {
+        self.sources
+            .iter()
+            .find_map(|(_, source)| source.recorder())
+    }
+
+    #[cfg(feature = "chaos")]
+    fn faults(&self) -> Option<Arc<FaultInjector>> {
+        self.sources.iter().find_map(|(_, source)| source.faults())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+
+    use crate::testing::test_peer_info;
+
+    /// a backend whose table the test edits directly
+    struct Table {
+        me: RwLock<PeerInfo>,
+        peers: Mutex<Vec<PeerInfo>>,
+        events: broadcast::Sender<DiscoveryEvent>,
+    }
+
+    impl Table {
+        fn new(peers: Vec<PeerInfo>) -> Arc<Self> {
+            Arc::new(Table {
+                me: RwLock::new(test_peer_info()),
+                peers: Mutex::new(peers),
+                events: broadcast::channel(16).0,
+            })
+        }
+
+        fn add(&self, peer: PeerInfo) {
+            self.peers.lock().unwrap().push(peer.clone());
+            let _ = self.events.send(DiscoveryEvent::PeerDiscovered(peer));
+        }
+
+        fn remove(&self, peer_id: PeerId) {
+            self.peers.lock().unwrap().retain(|p| p.peer_id != peer_id);
+            let _ = self.events.send(DiscoveryEvent::PeerExpired(peer_id));
+        }
+    }
+
+    impl Discovery for Table {
+        fn get_peers(&self) -> BoxFuture<'_, Vec<PeerInfo>> {
+            Box::pin(async { self.peers.lock().unwrap().clone() })
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+            self.events.subscribe()
+        }
+
+        fn get_peer_info(&self) -> PeerInfo {
+            self.me.read().unwrap().clone()
+        }
+
+        fn announce(&self, peer_info: PeerInfo) {
+            *self.me.write().unwrap() = peer_info;
+        }
+
+        fn start(self: Arc<Self>) -> BoxFuture<'static, ()> {
+            Box::pin(future::pending())
+        }
+    }
+
+    #[tokio::test]
+    /// a peer known to two backends is listed once, with the primary's
+    /// record and both sources, and only expires once both drop it
+    async fn merges_and_dedups_by_peer_id() {
+        let shared = test_peer_info();
+        let stale = PeerInfo {
+            price: 9.0,
+            ..shared.clone()
+        };
+        let remote = test_peer_info();
+        let lan = Table::new(vec![]);
+        let list = Table::new(vec![stale.clone(), remote.clone()]);
+        let composite = Arc::new(
+            CompositeDiscovery::new("multicast", lan.clone()).with_source("static", list.clone()),
+        );
+        let mut events = composite.subscribe();
+        tokio::spawn(composite.clone().start());
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DiscoveryEvent::PeerDiscovered(stale)
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DiscoveryEvent::PeerDiscovered(remote.clone())
+        );
+
+        lan.add(shared.clone());
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DiscoveryEvent::PeerUpdated(shared.clone())
+        );
+        let sourced = composite.sourced_peers().await;
+        assert_eq!(
+            sourced,
+            vec![
+                SourcedPeer {
+                    peer: shared.clone(),
+                    sources: vec!["multicast".into(), "static".into()],
+                },
+                SourcedPeer {
+                    peer: remote.clone(),
+                    sources: vec!["static".into()],
+                },
+            ]
+        );
+
+        lan.remove(shared.peer_id);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            DiscoveryEvent::PeerUpdated(p) if p.price == 9.0
+        ));
+        list.remove(shared.peer_id);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DiscoveryEvent::PeerExpired(shared.peer_id)
+        );
+        assert_eq!(composite.get_peers().await, vec![remote]);
+
+        let me = PeerInfo {
+            spare_mbs: 1,
+            ..composite.get_peer_info()
+        };
+        composite.announce(me.clone());
+        assert_eq!(list.get_peer_info(), me);
+    }
+}
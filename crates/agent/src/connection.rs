@@ -1,12 +1,145 @@
-use anyhow::{Context, Error, Result};
-use quinn::{Endpoint, ServerConfig};
-use rustls::{crypto::ring, pki_types::PrivateKeyDer};
-use std::{net::SocketAddr, sync::Once};
+use anyhow::{anyhow, Context, Error, Result};
+use quinn::{
+    crypto::rustls::{HandshakeData, QuicClientConfig, QuicServerConfig},
+    Endpoint, IdleTimeout, Incoming, ServerConfig, TransportConfig, VarInt,
+};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Once},
+    time::Duration,
+};
+use thiserror::Error as ThisError;
+use tokio::time;
 
-use crate::deal::Deal;
+use crate::{
+    deal::{Deal, DecodeError, DecodeLimits},
+    metrics::{ErrorKind, TransportStats},
+};
 
-#[cfg(test)]
-use {libp2p::PeerId, quinn::crypto::rustls::QuicClientConfig, std::sync::Arc};
+/// Returned by [`receive`] once the endpoint has been closed and will never
+/// yield another connection.
+#[derive(Debug, ThisError)]
+#[error("endpoint closed")]
+pub struct EndpointClosed;
+
+/// Context marking errors that happened while accepting a connection.
+#[derive(Debug, ThisError)]
+#[error("connection handshake failed")]
+struct HandshakeFailed;
+
+/// Category of an error returned by [`receive`], or `None` for
+/// [`EndpointClosed`], which is a shutdown rather than a failure.
+pub fn receive_error_kind(err: &Error) -> Option<ErrorKind> {
+    if err.is::<EndpointClosed>() {
+        None
+    } else if err.is::<HandshakeFailed>() {
+        Some(ErrorKind::Handshake)
+    } else if err.is::<DecodeError>() {
+        Some(ErrorKind::Deserialize)
+    } else {
+        Some(ErrorKind::Stream)
+    }
+}
+
+/// Bounds on what the QUIC endpoints hold open, so a long-running agent's
+/// socket and memory use stays flat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointLimits {
+    /// connections without traffic for this long are closed, on both ends
+    pub idle_timeout: Duration,
+    /// connection attempts queued on the receiver before more are refused
+    pub max_incoming: usize,
+    /// connections being read by the receiver at once; more are refused
+    /// until one finishes
+    pub max_reading: usize,
+    /// time a connection gets to complete its handshake and deliver its
+    /// deal, however it keeps itself alive
+    pub read_timeout: Duration,
+    /// deal connections open at once across all peers when sending
+    pub max_outgoing: usize,
+}
+
+impl Default for EndpointLimits {
+    fn default() -> Self {
+        EndpointLimits {
+            idle_timeout: Duration::from_secs(10),
+            max_incoming: 256,
+            max_reading: 64,
+            read_timeout: Duration::from_secs(10),
+            max_outgoing: 64,
+        }
+    }
+}
+
+/// How deals are encoded on a connection, chosen by the client through
+/// ALPN. Each ALPN names one deal layout, so a layout change takes a new
+/// one: peers still speaking an older layout fail the handshake instead of
+/// having their deals misread. QUIC requires ALPN on both ends, so agents
+/// that predate it can't connect at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    /// `serde_json`, for debugging and non-Rust tooling
+    Json,
+}
+
+impl Encoding {
+    /// The layout with region, start time and capabilities in the sender's
+    /// peer info; `sparenet/1` deals lacked them.
+    pub const BINCODE_ALPN: &'static [u8] = b"sparenet/2";
+    pub const JSON_ALPN: &'static [u8] = b"sparenet-json/1";
+
+    pub fn alpn(self) -> &'static [u8] {
+        match self {
+            Encoding::Bincode => Self::BINCODE_ALPN,
+            Encoding::Json => Self::JSON_ALPN,
+        }
+    }
+
+    fn negotiated(conn: &quinn::Connection) -> Self {
+        let protocol = conn
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok())
+            .and_then(|data| data.protocol);
+        match protocol.as_deref() {
+            Some(Self::JSON_ALPN) => Encoding::Json,
+            // the server only selects protocols it offers
+            _ => Encoding::Bincode,
+        }
+    }
+
+    fn encode(self, deal: &Deal) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Bincode => bincode::serialize(deal)?,
+            Encoding::Json => serde_json::to_vec(deal)?,
+        })
+    }
+
+    fn decode(self, bytes: &[u8], limits: &DecodeLimits) -> Result<Deal, DecodeError> {
+        match self {
+            Encoding::Bincode => Deal::decode(bytes, limits),
+            Encoding::Json => Deal::decode_json(bytes, limits),
+        }
+    }
+}
+
+/// A deal connection carries one unidirectional stream from the client and
+/// nothing else.
+fn transport_config(limits: &EndpointLimits, uni_streams: u32) -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .max_idle_timeout(Some(
+            IdleTimeout::try_from(limits.idle_timeout).unwrap_or(VarInt::MAX.into()),
+        ))
+        .max_concurrent_uni_streams(uni_streams.into())
+        .max_concurrent_bidi_streams(0u32.into());
+    Arc::new(transport)
+}
 
 fn ensure_crypto_provider() {
     static INIT: Once = Once::new();
@@ -17,54 +150,140 @@ fn ensure_crypto_provider() {
     });
 }
 
-pub async fn open_receiver_endpoint(listen_addr: SocketAddr) -> Result<Endpoint> {
+pub async fn open_receiver_endpoint(
+    listen_addr: SocketAddr,
+    limits: &EndpointLimits,
+) -> Result<Endpoint> {
+    Ok(Endpoint::server(server_config(limits)?, listen_addr)?)
+}
+
+/// Receiver configuration within `limits`, with a fresh certificate.
+pub fn server_config(limits: &EndpointLimits) -> Result<ServerConfig> {
+    server_config_for(limits, &[Encoding::BINCODE_ALPN, Encoding::JSON_ALPN])
+}
+
+/// Receiver configuration within `limits` accepting one uni stream per
+/// connection for any of `protocols`, with a fresh certificate.
+pub(crate) fn server_config_for(
+    limits: &EndpointLimits,
+    protocols: &[&[u8]],
+) -> Result<ServerConfig> {
+    Ok(server_config_with_cert(limits, protocols)?.0)
+}
+
+/// Like [`server_config_for`], also returning the fresh certificate so it
+/// can be pinned by clients.
+pub(crate) fn server_config_with_cert(
+    limits: &EndpointLimits,
+    protocols: &[&[u8]],
+) -> Result<(ServerConfig, CertificateDer<'static>)> {
     ensure_crypto_provider();
     // QUIC requires TLS, so mint a throwaway self-signed certificate for this endpoint.
     let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
-    let cert_der = certified_key.cert.der();
+    let cert_der = certified_key.cert.der().clone();
     let key_der = PrivateKeyDer::try_from(certified_key.key_pair.serialize_der())
         .map_err(Error::msg)
         .context("failed to parse key into DER")?;
-    let cert_chain = vec![cert_der.to_owned()];
-    let svr_cfg = ServerConfig::with_single_cert(cert_chain, key_der)?;
-    Ok(Endpoint::server(svr_cfg, listen_addr)?)
+    let cert_chain = vec![cert_der.clone()];
+    let mut crypto =
+        rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key_der)?;
+    crypto.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+    let mut svr_cfg = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    svr_cfg
+        .transport_config(transport_config(limits, 1))
+        .max_incoming(limits.max_incoming);
+    Ok((svr_cfg, cert_der))
 }
 
-/// Start a QUIC listener on `listen_addr`, read a single unidirectional stream,
-/// and deserialize the payload into a [`Deal`].
-pub async fn receive(endpoint: &Endpoint) -> Result<Deal> {
-    // Each `accept()` unwraps one layer: endpoint -> incoming connections -> QUIC handshake.
-    let conn = endpoint
+/// Wait for the next connection attempt on `endpoint`, before the handshake,
+/// so the caller can [`Incoming::refuse`] it cheaply.
+pub async fn next_incoming(endpoint: &Endpoint) -> Result<Incoming> {
+    Ok(endpoint.accept().await.ok_or(EndpointClosed)?)
+}
+
+/// Complete the handshake for `incoming`, read a single unidirectional
+/// stream, and decode the payload into a [`Deal`] within `limits`, in the
+/// [`Encoding`] the client negotiated.
+pub async fn read_deal(incoming: Incoming, limits: &DecodeLimits) -> Result<Deal> {
+    let conn = incoming
         .accept()
+        .context(HandshakeFailed)?
         .await
-        .context("no incoming")?
-        .accept()
-        .context("connection handshake failed")?
-        .await?;
+        .context(HandshakeFailed)?;
     let mut uni = conn
         .accept_uni()
         .await
         .context("failed to accept unidirectional stream")?;
     let bytes = uni
-        .read_to_end(1024)
+        .read_to_end(limits.max_deal_bytes)
         .await
         .context("failed to read from unidirectional stream")?;
-    let deal: Deal = bincode::deserialize(&bytes).context("deserializing deal")?;
+    let deal = Encoding::negotiated(&conn)
+        .decode(&bytes, limits)
+        .context("decoding deal")?;
     Ok(deal)
 }
 
-pub async fn open_sender_endpoint() -> Result<Endpoint> {
-    ensure_crypto_provider();
-    let client_cfg = default_client_config();
+/// [`read_deal`], abandoning the connection if the deal hasn't arrived
+/// within `timeout`. Keep-alives don't extend it, unlike the idle timeout.
+pub async fn read_deal_within(
+    incoming: Incoming,
+    limits: &DecodeLimits,
+    timeout: Duration,
+) -> Result<Deal> {
+    time::timeout(timeout, read_deal(incoming, limits))
+        .await
+        .map_err(|_| anyhow!("no deal within {timeout:?}"))?
+}
+
+/// Accept the next connection on `endpoint` and read one [`Deal`] from it.
+pub async fn receive(endpoint: &Endpoint) -> Result<Deal> {
+    read_deal(next_incoming(endpoint).await?, &DecodeLimits::default()).await
+}
+
+pub async fn open_sender_endpoint(limits: &EndpointLimits) -> Result<Endpoint> {
     let mut ep = Endpoint::client("0.0.0.0:0".parse().unwrap())
         .context("failed to create client endpoint")?;
-    ep.set_default_client_config(client_cfg);
+    ep.set_default_client_config(client_config(limits, Encoding::default()));
     Ok(ep)
 }
 
-/// Establish a QUIC connection to `peer_addr` and push a [`Deal`] over a
-/// unidirectional stream.
-pub async fn send(endpoint: &Endpoint, peer_addr: SocketAddr, deal: Deal) -> Result<()> {
+/// Sender configuration within `limits`, offering only `encoding`.
+pub fn client_config(limits: &EndpointLimits, encoding: Encoding) -> quinn::ClientConfig {
+    client_config_for(limits, encoding.alpn())
+}
+
+/// Sender configuration within `limits`, offering only `protocol`.
+pub(crate) fn client_config_for(limits: &EndpointLimits, protocol: &[u8]) -> quinn::ClientConfig {
+    ensure_crypto_provider();
+    client_config_with(limits, protocol, client_crypto())
+}
+
+/// Sender configuration within `limits`, offering only `protocol` and
+/// verifying the server with `crypto`.
+pub(crate) fn client_config_with(
+    limits: &EndpointLimits,
+    protocol: &[u8],
+    mut crypto: rustls::ClientConfig,
+) -> quinn::ClientConfig {
+    crypto.alpn_protocols = vec![protocol.into()];
+    let quic_crypto =
+        QuicClientConfig::try_from(crypto).expect("TLS 1.3 client config is valid for QUIC");
+    let mut client_cfg = quinn::ClientConfig::new(Arc::new(quic_crypto));
+    client_cfg.transport_config(transport_config(limits, 0));
+    client_cfg
+}
+
+/// Deliver `deal` to `peer_addr` over a unidirectional stream, in the
+/// [`Encoding`] negotiated with the receiver, and return the connection's
+/// path statistics.
+pub async fn send(
+    endpoint: &Endpoint,
+    peer_addr: SocketAddr,
+    deal: Deal,
+) -> Result<TransportStats> {
     // Dial the remote endpoint; the hostname must match what the server's cert expects.
     let connect = endpoint
         .connect(peer_addr, "localhost")
@@ -78,7 +297,9 @@ pub async fn send(endpoint: &Endpoint, peer_addr: SocketAddr, deal: Deal) -> Res
         .context("failed to open uni stream")?;
 
     // Serialize and transmit the deal, then gracefully finish the stream.
-    let bytes = bincode::serialize(&deal).context("failed to serialize deal")?;
+    let bytes = Encoding::negotiated(&connection)
+        .encode(&deal)
+        .context("failed to serialize deal")?;
     uni.write_all(&bytes)
         .await
         .context("failed to write into uni stream")?;
@@ -86,16 +307,22 @@ pub async fn send(endpoint: &Endpoint, peer_addr: SocketAddr, deal: Deal) -> Res
     // Closing the stream and connection
     uni.finish()?;
     connection.closed().await;
-    Ok(())
+    Ok(TransportStats::from_connection(&connection))
 }
 
-#[cfg(not(test))]
-fn default_client_config() -> quinn::ClientConfig {
-    quinn::ClientConfig::with_platform_verifier()
+#[cfg(not(any(test, feature = "testing")))]
+fn client_crypto() -> rustls::ClientConfig {
+    rustls::ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(rustls_platform_verifier::Verifier::new()))
+        .with_no_client_auth()
 }
 
-#[cfg(test)]
-fn default_client_config() -> quinn::ClientConfig {
+/// Test builds (and the `testing` feature) accept any server certificate so
+/// loopback agents can talk without provisioning trust anchors. Never enable
+/// `testing` in a production build.
+#[cfg(any(test, feature = "testing"))]
+fn client_crypto() -> rustls::ClientConfig {
     use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
     use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
     use rustls::{ClientConfig, DigitallySignedStruct, Error as RustlsError, SignatureScheme};
@@ -146,54 +373,73 @@ fn default_client_config() -> quinn::ClientConfig {
         }
     }
 
-    let crypto = ClientConfig::builder()
+    ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
         .dangerous()
         .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
-        .with_no_client_auth();
-
-    let quic_crypto =
-        QuicClientConfig::try_from(crypto).expect("failed to create QUIC client config");
-
-    quinn::ClientConfig::new(Arc::new(quic_crypto))
+        .with_no_client_auth()
 }
 
 #[cfg(test)]
 mod tests {
+    use libp2p::PeerId;
     use serde_bytes::ByteBuf;
 
     use super::*;
-    use crate::{deal::BYTES_PER_MEBIBYTE, peer_info::PeerInfoWire};
+    use crate::{
+        deal::{Priority, BYTES_PER_MEBIBYTE},
+        peer_info::{Capabilities, Features, PeerInfoWire},
+    };
 
     #[tokio::test]
     #[ignore = "requires local QUIC handshake"]
     async fn round_trip_control_deal() {
-        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap_or_else(|err| {
+        let bind: SocketAddr = "127.0.0.1:0".parse().unwrap_or_else(|err| {
             eprintln!("failed to parse into socket {}", err);
             panic!("failed to parse into socket address");
         });
 
-        let rep = open_receiver_endpoint(addr).await.unwrap_or_else(|err| {
-            eprintln!("failed to open receiving quic endpoint {}", err);
-            panic!("failed to open receiver quic endpoint");
-        });
+        let rep = open_receiver_endpoint(bind, &EndpointLimits::default())
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("failed to open receiving quic endpoint {}", err);
+                panic!("failed to open receiver quic endpoint");
+            });
+        let addr = rep.local_addr().unwrap();
 
-        let sep = open_sender_endpoint().await.unwrap_or_else(|err| {
-            eprintln!("failed to open sending quic endpoint {}", err);
-            panic!("failed to open sender quic endpoint");
-        });
+        let sep = open_sender_endpoint(&EndpointLimits::default())
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("failed to open sending quic endpoint {}", err);
+                panic!("failed to open sender quic endpoint");
+            });
 
         let ep_thread = rep.clone();
         let server = tokio::spawn(async move { receive(&ep_thread).await });
 
         let deal = Deal {
             peer_info_wire: PeerInfoWire {
-                addr: addr,
+                addr,
                 peer_id_bytes: ByteBuf::from(PeerId::random().to_bytes()),
                 spare_mbs: 10,
                 price: 10.0,
+                egress_price_per_gib: 1.0,
+                storage_offers: vec![],
+                burst_mbs: 0,
+                burst_price: 0.0,
+                features: Features::default(),
+                metadata: Default::default(),
+                region: None,
+                online_since: None,
+                capabilities: Capabilities::default(),
             },
             file_len: 10 * BYTES_PER_MEBIBYTE,
             price_per_mb: 10.0,
+            storage_class: None,
+            deadline_secs: None,
+            redundancy: None,
+            labels: vec![],
+            burst: false,
+            priority: Priority::Normal,
         };
 
         match send(&sep, addr, deal.clone()).await {
@@ -217,4 +463,21 @@ mod tests {
         assert_eq!(received_deal.file_len, deal.file_len);
         assert_eq!(received_deal.price_per_mb, deal.price_per_mb);
     }
+
+    #[tokio::test]
+    /// peers offering the previous deal layout are refused at the handshake
+    async fn previous_layout_is_refused() {
+        let limits = EndpointLimits::default();
+        let receiver = open_receiver_endpoint("127.0.0.1:0".parse().unwrap(), &limits)
+            .await
+            .unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let accepted = tokio::spawn(async move { receive(&receiver).await });
+
+        let mut sender = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        sender.set_default_client_config(client_config_for(&limits, b"sparenet/1"));
+        let connect = sender.connect(addr, "localhost").unwrap();
+        assert!(connect.await.is_err());
+        assert!(accepted.await.unwrap().is_err());
+    }
 }
@@ -1,15 +1,392 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+use thiserror::Error;
 
-use crate::peer_info::PeerInfoWire;
+use crate::peer_info::{Features, PeerInfoWire, StorageClass, MAX_METADATA_BYTES};
 
 /// Number of bytes in one mebibyte (MiB).
 pub const BYTES_PER_MEBIBYTE: u64 = 1024 * 1024;
 
+/// Number of bytes in one gibibyte (GiB).
+pub const BYTES_PER_GIBIBYTE: u64 = 1024 * BYTES_PER_MEBIBYTE;
+
+/// How many copies of a file a consumer wants and in what form.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum Redundancy {
+    /// One full copy on a single provider.
+    Single,
+    /// `n` full copies on distinct providers.
+    Replicas(u8),
+    /// The file is split into `n` shards on distinct providers, any `k` of
+    /// which reconstruct it.
+    ErasureCoded { k: u8, n: u8 },
+}
+
+impl Redundancy {
+    /// Number of distinct providers the scheme needs.
+    pub fn providers(&self) -> usize {
+        match *self {
+            Redundancy::Single => 1,
+            Redundancy::Replicas(n) => n as usize,
+            Redundancy::ErasureCoded { n, .. } => n as usize,
+        }
+    }
+
+    /// Bytes each provider stores for a file of `file_len` bytes.
+    pub fn bytes_per_provider(&self, file_len: u64) -> u64 {
+        match *self {
+            Redundancy::Single | Redundancy::Replicas(_) => file_len,
+            Redundancy::ErasureCoded { k, .. } => file_len.div_ceil(k.max(1) as u64),
+        }
+    }
+}
+
+/// How urgently a consumer wants a deal handled. Queued proposals are sent,
+/// and pending ones reviewed, highest priority first.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+pub enum Priority {
+    /// Bulk work such as scheduled backups.
+    Low,
+    #[default]
+    Normal,
+    /// Urgent work, e.g. re-placing data needed for a restore.
+    High,
+}
+
+/// Content-derived identifier of a deal: the FNV-1a hash of its `bincode`
+/// encoding. Sender and receiver compute the same id without it being sent,
+/// so logs and traces from both agents can be correlated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DealId(pub u64);
+
+impl fmt::Display for DealId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Caps on what a peer may make us decode. Decoding checks every length
+/// prefix against the bytes actually left in the payload before allocating,
+/// so `max_deal_bytes` bounds allocation as a whole; the per-field caps are
+/// checked once the deal is decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// encoded deal, in bytes
+    pub max_deal_bytes: usize,
+    pub max_peer_id_bytes: usize,
+    pub max_storage_offers: usize,
+    /// keys plus values of the embedded peer's metadata, in bytes
+    pub max_metadata_bytes: usize,
+    pub max_labels: usize,
+    pub max_label_bytes: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_deal_bytes: 4096,
+            // multihash-encoded peer ids are under 50 bytes
+            max_peer_id_bytes: 64,
+            max_storage_offers: 8,
+            max_metadata_bytes: MAX_METADATA_BYTES,
+            max_labels: 16,
+            max_label_bytes: 64,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("malformed deal: {0}")]
+    Bincode(#[from] bincode::Error),
+    #[error("malformed JSON deal: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{field} is {len}, limit is {limit}")]
+    TooLong {
+        field: &'static str,
+        len: usize,
+        limit: usize,
+    },
+    #[error("{field} is not a finite number")]
+    NotFinite { field: &'static str },
+}
+
+fn check_len(field: &'static str, len: usize, limit: usize) -> Result<(), DecodeError> {
+    if len > limit {
+        Err(DecodeError::TooLong { field, len, limit })
+    } else {
+        Ok(())
+    }
+}
+
+fn check_finite(field: &'static str, value: f32) -> Result<(), DecodeError> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(DecodeError::NotFinite { field })
+    }
+}
+
 /// Describes a storage deal request between peers.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Deal {
     pub peer_info_wire: PeerInfoWire,
     /// File length in bytes.
     pub file_len: u64,
     pub price_per_mb: f32,
+    /// Storage class requested by the consumer, if any.
+    pub storage_class: Option<StorageClass>,
+    /// Seconds the provider has to ingest the file, if the consumer has a deadline.
+    pub deadline_secs: Option<u64>,
+    /// Placement scheme; `None` offers the deal to every matching peer.
+    pub redundancy: Option<Redundancy>,
+    /// Consumer-chosen tags (e.g. `backup:photos`) for breaking down usage
+    /// and spend by purpose.
+    pub labels: Vec<String>,
+    /// Accept the provider's reclaimable burst capacity at its burst price
+    /// instead of committed space.
+    pub burst: bool,
+    pub priority: Priority,
+}
+
+impl Deal {
+    pub fn id(&self) -> DealId {
+        const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+        let bytes = bincode::serialize(self).expect("deal serializes");
+        DealId(
+            bytes
+                .iter()
+                .fold(OFFSET, |hash, b| (hash ^ *b as u64).wrapping_mul(PRIME)),
+        )
+    }
+
+    /// Decode a deal received from a peer, enforcing `limits`. Only the
+    /// current layout is read; peers sending another negotiate a different
+    /// ALPN and never get this far.
+    pub fn decode(bytes: &[u8], limits: &DecodeLimits) -> Result<Deal, DecodeError> {
+        check_len("deal", bytes.len(), limits.max_deal_bytes)?;
+        let deal: Deal = bincode::deserialize(bytes)?;
+        deal.check_limits(limits)?;
+        Ok(deal)
+    }
+
+    /// Like [`Deal::decode`], for the JSON encoding used by debugging and
+    /// non-Rust tooling.
+    pub fn decode_json(bytes: &[u8], limits: &DecodeLimits) -> Result<Deal, DecodeError> {
+        check_len("deal", bytes.len(), limits.max_deal_bytes)?;
+        let deal: Deal = serde_json::from_slice(bytes)?;
+        deal.check_limits(limits)?;
+        Ok(deal)
+    }
+
+    /// Per-field limits and finite prices, checked once the payload has
+    /// decoded.
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<(), DecodeError> {
+        let peer = &self.peer_info_wire;
+        check_len(
+            "peer_id_bytes",
+            peer.peer_id_bytes.len(),
+            limits.max_peer_id_bytes,
+        )?;
+        check_len(
+            "storage_offers",
+            peer.storage_offers.len(),
+            limits.max_storage_offers,
+        )?;
+        let metadata = peer.metadata.iter().map(|(k, v)| k.len() + v.len()).sum();
+        check_len("metadata", metadata, limits.max_metadata_bytes)?;
+        check_len("labels", self.labels.len(), limits.max_labels)?;
+        for label in &self.labels {
+            check_len("label", label.len(), limits.max_label_bytes)?;
+        }
+        // NaN and infinities compare false against every bound and
+        // serialize to JSON as null
+        check_finite("price_per_mb", self.price_per_mb)?;
+        check_finite("price", peer.price)?;
+        check_finite("egress_price_per_gib", peer.egress_price_per_gib)?;
+        check_finite("burst_price", peer.burst_price)?;
+        for offer in &peer.storage_offers {
+            check_finite("storage_offer.price", offer.price)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the deal carries `label`.
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|l| l == label)
+    }
+
+    /// Features a provider must announce to take this deal.
+    pub fn required_features(&self) -> Features {
+        match self.redundancy {
+            Some(Redundancy::ErasureCoded { .. }) => Features::ERASURE_CODING,
+            _ => Features::default(),
+        }
+    }
+
+    /// Total asking price for storing the whole file.
+    pub fn cost(&self) -> f32 {
+        (self.file_len as f64 / BYTES_PER_MEBIBYTE as f64) as f32 * self.price_per_mb
+    }
+}
+
+/// Aggregate of the deals sharing a label.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct LabelUsage {
+    pub deals: usize,
+    pub bytes: u64,
+    pub spend: f32,
+}
+
+/// Break `deals` down by label. A deal with several labels counts towards
+/// each; unlabelled deals are grouped under `""`.
+pub fn usage_by_label<'a>(
+    deals: impl IntoIterator<Item = &'a Deal>,
+) -> BTreeMap<String, LabelUsage> {
+    let mut usage: BTreeMap<String, LabelUsage> = BTreeMap::new();
+    for deal in deals {
+        let unlabelled = [String::new()];
+        let labels = if deal.labels.is_empty() {
+            &unlabelled[..]
+        } else {
+            &deal.labels[..]
+        };
+        for label in labels {
+            let entry = usage.entry(label.clone()).or_default();
+            entry.deals += 1;
+            entry.bytes += deal.file_len;
+            entry.spend += deal.cost();
+        }
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{deal_from, test_peer_info};
+
+    fn labelled(file_mbs: u64, labels: &[&str]) -> Deal {
+        Deal {
+            price_per_mb: 2.0,
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            ..deal_from(test_peer_info(), file_mbs)
+        }
+    }
+
+    #[test]
+    /// multi-label deals count under each label, unlabelled under ""
+    fn usage_broken_down_by_label() {
+        let deals = [
+            labelled(10, &["backup:photos"]),
+            labelled(5, &["backup:photos", "project:x"]),
+            labelled(1, &[]),
+        ];
+        let usage = usage_by_label(&deals);
+        assert_eq!(usage["backup:photos"].deals, 2);
+        assert_eq!(usage["backup:photos"].bytes, 15 * BYTES_PER_MEBIBYTE);
+        assert_eq!(usage["backup:photos"].spend, 30.0);
+        assert_eq!(usage["project:x"].deals, 1);
+        assert_eq!(usage[""].spend, 2.0);
+        assert!(deals[1].has_label("project:x"));
+    }
+
+    #[test]
+    /// the id depends only on the deal's contents
+    fn deal_id_is_content_derived() {
+        let deal = labelled(10, &["a"]);
+        assert_eq!(deal.id(), deal.clone().id());
+        assert_ne!(deal.id(), labelled(10, &["b"]).id());
+        assert_eq!(deal.id().to_string().len(), 16);
+    }
+
+    #[test]
+    /// decoding rejects oversized fields and payloads but reads anything
+    /// `bincode::serialize` produced within the limits
+    fn decode_limits() {
+        let limits = DecodeLimits::default();
+        let mut deal = labelled(1, &["backup:photos"]);
+        let bytes = bincode::serialize(&deal).unwrap();
+        assert_eq!(Deal::decode(&bytes, &limits).unwrap(), deal);
+
+        deal.labels = vec!["x".repeat(limits.max_label_bytes + 1)];
+        let bytes = bincode::serialize(&deal).unwrap();
+        assert!(matches!(
+            Deal::decode(&bytes, &limits),
+            Err(DecodeError::TooLong { field: "label", .. })
+        ));
+
+        deal.labels = vec!["x".repeat(limits.max_deal_bytes)];
+        let bytes = bincode::serialize(&deal).unwrap();
+        assert!(matches!(
+            Deal::decode(&bytes, &limits),
+            Err(DecodeError::TooLong { field: "deal", .. })
+        ));
+
+        // a length prefix claiming more than the payload holds fails before
+        // allocating
+        let truncated = &bytes[..limits.max_deal_bytes];
+        assert!(matches!(
+            Deal::decode(truncated, &limits),
+            Err(DecodeError::Bincode(_))
+        ));
+    }
+
+    #[test]
+    /// NaN and infinite prices are rejected wherever they appear
+    fn decode_rejects_non_finite_prices() {
+        let limits = DecodeLimits::default();
+        let mut deal = labelled(1, &[]);
+        deal.price_per_mb = f32::NAN;
+        assert!(matches!(
+            Deal::decode(&bincode::serialize(&deal).unwrap(), &limits),
+            Err(DecodeError::NotFinite {
+                field: "price_per_mb"
+            })
+        ));
+
+        let mut deal = labelled(1, &[]);
+        deal.peer_info_wire.burst_price = f32::INFINITY;
+        assert!(matches!(
+            Deal::decode(&bincode::serialize(&deal).unwrap(), &limits),
+            Err(DecodeError::NotFinite {
+                field: "burst_price"
+            })
+        ));
+    }
+
+    #[test]
+    /// the JSON encoding round-trips and is held to the same limits
+    fn decode_json() {
+        let limits = DecodeLimits::default();
+        let mut deal = labelled(1, &["backup:photos"]);
+        let json = serde_json::to_vec(&deal).unwrap();
+        assert_eq!(Deal::decode_json(&json, &limits).unwrap(), deal);
+
+        deal.labels = vec!["x".repeat(limits.max_label_bytes + 1)];
+        let json = serde_json::to_vec(&deal).unwrap();
+        assert!(matches!(
+            Deal::decode_json(&json, &limits),
+            Err(DecodeError::TooLong { field: "label", .. })
+        ));
+        assert!(matches!(
+            Deal::decode_json(b"{}", &limits),
+            Err(DecodeError::Json(_))
+        ));
+    }
 }
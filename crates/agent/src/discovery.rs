@@ -1,26 +1,438 @@
-use libp2p::{futures::lock::Mutex, PeerId};
+use futures::future::BoxFuture;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
-    net::SocketAddr,
-    sync::Arc,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Add,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
     time::{Duration, Instant},
 };
-use tokio::{net::UdpSocket, time};
+use thiserror::Error as ThisError;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, Notify},
+    time,
+};
+use tracing::{debug, warn};
 
-use crate::peer_info::{PeerInfo, PeerInfoWire};
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjector;
+use crate::{
+    explorer::{network_stats, NetworkStats},
+    metrics::{ErrorCounters, ErrorCounts, ErrorKind, Rejection, RejectionCounts, RejectionLog},
+    peer_info::{
+        decode_announcement, encode_announcement, Capabilities, Features, PeerInfo, PeerInfoWire,
+    },
+    recorder::{Direction, Message, TrafficRecorder},
+};
 
-const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
-const PEER_TIMEOUT: Duration = Duration::from_secs(5);
 const MULTICAST_ADDR: &str = "224.0.0.251:5353";
 const MAGIC_HEADER: &[u8; 4] = b"SPAR";
+/// Header of an announcement sent to a bootstrap peer, asking it to answer
+/// with its own announcement and those of the peers it knows.
+const BOOTSTRAP_HEADER: &[u8; 4] = b"SPRB";
+/// Known peers relayed in answer to one bootstrap announcement, bounding
+/// the reply to a spoofed request.
+const MAX_BOOTSTRAP_RELAY: usize = 16;
+/// Source IPs whose last bootstrap answer is remembered for rate limiting;
+/// reset when full, like the digest cache.
+const MAX_BOOTSTRAP_SOURCES: usize = 1024;
+/// Consecutive socket errors after which a loop gives up so the owner can
+/// rebind the socket instead of spinning on a dead one.
+pub(crate) const MAX_SOCKET_ERRORS: u32 = 10;
+/// Identical announcements from a peer within this window are not reprocessed.
+const SUPPRESS_WINDOW: Duration = Duration::from_secs(1);
+/// Largest announcement datagram, header included. Kept under common path
+/// MTUs (QUIC's 1200-byte floor) so announcements never fragment.
+pub const MAX_ANNOUNCEMENT_BYTES: usize = 1200;
+/// Announcement sources whose last digest is remembered; the cache is reset
+/// when full so spoofed source addresses can't grow it without bound.
+const MAX_DIGESTS: usize = 1024;
+/// Events buffered per subscriber before the slowest one starts lagging.
+pub(crate) const EVENT_CAPACITY: usize = 256;
+
+/// Nonblocking UDP socket bound to `addr` with address (and, on unix, port)
+/// reuse, so a replacement can bind while the failed socket is still held
+/// and other mDNS responders can share port 5353.
+pub(crate) fn bind_reusable(addr: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Change to the peer table, see [`DiscoveryService::subscribe`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscoveryEvent {
+    /// a peer entered the table
+    PeerDiscovered(PeerInfo),
+    /// a known peer announced different info
+    PeerUpdated(PeerInfo),
+    /// a peer timed out of the table
+    PeerExpired(PeerId),
+}
+
+/// Peers heard from and when, shared by the discovery backends: every
+/// change is counted and sent to subscribers as a [`DiscoveryEvent`].
+#[derive(Debug)]
+pub(crate) struct PeerTable {
+    peers: Mutex<HashMap<PeerId, (PeerInfo, Instant)>>,
+    events: broadcast::Sender<DiscoveryEvent>,
+    joined: AtomicU64,
+    left: AtomicU64,
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        PeerTable {
+            peers: Mutex::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            joined: AtomicU64::new(0),
+            left: AtomicU64::new(0),
+        }
+    }
+}
+
+impl PeerTable {
+    fn lock(&self) -> MutexGuard<'_, HashMap<PeerId, (PeerInfo, Instant)>> {
+        self.peers.lock().expect("peer lock poisoned")
+    }
+
+    fn emit(&self, event: DiscoveryEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.events.subscribe()
+    }
+
+    /// peers added and removed so far
+    pub(crate) fn churn(&self) -> (u64, u64) {
+        (
+            self.joined.load(Ordering::Relaxed),
+            self.left.load(Ordering::Relaxed),
+        )
+    }
+
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&HashMap<PeerId, (PeerInfo, Instant)>) -> R) -> R {
+        f(&self.lock())
+    }
+
+    pub(crate) fn get_peers(&self) -> Vec<PeerInfo> {
+        self.with(|peers| {
+            peers
+                .values()
+                .map(|(peer_info, _)| peer_info.clone())
+                .collect()
+        })
+    }
+
+    /// store `peer_info` as last heard at `seen`, announcing it if it is new
+    /// or changed, unless `keep` says the current entry should stay. returns
+    /// whether the table was written.
+    pub(crate) fn insert(
+        &self,
+        peer_info: &PeerInfo,
+        seen: Instant,
+        keep: impl FnOnce(&PeerInfo, Instant) -> bool,
+    ) -> bool {
+        let mut peers = self.lock();
+        if let Some((known, known_seen)) = peers.get(&peer_info.peer_id) {
+            if keep(known, *known_seen) {
+                return false;
+            }
+        }
+        let event = match peers.insert(peer_info.peer_id, (peer_info.clone(), seen)) {
+            None => {
+                self.joined.fetch_add(1, Ordering::Relaxed);
+                Some(DiscoveryEvent::PeerDiscovered(peer_info.clone()))
+            }
+            Some((known, _)) if known != *peer_info => {
+                Some(DiscoveryEvent::PeerUpdated(peer_info.clone()))
+            }
+            Some(_) => None,
+        };
+        drop(peers);
+        if let Some(event) = event {
+            self.emit(event);
+        }
+        true
+    }
+
+    /// bump a known peer's last-seen time; false if it isn't in the table
+    pub(crate) fn refresh(&self, peer_id: PeerId) -> bool {
+        match self.lock().get_mut(&peer_id) {
+            Some((_, seen)) => {
+                *seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// drop a peer right away, e.g. when it said goodbye
+    pub(crate) fn forget(&self, peer_id: PeerId) -> bool {
+        let removed = self.lock().remove(&peer_id).is_some();
+        if removed {
+            self.expired(vec![peer_id]);
+        }
+        removed
+    }
+
+    /// drop peers not heard from within `timeout`
+    pub(crate) fn sweep(&self, timeout: Duration) {
+        self.age(Duration::ZERO, timeout);
+    }
+
+    /// treat every peer as last heard `by` earlier, then drop those not
+    /// heard from within `timeout`
+    pub(crate) fn age(&self, by: Duration, timeout: Duration) {
+        let mut expired = Vec::new();
+        self.lock().retain(|peer_id, (_, seen)| {
+            let live = match seen.checked_sub(by) {
+                Some(earlier) => {
+                    *seen = earlier;
+                    earlier.elapsed() <= timeout
+                }
+                None => false,
+            };
+            if !live {
+                expired.push(*peer_id);
+            }
+            live
+        });
+        self.expired(expired);
+    }
+
+    /// count and announce peers removed from the table
+    fn expired(&self, peer_ids: Vec<PeerId>) {
+        self.left
+            .fetch_add(peer_ids.len() as u64, Ordering::Relaxed);
+        for peer_id in peer_ids {
+            self.emit(DiscoveryEvent::PeerExpired(peer_id));
+        }
+    }
+}
+
+/// A source of peers an [`Agent`](crate::agent::Agent) runs on: the multicast
+/// [`DiscoveryService`], or a DHT, gossip, rendezvous or static-list
+/// backend. Futures are boxed so backends can be combined behind
+/// `Arc<dyn Discovery>`. Only the peer table, its events and our own
+/// announcement are required; the rest default to doing nothing.
+pub trait Discovery: Send + Sync + 'static {
+    /// peers currently known
+    fn get_peers(&self) -> BoxFuture<'_, Vec<PeerInfo>>;
+
+    /// peer table changes from now on
+    fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent>;
+
+    /// the info we advertise
+    fn get_peer_info(&self) -> PeerInfo;
+
+    /// advertise `peer_info` from now on, starting right away. the peer id
+    /// must not change.
+    fn announce(&self, peer_info: PeerInfo);
+
+    /// discover and announce until the backend fails; the agent then calls
+    /// [`Discovery::restart`] and starts it again
+    fn start(self: Arc<Self>) -> BoxFuture<'static, ()>;
+
+    /// recover after `start` returned, e.g. by rebinding a socket
+    fn restart(&self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// add a peer learned outside discovery (e.g. from a deal it sent)
+    /// unless it is us or already known. returns whether it was added.
+    fn learn_peer(&self, _peer_info: PeerInfo) -> BoxFuture<'_, bool> {
+        Box::pin(async { false })
+    }
+
+    /// treat every peer as last heard `by` earlier, after the host was
+    /// suspended
+    fn age_peers(&self, _by: Duration) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    fn stats(&self) -> DiscoveryStats {
+        DiscoveryStats::default()
+    }
+
+    fn rejections(&self) -> RejectionCounts {
+        RejectionCounts::default()
+    }
+
+    fn errors(&self) -> ErrorCounts {
+        ErrorCounts::default()
+    }
+
+    /// where the agent records the deals it sends and receives, if anywhere
+    fn recorder(&self) -> Option<Arc<TrafficRecorder>> {
+        None
+    }
+
+    /// faults shared with the agent built on this backend, if it injects any
+    #[cfg(feature = "chaos")]
+    fn faults(&self) -> Option<Arc<FaultInjector>> {
+        None
+    }
+}
+
+/// How often we announce and how long peers are remembered, tunable per
+/// deployment, e.g. a slower announce rate on metered links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoveryConfig {
+    /// time between our announcements
+    pub announce_interval: Duration,
+    /// peers not heard from for this long are dropped; keep it a few
+    /// announce intervals so one lost datagram doesn't evict a peer
+    pub peer_timeout: Duration,
+    /// how often the table is checked for timed-out peers
+    pub sweep_interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            announce_interval: Duration::from_secs(2),
+            peer_timeout: Duration::from_secs(5),
+            sweep_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A [`DiscoveryConfig`] the discovery timers can't run on.
+#[derive(Debug, Clone, PartialEq, Eq, ThisError)]
+pub enum ConfigError {
+    #[error("{0} must be longer than zero")]
+    ZeroDuration(&'static str),
+}
+
+impl DiscoveryConfig {
+    /// reject zero durations, which would spin the announcer or sweeper
+    /// (tokio intervals panic on them)
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (name, duration) in [
+            ("announce interval", self.announce_interval),
+            ("peer timeout", self.peer_timeout),
+            ("sweep interval", self.sweep_interval),
+        ] {
+            if duration.is_zero() {
+                return Err(ConfigError::ZeroDuration(name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Point-in-time copy of the announcement counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DiscoveryStats {
+    /// valid announcements read off the socket
+    pub received: u64,
+    /// announcements dropped as our own echo or a recent duplicate
+    pub suppressed: u64,
+    /// announcements sent, counting each destination separately
+    pub sent: u64,
+    /// metadata entries left out of our announcement to fit
+    /// `MAX_ANNOUNCEMENT_BYTES`
+    pub metadata_dropped: u64,
+    /// received datagrams over `MAX_ANNOUNCEMENT_BYTES`, discarded
+    pub oversized: u64,
+    /// announcements identical to the sender's previous one, which only
+    /// refreshed its last-seen time
+    pub unchanged: u64,
+    /// peers added to the table
+    pub joined: u64,
+    /// peers that timed out of the table
+    pub left: u64,
+}
+
+impl Add for DiscoveryStats {
+    type Output = DiscoveryStats;
+
+    fn add(self, other: DiscoveryStats) -> DiscoveryStats {
+        DiscoveryStats {
+            received: self.received + other.received,
+            suppressed: self.suppressed + other.suppressed,
+            sent: self.sent + other.sent,
+            metadata_dropped: self.metadata_dropped + other.metadata_dropped,
+            oversized: self.oversized + other.oversized,
+            unchanged: self.unchanged + other.unchanged,
+            joined: self.joined + other.joined,
+            left: self.left + other.left,
+        }
+    }
+}
+
+/// Serializable copy of the peer table, used to seed or debug other agents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerSnapshot {
+    pub peers: Vec<PeerSnapshotEntry>,
+}
+
+/// One peer in a [`PeerSnapshot`] along with how long ago it was last heard.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerSnapshotEntry {
+    pub peer: PeerInfoWire,
+    pub age_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct AnnounceCounters {
+    received: AtomicU64,
+    suppressed: AtomicU64,
+    sent: AtomicU64,
+    metadata_dropped: AtomicU64,
+    oversized: AtomicU64,
+    unchanged: AtomicU64,
+}
 
 #[derive(Debug)]
 pub struct DiscoveryService {
-    peers: Arc<Mutex<HashMap<PeerId, (PeerInfo, Instant)>>>,
-    socket: Arc<UdpSocket>,
-    peer_info: PeerInfo,
+    peers: PeerTable,
+    socket: RwLock<Arc<UdpSocket>>,
+    /// address the socket was bound to, reused by `rebind`
+    bound_addr: SocketAddr,
+    /// (group, interface) joined on bind, if any
+    multicast: Option<(Ipv4Addr, Ipv4Addr)>,
+    /// our announced info; only the price changes after construction
+    peer_info: RwLock<PeerInfo>,
+    peer_id: PeerId,
+    /// bumped whenever `peer_info` changes, so the announcer re-encodes
+    peer_info_version: AtomicU64,
     dest: SocketAddr,
+    /// Extra addresses announced to directly, for networks without multicast.
+    unicast_targets: Vec<SocketAddr>,
+    /// Known agents asked for their peer table every announce interval.
+    bootstrap: Vec<SocketAddr>,
+    /// answer bootstrap requests, see [`DiscoveryService::with_bootstrap_server`]
+    bootstrap_server: bool,
+    config: DiscoveryConfig,
+    counters: AnnounceCounters,
+    errors: ErrorCounters,
+    rejections: RejectionLog,
+    /// wakes the announcer ahead of its interval
+    announce_now: Notify,
+    /// listen-only: never announce, see [`DiscoveryService::observing`]
+    observer: bool,
+    started: Instant,
+    /// control traffic recorder shared with the agent, if recording
+    recorder: Option<Arc<TrafficRecorder>>,
+    #[cfg(feature = "chaos")]
+    faults: Arc<FaultInjector>,
 }
 
 impl DiscoveryService {
@@ -36,26 +448,57 @@ impl DiscoveryService {
         dest_addr: &str,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let mut parts = dest_addr.split(":");
-        let dest_ip: std::net::Ipv4Addr = parts.next().ok_or("missing multicast IP")?.parse()?;
+        let dest_ip: Ipv4Addr = parts.next().ok_or("missing multicast IP")?.parse()?;
         parts.next().ok_or("missing multicast port")?;
 
         let mut parts = bind_addr.split(':');
-        let local_ip: std::net::Ipv4Addr = parts.next().ok_or("missing bind IP")?.parse()?;
+        let local_ip: Ipv4Addr = parts.next().ok_or("missing bind IP")?.parse()?;
         parts.next().ok_or("missing bind port")?;
 
         // bind and join
-        let socket = UdpSocket::bind(bind_addr).await?;
+        let socket = UdpSocket::from_std(bind_reusable(bind_addr.parse()?)?.into())?;
         socket.join_multicast_v4(dest_ip, local_ip)?;
 
-        Ok(Self {
-            peers: Arc::new(Mutex::new(HashMap::new())),
-            socket: Arc::new(socket),
+        Self::from_socket(
             peer_info,
-            dest: dest_addr.parse()?,
+            socket,
+            dest_addr.parse()?,
+            Some((dest_ip, local_ip)),
+        )
+    }
+
+    fn from_socket(
+        peer_info: PeerInfo,
+        socket: UdpSocket,
+        dest: SocketAddr,
+        multicast: Option<(Ipv4Addr, Ipv4Addr)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            peers: PeerTable::default(),
+            bound_addr: socket.local_addr()?,
+            socket: RwLock::new(Arc::new(socket)),
+            multicast,
+            peer_id: peer_info.peer_id,
+            peer_info: RwLock::new(peer_info),
+            peer_info_version: AtomicU64::new(0),
+            dest,
+            unicast_targets: Vec::new(),
+            bootstrap: Vec::new(),
+            bootstrap_server: false,
+            config: DiscoveryConfig::default(),
+            counters: AnnounceCounters::default(),
+            errors: ErrorCounters::default(),
+            rejections: RejectionLog::default(),
+            announce_now: Notify::new(),
+            observer: false,
+            started: Instant::now(),
+            recorder: None,
+            #[cfg(feature = "chaos")]
+            faults: Arc::default(),
         })
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     /// [TEST ONLY] constructor to be used in testcases to circumvent the
     /// multicast connection issue
     pub async fn test_with_addr(
@@ -63,143 +506,704 @@ impl DiscoveryService {
         bind_addr: &str,
         dest_addr: &str,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let socket = UdpSocket::bind(bind_addr).await?;
-        Ok(Self {
-            peers: Arc::new(Mutex::new(HashMap::new())),
-            socket: Arc::new(socket),
-            peer_info,
-            dest: dest_addr.parse()?,
-        })
+        let socket = UdpSocket::from_std(bind_reusable(bind_addr.parse()?)?.into())?;
+        Self::from_socket(peer_info, socket, dest_addr.parse()?, None)
+    }
+
+    /// a listen-only service on the multicast group for network explorers
+    /// and dashboards. it tracks announced peers but never announces itself,
+    /// so its own peer info is a placeholder.
+    pub async fn observer() -> Result<Self, Box<dyn Error>> {
+        let placeholder = PeerInfo {
+            addr: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            peer_id: PeerId::random(),
+            spare_mbs: 0,
+            price: 0.0,
+            egress_price_per_gib: 0.0,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: BTreeMap::new(),
+            region: None,
+            online_since: None,
+            capabilities: Capabilities::default(),
+        };
+        Ok(Self::new(placeholder).await?.observing())
+    }
+
+    /// bound to an ephemeral loopback port and joined to no group, so nothing
+    /// is heard or reached; for replaying recordings offline
+    pub async fn offline(peer_info: PeerInfo) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let dest = socket.local_addr()?;
+        Self::from_socket(peer_info, socket, dest, None)
+    }
+
+    /// listen and track peers without ever announcing
+    pub fn observing(mut self) -> Self {
+        self.observer = true;
+        self
+    }
+
+    /// also unicast announcements to `targets`, alongside the multicast group
+    pub fn with_unicast_targets(mut self, targets: Vec<SocketAddr>) -> Self {
+        self.unicast_targets = targets;
+        self
+    }
+
+    /// join through known agents outside the multicast domain. each announce
+    /// interval our announcement is sent to every address in `peers`, which
+    /// records us and answers with its own announcement and up to
+    /// `MAX_BOOTSTRAP_RELAY` of the peers it knows, so the table is seeded
+    /// and kept fresh without multicast
+    pub fn with_bootstrap(mut self, peers: Vec<SocketAddr>) -> Self {
+        self.bootstrap = peers;
+        self
+    }
+
+    /// answer bootstrap requests, so agents outside the multicast domain can
+    /// join through us. off by default: an answer is many times the size of
+    /// the (spoofable) request, so each source IP gets at most one per
+    /// announce interval
+    pub fn with_bootstrap_server(mut self, on: bool) -> Self {
+        self.bootstrap_server = on;
+        self
+    }
+
+    /// announce and expire peers on `config`'s schedule instead of the
+    /// defaults. only takes effect if set before `start`; fails on zero
+    /// durations
+    pub fn with_config(mut self, config: DiscoveryConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        if config.peer_timeout <= config.announce_interval {
+            warn!(
+                "peer timeout {:?} is not longer than the announce interval {:?}, peers will flap",
+                config.peer_timeout, config.announce_interval
+            );
+        }
+        self.config = config;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &DiscoveryConfig {
+        &self.config
+    }
+
+    /// include the start of each rejected datagram, hex-encoded, in the
+    /// rejection log lines; for debugging what else shares the group
+    pub fn with_rejection_samples(self, on: bool) -> Self {
+        self.rejections.set_samples(on);
+        self
+    }
+
+    /// record announcements heard and sent, and the agent's deals, to
+    /// `recorder`
+    pub fn with_recorder(mut self, recorder: Arc<TrafficRecorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn recorder(&self) -> Option<&Arc<TrafficRecorder>> {
+        self.recorder.as_ref()
+    }
+
+    fn record(&self, direction: Direction, peer: SocketAddr, payload: &[u8]) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(
+                direction,
+                peer,
+                Message::Announcement {
+                    payload: payload.to_vec(),
+                },
+            );
+        }
+    }
+
+    /// faults injected into this service and the agent built around it
+    #[cfg(feature = "chaos")]
+    pub fn faults(&self) -> &Arc<FaultInjector> {
+        &self.faults
+    }
+
+    /// address the discovery socket is actually bound to, useful when the
+    /// bind address asked for port 0
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+
+    fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.read().expect("socket lock poisoned").clone()
+    }
+
+    /// replace the socket with a fresh one on the same address (rejoining
+    /// the multicast group), e.g. after `start` returned because the old
+    /// socket kept failing. the peer map and counters are kept.
+    pub async fn rebind(&self) -> std::io::Result<()> {
+        // the old socket is still bound until it is swapped out below
+        let socket = UdpSocket::from_std(bind_reusable(self.bound_addr)?.into())?;
+        if let Some((group, interface)) = self.multicast {
+            socket.join_multicast_v4(group, interface)?;
+        }
+        *self.socket.write().expect("socket lock poisoned") = Arc::new(socket);
+        Ok(())
+    }
+
+    /// snapshot of the announcement counters
+    pub fn stats(&self) -> DiscoveryStats {
+        let (joined, left) = self.peers.churn();
+        DiscoveryStats {
+            received: self.counters.received.load(Ordering::Relaxed),
+            suppressed: self.counters.suppressed.load(Ordering::Relaxed),
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            metadata_dropped: self.counters.metadata_dropped.load(Ordering::Relaxed),
+            oversized: self.counters.oversized.load(Ordering::Relaxed),
+            unchanged: self.counters.unchanged.load(Ordering::Relaxed),
+            joined,
+            left,
+        }
+    }
+
+    /// receive peer table changes from now on, instead of polling
+    /// `get_peers`. a subscriber more than `EVENT_CAPACITY` events behind
+    /// gets `RecvError::Lagged` and should re-read the table.
+    pub fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.peers.subscribe()
+    }
+
+    /// snapshot of the socket and decode errors seen so far
+    pub fn errors(&self) -> ErrorCounts {
+        self.errors.snapshot()
+    }
+
+    /// datagrams rejected so far, by reason
+    pub fn rejections(&self) -> RejectionCounts {
+        self.rejections.snapshot()
     }
 
     /// return own info
-    pub fn get_peer_info(&self) -> &PeerInfo {
-        &self.peer_info
+    pub fn get_peer_info(&self) -> PeerInfo {
+        self.peer_info
+            .read()
+            .expect("peer info lock poisoned")
+            .clone()
     }
 
     /// start the discovery service
-    /// we pass self as an Arc because the uses itself to run the functions
+    /// we pass self as an Arc because the uses itself to run the functions.
+    /// returns only if the socket keeps failing; call `rebind` before
+    /// starting again.
     pub async fn start(self: Arc<Self>) {
         // clone out into locals so they live long enough
         let svc_listen = self.clone();
         let svc_announce = self.clone();
         let svc_sweep = self.clone();
 
-        tokio::join!(
-            svc_listen.listen_to_peers(),
-            svc_announce.announce_presence(),
-            svc_sweep.sweep_timeout_peers(),
+        // the listener and announcer only return when their socket is dead;
+        // stop everything then so the owner notices and can rebuild
+        tokio::select!(
+            _ = svc_listen.listen_to_peers() => {}
+            _ = svc_announce.announce_presence(), if !self.observer => {}
+            _ = svc_sweep.sweep_timeout_peers() => {}
         );
     }
 
     /// listen to incoming broadcast from the multicast address and store into peer map
     async fn listen_to_peers(&self) {
-        let mut buf = [0u8; 1024];
+        // one spare byte so a datagram over the limit is seen as such rather
+        // than silently cut to fit
+        let mut buf = [0u8; MAX_ANNOUNCEMENT_BYTES + 1];
+        // digest of the last announcement from each source address
+        let mut digests = HashMap::new();
+        // when each source IP was last answered a bootstrap request
+        let mut answered = HashMap::new();
+        let mut errors = 0;
         loop {
-            // read from udp socket into mutable buffer of 1024 byte
-            let (len, _src) = match self.socket.recv_from(&mut buf).await {
-                Ok(pair) => pair,
+            let (len, src) = match self.socket().recv_from(&mut buf).await {
+                Ok(pair) => {
+                    errors = 0;
+                    pair
+                }
                 Err(e) => {
-                    eprintln!("Error reading from socket: {}", e);
+                    self.errors.record(ErrorKind::Socket);
+                    warn!("error reading from discovery socket: {e}");
+                    errors += 1;
+                    if errors >= MAX_SOCKET_ERRORS {
+                        warn!("discovery socket keeps failing, stopping listener");
+                        return;
+                    }
                     continue;
                 }
             };
 
-            if len < MAGIC_HEADER.len() || &buf[..MAGIC_HEADER.len()] != MAGIC_HEADER {
+            let datagram = &buf[..len.min(MAX_ANNOUNCEMENT_BYTES)];
+            let header = &buf[..MAGIC_HEADER.len().min(len)];
+            let bootstrap = header == BOOTSTRAP_HEADER;
+            if header != MAGIC_HEADER && !bootstrap {
+                self.rejections
+                    .record(Rejection::Foreign, src, datagram, "no spare-net header");
+                continue;
+            }
+
+            if len > MAX_ANNOUNCEMENT_BYTES {
+                self.counters.oversized.fetch_add(1, Ordering::Relaxed);
+                self.rejections.record(
+                    Rejection::Oversized,
+                    src,
+                    datagram,
+                    format_args!("over {MAX_ANNOUNCEMENT_BYTES} bytes"),
+                );
                 continue;
             }
 
             let payload = &buf[MAGIC_HEADER.len()..len];
+            self.record(Direction::In, src, payload);
 
-            // deserialize bytes -> peer info wire
-            let peer_info_wire = match bincode::deserialize::<PeerInfoWire>(payload) {
-                Ok(piw) => piw,
-                Err(e) => {
-                    eprintln!("Failed to deserialize bytes into PeerInfoWire: {}", e);
-                    continue;
+            #[cfg(feature = "chaos")]
+            if self.faults.drop_announcement() {
+                continue;
+            }
+
+            let from = self.handle_announcement(src, payload, &mut digests).await;
+            let serve = bootstrap && self.bootstrap_server && !self.observer;
+            if let Some(peer_id) = from.filter(|_| serve) {
+                if self.bootstrap_allowed(src.ip(), &mut answered) {
+                    self.answer_bootstrap(src, peer_id).await;
+                } else {
+                    debug!("not answering bootstrap from {src} again this interval");
                 }
-            };
+            }
+        }
+    }
 
-            // convert peer info wire to peer info
-            let peer_info = match PeerInfo::try_from(peer_info_wire) {
-                Ok(pi) => pi,
+    /// whether `ip` may be answered now, at most once per announce interval,
+    /// and if so note that it was
+    fn bootstrap_allowed(&self, ip: IpAddr, answered: &mut HashMap<IpAddr, Instant>) -> bool {
+        let now = Instant::now();
+        if answered
+            .get(&ip)
+            .is_some_and(|at| now.duration_since(*at) < self.config.announce_interval)
+        {
+            return false;
+        }
+        if answered.len() >= MAX_BOOTSTRAP_SOURCES {
+            answered.clear();
+        }
+        answered.insert(ip, now);
+        true
+    }
+
+    /// send our announcement and those of up to `MAX_BOOTSTRAP_RELAY` known
+    /// peers, other than the asker, back to a bootstrapping peer
+    async fn answer_bootstrap(&self, src: SocketAddr, asker: PeerId) {
+        let mut replies = vec![self.encode_announcement()];
+        self.with_peers(|peers| {
+            replies.extend(
+                peers
+                    .values()
+                    .filter(|(peer, _)| peer.peer_id != asker)
+                    .take(MAX_BOOTSTRAP_RELAY)
+                    .map(|(peer, _)| {
+                        let mut data = MAGIC_HEADER.to_vec();
+                        data.extend_from_slice(&encode_announcement(&PeerInfoWire::from(
+                            peer.clone(),
+                        )));
+                        data
+                    }),
+            );
+        })
+        .await;
+        let socket = self.socket();
+        for data in replies {
+            match socket.send_to(&data, src).await {
+                Ok(_) => {
+                    self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                    self.record(Direction::Out, src, &data[MAGIC_HEADER.len()..]);
+                }
                 Err(e) => {
-                    eprintln!("Failed to parse PeerInfoWire to PeerInfo: {}", e);
-                    continue;
+                    self.errors.record(ErrorKind::Socket);
+                    warn!("failed to answer bootstrap from {src}: {e}");
+                    return;
                 }
-            };
+            }
+        }
+    }
+
+    /// decode and record one announcement payload from `src`, unless it is
+    /// byte-for-byte the last one `src` sent, in which case only the peer's
+    /// last-seen time is refreshed. returns the announcing peer, if the
+    /// payload was valid
+    pub(crate) async fn handle_announcement(
+        &self,
+        src: SocketAddr,
+        payload: &[u8],
+        digests: &mut HashMap<SocketAddr, (u64, PeerId)>,
+    ) -> Option<PeerId> {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let digest = hasher.finish();
+        if let Some(&(last, peer_id)) = digests.get(&src) {
+            if last == digest && self.refresh_peer(peer_id).await {
+                return Some(peer_id);
+            }
+        }
+
+        // deserialize bytes -> peer info wire
+        let peer_info_wire = match decode_announcement(payload) {
+            Ok(piw) => piw,
+            Err(e) => {
+                self.errors.record(ErrorKind::Deserialize);
+                self.rejections
+                    .record(Rejection::Malformed, src, payload, e);
+                return None;
+            }
+        };
+
+        // convert peer info wire to peer info
+        let peer_info = match PeerInfo::try_from(peer_info_wire) {
+            Ok(pi) => pi,
+            Err(e) => {
+                self.errors.record(ErrorKind::Deserialize);
+                self.rejections.record(Rejection::Invalid, src, payload, e);
+                return None;
+            }
+        };
+
+        if digests.len() >= MAX_DIGESTS {
+            digests.clear();
+        }
+        let peer_id = peer_info.peer_id;
+        digests.insert(src, (digest, peer_id));
+        self.record_announcement(peer_info).await;
+        Some(peer_id)
+    }
+
+    /// bump the last-seen time of a peer whose announcement didn't change.
+    /// returns false if the peer isn't in the table (e.g. it is us, or it
+    /// timed out), so the announcement is processed in full.
+    async fn refresh_peer(&self, peer_id: PeerId) -> bool {
+        if !self.peers.refresh(peer_id) {
+            return false;
+        }
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        self.counters.unchanged.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// store a decoded announcement, skipping our own multicast echo and
+    /// identical copies (e.g. relayed) heard within `SUPPRESS_WINDOW`.
+    /// returns whether the peer map was updated.
+    pub(crate) async fn record_announcement(&self, peer_info: PeerInfo) -> bool {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        if peer_info.peer_id == self.peer_id {
+            self.counters.suppressed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
 
-            // once passed all, acquire lock and insert into map
-            let mut peers_map = self.peers.lock().await;
-            peers_map.insert(peer_info.peer_id, (peer_info, Instant::now()));
+        let recorded = self
+            .peers
+            .insert(&peer_info, Instant::now(), |known, seen| {
+                *known == peer_info && seen.elapsed() < SUPPRESS_WINDOW
+            });
+        if !recorded {
+            self.counters.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+        recorded
+    }
+
+    /// add a peer learned outside discovery (e.g. from a deal it sent)
+    /// unless it is us or already known, in which case its announcements
+    /// stay authoritative. returns whether it was added.
+    pub(crate) async fn learn_peer(&self, peer_info: PeerInfo) -> bool {
+        if peer_info.peer_id == self.peer_id {
+            return false;
+        }
+        self.peers.insert(&peer_info, Instant::now(), |_, _| true)
+    }
+
+    /// encode our announcement, dropping metadata entries (last key first)
+    /// until it fits in `MAX_ANNOUNCEMENT_BYTES`
+    pub(crate) fn encode_announcement(&self) -> Vec<u8> {
+        let mut piw = PeerInfoWire::from(self.get_peer_info());
+        loop {
+            // add protocol magic header for listener to filter out non-protocol data
+            let mut data = MAGIC_HEADER.to_vec();
+            data.extend_from_slice(&encode_announcement(&piw));
+            if data.len() <= MAX_ANNOUNCEMENT_BYTES {
+                return data;
+            }
+            match piw.metadata.pop_last() {
+                Some((key, _)) => {
+                    self.counters
+                        .metadata_dropped
+                        .fetch_add(1, Ordering::Relaxed);
+                    warn!("announcement too large, leaving out metadata `{key}`");
+                }
+                None => {
+                    warn!(
+                        "announcement is {} bytes, over the {MAX_ANNOUNCEMENT_BYTES}-byte limit",
+                        data.len()
+                    );
+                    return data;
+                }
+            }
         }
     }
 
     /// broadcast current peer info to multicast address for other peers
     async fn announce_presence(&self) {
-        let piw = PeerInfoWire::from(self.peer_info.clone());
-        let mut data = Vec::with_capacity(MAGIC_HEADER.len() + 64);
-
-        // add protocol magic header for listener to filter out non-protocol data
-        data.extend_from_slice(MAGIC_HEADER);
-        data.extend_from_slice(&bincode::serialize(&piw).unwrap());
-        let mut interval = time::interval(ANNOUNCE_INTERVAL);
+        let mut version = self.peer_info_version.load(Ordering::Acquire);
+        let mut data = self.encode_announcement();
+        let mut hello = bootstrap_request(&data);
+        let mut interval = time::interval(self.config.announce_interval);
 
         // run intervals to broadcast one's peer info wire
+        let mut errors = 0;
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.announce_now.notified() => {}
+            }
+            let current = self.peer_info_version.load(Ordering::Acquire);
+            if current != version {
+                version = current;
+                data = self.encode_announcement();
+                hello = bootstrap_request(&data);
+            }
+            let socket = self.socket();
             // send peer info wire in bytes to multicast address
-            if let Err(e) = self.socket.send_to(&data, self.dest).await {
-                eprintln!("Broadcast error: {}", e);
+            match socket.send_to(&data, self.dest).await {
+                Ok(_) => {
+                    errors = 0;
+                    self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                    self.record(Direction::Out, self.dest, &data[MAGIC_HEADER.len()..]);
+                }
+                Err(e) => {
+                    self.errors.record(ErrorKind::Socket);
+                    warn!("failed to broadcast announcement: {e}");
+                    errors += 1;
+                    if errors >= MAX_SOCKET_ERRORS {
+                        warn!("discovery socket keeps failing, stopping announcer");
+                        return;
+                    }
+                }
+            }
+            // and to every configured unicast fallback target
+            for target in &self.unicast_targets {
+                match socket.send_to(&data, target).await {
+                    Ok(_) => {
+                        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                        self.record(Direction::Out, *target, &data[MAGIC_HEADER.len()..]);
+                    }
+                    Err(e) => {
+                        self.errors.record(ErrorKind::Socket);
+                        warn!("failed to announce to {target}: {e}");
+                    }
+                }
+            }
+            // and ask every bootstrap peer for its table
+            for target in &self.bootstrap {
+                match socket.send_to(&hello, target).await {
+                    Ok(_) => {
+                        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                        self.record(Direction::Out, *target, &hello[BOOTSTRAP_HEADER.len()..]);
+                    }
+                    Err(e) => {
+                        self.errors.record(ErrorKind::Socket);
+                        warn!("failed to bootstrap from {target}: {e}");
+                    }
+                }
             }
         }
     }
 
     /// Remove any stale peers *once*.
     pub async fn sweep_once(&self) {
-        let mut peers_map = self.peers.lock().await;
-        peers_map.retain(|_, (_, seen)| seen.elapsed() <= PEER_TIMEOUT);
+        self.peers.sweep(self.config.peer_timeout);
     }
 
-    /// Continuously run `sweep_once` every second.
+    /// Continuously run `sweep_once` every sweep interval.
     async fn sweep_timeout_peers(&self) {
-        let mut interval = time::interval(Duration::from_secs(1));
+        let mut interval = time::interval(self.config.sweep_interval);
         loop {
             interval.tick().await;
             self.sweep_once().await;
         }
     }
 
+    /// Advertise `price` per MiB from now on, announcing it right away.
+    pub fn set_price(&self, price: f32) {
+        self.update_peer_info(|peer_info| peer_info.price = price);
+    }
+
+    fn update_peer_info(&self, update: impl FnOnce(&mut PeerInfo)) {
+        update(&mut self.peer_info.write().expect("peer info lock poisoned"));
+        self.peer_info_version.fetch_add(1, Ordering::Release);
+        self.announce_now();
+    }
+
+    /// Send our announcement now instead of at the next interval.
+    pub fn announce_now(&self) {
+        self.announce_now.notify_one();
+    }
+
+    /// Treat every peer as last heard `by` earlier, then sweep. Used after
+    /// the host was suspended, which the monotonic clock does not count.
+    pub async fn age_peers(&self, by: Duration) {
+        self.peers.age(by, self.config.peer_timeout);
+    }
+
     /// Give callers read-only access to the peer map without cloning.
     pub async fn with_peers<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&HashMap<PeerId, (PeerInfo, Instant)>) -> R,
     {
-        let peers_map = self.peers.lock().await;
-        f(&*peers_map)
+        self.peers.with(f)
     }
 
-    /// Retrieve the current peers by cloning the entries into a Vec.
-    pub async fn get_peers(&self) -> Vec<PeerInfo> {
-        self.with_peers(|map| {
-            map.values()
-                .map(|(peer_info, _instant)| peer_info.clone())
-                .collect()
+    /// Export the peer table with the age of each entry.
+    pub async fn export_snapshot(&self) -> PeerSnapshot {
+        self.with_peers(|map| PeerSnapshot {
+            peers: map
+                .values()
+                .map(|(peer_info, seen)| PeerSnapshotEntry {
+                    peer: PeerInfoWire::from(peer_info.clone()),
+                    age_ms: seen.elapsed().as_millis() as u64,
+                })
+                .collect(),
         })
         .await
     }
+
+    /// Merge a snapshot into the peer table, keeping each entry's age so the
+    /// sweeper expires it on schedule. Entries for ourselves, entries that
+    /// fail to parse, and entries older than what we already know are
+    /// skipped. Returns the number of peers imported.
+    pub async fn import_snapshot(&self, snapshot: PeerSnapshot) -> usize {
+        let now = Instant::now();
+        let mut imported = 0;
+        for entry in snapshot.peers {
+            let peer_info = match PeerInfo::try_from(entry.peer) {
+                Ok(pi) => pi,
+                Err(e) => {
+                    self.errors.record(ErrorKind::Deserialize);
+                    warn!("skipping invalid snapshot entry: {e}");
+                    continue;
+                }
+            };
+            if peer_info.peer_id == self.peer_id {
+                continue;
+            }
+            let Some(seen) = now.checked_sub(Duration::from_millis(entry.age_ms)) else {
+                continue;
+            };
+            if self
+                .peers
+                .insert(&peer_info, seen, |_, known| known >= seen)
+            {
+                imported += 1;
+            }
+        }
+        imported
+    }
+
+    /// Aggregate capacity, price percentiles and churn over the peer table.
+    pub async fn network_stats(&self) -> NetworkStats {
+        let stats = self.stats();
+        network_stats(
+            &self.get_peers().await,
+            stats.joined,
+            stats.left,
+            self.started.elapsed(),
+        )
+    }
+
+    /// Retrieve the current peers by cloning the entries into a Vec.
+    pub async fn get_peers(&self) -> Vec<PeerInfo> {
+        self.peers.get_peers()
+    }
+}
+
+/// `announcement` with the bootstrap header in place of the magic header
+fn bootstrap_request(announcement: &[u8]) -> Vec<u8> {
+    let mut hello = BOOTSTRAP_HEADER.to_vec();
+    hello.extend_from_slice(&announcement[MAGIC_HEADER.len()..]);
+    hello
+}
+
+impl Discovery for DiscoveryService {
+    fn get_peers(&self) -> BoxFuture<'_, Vec<PeerInfo>> {
+        Box::pin(DiscoveryService::get_peers(self))
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        DiscoveryService::subscribe(self)
+    }
+
+    fn get_peer_info(&self) -> PeerInfo {
+        DiscoveryService::get_peer_info(self)
+    }
+
+    fn announce(&self, peer_info: PeerInfo) {
+        if peer_info.peer_id != self.peer_id {
+            warn!(
+                "not announcing as {}, this service is {}",
+                peer_info.peer_id, self.peer_id
+            );
+            return;
+        }
+        self.update_peer_info(|current| *current = peer_info);
+    }
+
+    fn start(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(DiscoveryService::start(self))
+    }
+
+    fn restart(&self) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(self.rebind())
+    }
+
+    fn learn_peer(&self, peer_info: PeerInfo) -> BoxFuture<'_, bool> {
+        Box::pin(DiscoveryService::learn_peer(self, peer_info))
+    }
+
+    fn age_peers(&self, by: Duration) -> BoxFuture<'_, ()> {
+        Box::pin(DiscoveryService::age_peers(self, by))
+    }
+
+    fn stats(&self) -> DiscoveryStats {
+        DiscoveryService::stats(self)
+    }
+
+    fn rejections(&self) -> RejectionCounts {
+        DiscoveryService::rejections(self)
+    }
+
+    fn errors(&self) -> ErrorCounts {
+        DiscoveryService::errors(self)
+    }
+
+    fn recorder(&self) -> Option<Arc<TrafficRecorder>> {
+        DiscoveryService::recorder(self).cloned()
+    }
+
+    #[cfg(feature = "chaos")]
+    fn faults(&self) -> Option<Arc<FaultInjector>> {
+        Some(DiscoveryService::faults(self).clone())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::test_peer_info;
     use std::{sync::Arc, time::Duration};
     use tokio::time;
 
     #[tokio::test]
     /// testing for serializing and deserializing peer info wire
     async fn peer_info_wire_roundtrip() {
-        let pi = test_peer_info(6000);
+        let pi = test_peer_info();
         let wire: PeerInfoWire = pi.clone().into();
         let bytes = bincode::serialize(&wire).unwrap();
         let wire2: PeerInfoWire = bincode::deserialize(&bytes).unwrap();
@@ -207,6 +1211,7 @@ mod tests {
         assert_eq!(pi.peer_id, pi2.peer_id);
         assert_eq!(pi.spare_mbs, pi2.spare_mbs);
         assert_eq!(pi.price, pi2.price);
+        assert_eq!(pi.egress_price_per_gib, pi2.egress_price_per_gib);
     }
 
     #[tokio::test]
@@ -214,24 +1219,17 @@ mod tests {
     /// we bind the destination to the other service's address
     /// because we cannot send to a multicast address
     async fn discovery_roundtrip_on_loopback() {
-        let svc_a = Arc::new(
-            DiscoveryService::test_with_addr(
-                test_peer_info(6000),
-                "127.0.0.1:6000",
-                "127.0.0.1:6002",
-            )
-            .await
-            .unwrap(),
-        );
-        let svc_b = Arc::new(
-            DiscoveryService::test_with_addr(
-                test_peer_info(6002),
-                "127.0.0.1:6002",
-                "127.0.0.1:6000",
-            )
-            .await
-            .unwrap(),
-        );
+        let a = loopback_service().await;
+        let b = DiscoveryService::test_with_addr(
+            test_peer_info(),
+            "127.0.0.1:0",
+            &a.local_addr().unwrap().to_string(),
+        )
+        .await
+        .unwrap();
+        // a was bound before b existed, so reach b through a unicast target
+        let a = a.with_unicast_targets(vec![b.local_addr().unwrap()]);
+        let (svc_a, svc_b) = (Arc::new(a), Arc::new(b));
 
         // run both services
         tokio::spawn(svc_a.clone().start());
@@ -246,42 +1244,441 @@ mod tests {
 
         // check
         assert!(
-            peers_a.iter().any(|p| p.peer_id == svc_b.peer_info.peer_id),
+            peers_a.iter().any(|p| p.peer_id == svc_b.peer_id),
+            "A should see B"
+        );
+        assert!(
+            peers_b.iter().any(|p| p.peer_id == svc_a.peer_id),
+            "B should see A"
+        );
+    }
+
+    #[tokio::test]
+    /// a stopped service rebinds its port while the old socket is still
+    /// held, and once started again is heard and hears its peers
+    async fn rebind_after_stop_rejoins() {
+        let config = DiscoveryConfig {
+            announce_interval: Duration::from_millis(100),
+            peer_timeout: Duration::from_millis(500),
+            sweep_interval: Duration::from_millis(100),
+        };
+        let a = loopback_service().await.with_config(config).unwrap();
+        let b = DiscoveryService::test_with_addr(
+            test_peer_info(),
+            "127.0.0.1:0",
+            &a.local_addr().unwrap().to_string(),
+        )
+        .await
+        .unwrap()
+        .with_config(config)
+        .unwrap();
+        let a = a.with_unicast_targets(vec![b.local_addr().unwrap()]);
+        let (svc_a, svc_b) = (Arc::new(a), Arc::new(b));
+        let mut events = svc_b.subscribe();
+        let running = tokio::spawn(svc_a.clone().start());
+        tokio::spawn(svc_b.clone().start());
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(svc_b
+            .get_peers()
+            .await
+            .iter()
+            .any(|p| p.peer_id == svc_a.peer_id));
+
+        running.abort();
+        let _ = running.await;
+        let expired = time::timeout(Duration::from_secs(3), async {
+            while events.recv().await.unwrap() != DiscoveryEvent::PeerExpired(svc_a.peer_id) {}
+        });
+        assert!(expired.await.is_ok(), "B should forget the stopped A");
+
+        let addr = svc_a.local_addr().unwrap();
+        svc_a.rebind().await.unwrap();
+        assert_eq!(svc_a.local_addr().unwrap(), addr);
+        tokio::spawn(svc_a.clone().start());
+        let rediscovered = time::timeout(Duration::from_secs(3), async {
+            loop {
+                if let DiscoveryEvent::PeerDiscovered(peer) = events.recv().await.unwrap() {
+                    if peer.peer_id == svc_a.peer_id {
+                        break;
+                    }
+                }
+            }
+        });
+        assert!(rediscovered.await.is_ok(), "B should see A again");
+        // outlast the timeout, so B is only still known if A keeps hearing it
+        time::sleep(Duration::from_millis(700)).await;
+        assert!(svc_a
+            .get_peers()
+            .await
+            .iter()
+            .any(|p| p.peer_id == svc_b.peer_id));
+    }
+
+    #[tokio::test]
+    /// a config with any zero duration is refused instead of panicking in
+    /// the timers
+    async fn zero_durations_rejected() {
+        for config in [
+            DiscoveryConfig {
+                announce_interval: Duration::ZERO,
+                ..DiscoveryConfig::default()
+            },
+            DiscoveryConfig {
+                sweep_interval: Duration::ZERO,
+                ..DiscoveryConfig::default()
+            },
+        ] {
+            assert!(matches!(
+                loopback_service().await.with_config(config),
+                Err(ConfigError::ZeroDuration(_))
+            ));
+        }
+        assert!(loopback_service()
+            .await
+            .with_config(DiscoveryConfig::default())
+            .is_ok());
+    }
+
+    #[tokio::test]
+    /// an observer learns announced peers but is never announced itself
+    async fn observer_never_announces() {
+        let a = loopback_service().await;
+        let observer = DiscoveryService::test_with_addr(
+            test_peer_info(),
+            "127.0.0.1:0",
+            &a.local_addr().unwrap().to_string(),
+        )
+        .await
+        .unwrap()
+        .observing();
+        let a = a.with_unicast_targets(vec![observer.local_addr().unwrap()]);
+        let (a, observer) = (Arc::new(a), Arc::new(observer));
+        tokio::spawn(a.clone().start());
+        tokio::spawn(observer.clone().start());
+
+        time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(observer.get_peers().await.len(), 1);
+        assert!(a.get_peers().await.is_empty());
+        assert_eq!(observer.stats().sent, 0);
+    }
+
+    #[tokio::test]
+    /// peers still discover each other when the multicast destination is dead
+    /// but they are listed as unicast fallback targets
+    async fn discovery_via_unicast_targets() {
+        let a = loopback_service().await;
+        let b = loopback_service().await;
+        let (addr_a, addr_b) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let svc_a = Arc::new(a.with_unicast_targets(vec![addr_b]));
+        let svc_b = Arc::new(b.with_unicast_targets(vec![addr_a]));
+
+        tokio::spawn(svc_a.clone().start());
+        tokio::spawn(svc_b.clone().start());
+        time::sleep(Duration::from_secs(3)).await;
+
+        assert!(
+            svc_a
+                .get_peers()
+                .await
+                .iter()
+                .any(|p| p.peer_id == svc_b.peer_id),
             "A should see B"
         );
         assert!(
-            peers_b.iter().any(|p| p.peer_id == svc_a.peer_info.peer_id),
+            svc_b
+                .get_peers()
+                .await
+                .iter()
+                .any(|p| p.peer_id == svc_a.peer_id),
             "B should see A"
         );
     }
 
+    #[tokio::test]
+    /// a peer outside the group joins through one bootstrap address: it
+    /// learns the bootstrap peer and the peers that one knows, and the
+    /// bootstrap peer learns it
+    async fn discovery_via_bootstrap() {
+        let a = loopback_service().await;
+        let b = loopback_service().await;
+        let joiner = loopback_service().await;
+        let (addr_a, addr_b) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let a = Arc::new(
+            a.with_unicast_targets(vec![addr_b])
+                .with_bootstrap_server(true),
+        );
+        let b = Arc::new(b.with_unicast_targets(vec![addr_a]));
+        let joiner = Arc::new(joiner.with_bootstrap(vec![addr_a]));
+
+        for svc in [&a, &b, &joiner] {
+            tokio::spawn(svc.clone().start());
+        }
+        time::sleep(Duration::from_secs(5)).await;
+
+        let mut seen: Vec<_> = joiner
+            .get_peers()
+            .await
+            .into_iter()
+            .map(|p| p.peer_id)
+            .collect();
+        seen.sort();
+        let mut expected = vec![a.peer_id, b.peer_id];
+        expected.sort();
+        assert_eq!(seen, expected);
+        assert!(a
+            .get_peers()
+            .await
+            .iter()
+            .any(|p| p.peer_id == joiner.peer_id));
+        assert!(!b
+            .get_peers()
+            .await
+            .iter()
+            .any(|p| p.peer_id == joiner.peer_id));
+    }
+
+    #[tokio::test]
+    /// only a bootstrap server answers bootstrap requests, and it answers a
+    /// source at most once per announce interval
+    async fn bootstrap_answers_opt_in_and_rate_limited() {
+        let plain = Arc::new(loopback_service().await);
+        let server = Arc::new(loopback_service().await.with_bootstrap_server(true));
+        for svc in [&plain, &server] {
+            tokio::spawn(svc.clone().start());
+        }
+        let mut announcement = MAGIC_HEADER.to_vec();
+        announcement.extend_from_slice(&encode_announcement(&PeerInfoWire::from(test_peer_info())));
+        let request = bootstrap_request(&announcement);
+        let asker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; MAX_ANNOUNCEMENT_BYTES];
+        for (svc, expected) in [(&plain, 0), (&server, 1)] {
+            let addr = svc.local_addr().unwrap();
+            for _ in 0..3 {
+                asker.send_to(&request, addr).await.unwrap();
+            }
+            let mut answers = 0;
+            while time::timeout(Duration::from_millis(300), asker.recv_from(&mut buf))
+                .await
+                .is_ok()
+            {
+                answers += 1;
+            }
+            assert_eq!(answers, expected);
+        }
+    }
+
+    #[tokio::test]
+    /// foreign, oversized, malformed and invalid datagrams are counted by
+    /// reason and never reach the peer table
+    async fn rejections_counted_by_reason() {
+        let svc = Arc::new(loopback_service().await.observing());
+        let addr = svc.local_addr().unwrap();
+        tokio::spawn(svc.clone().start());
+
+        let mut bad_id = PeerInfoWire::from(test_peer_info());
+        bad_id.peer_id_bytes = vec![1, 2, 3].into();
+        let mut invalid = MAGIC_HEADER.to_vec();
+        invalid.extend_from_slice(&encode_announcement(&bad_id));
+        let mut oversized = MAGIC_HEADER.to_vec();
+        oversized.resize(MAX_ANNOUNCEMENT_BYTES + 1, 0);
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for datagram in [
+            b"\x00\x00\x84\x00".to_vec(),
+            b"SP".to_vec(),
+            oversized,
+            b"SPAR\xff".to_vec(),
+            invalid,
+        ] {
+            sender.send_to(&datagram, addr).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(
+            svc.rejections(),
+            RejectionCounts {
+                foreign: 2,
+                oversized: 1,
+                malformed: 1,
+                invalid: 1,
+            }
+        );
+        assert_eq!(svc.stats().oversized, 1);
+        assert!(svc.get_peers().await.is_empty());
+    }
+
+    #[tokio::test]
+    /// metadata that pushes the announcement past the datagram limit is
+    /// trimmed from the end and counted
+    async fn oversized_announcement_drops_metadata() {
+        let mut peer_info = test_peer_info();
+        // small entries within MAX_METADATA_BYTES, but each pays two length
+        // prefixes on the wire
+        peer_info.metadata = (0..60).map(|i| (format!("k{i:02}"), "v".into())).collect();
+        let svc = DiscoveryService::test_with_addr(peer_info, "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+
+        let data = svc.encode_announcement();
+        assert!(data.len() <= MAX_ANNOUNCEMENT_BYTES);
+        let piw = decode_announcement(&data[MAGIC_HEADER.len()..]).unwrap();
+        let dropped = svc.stats().metadata_dropped as usize;
+        assert!(dropped > 0);
+        assert_eq!(piw.metadata.len(), 60 - dropped);
+        assert!(piw.metadata.contains_key("k00"));
+    }
+
+    #[tokio::test]
+    /// aging peers past the timeout removes them, a short age keeps them
+    async fn age_peers_after_suspend() {
+        let svc = loopback_service().await;
+        let peer = test_peer_info();
+        svc.record_announcement(peer.clone()).await;
+
+        svc.age_peers(Duration::from_secs(1)).await;
+        assert_eq!(svc.get_peers().await, vec![peer]);
+        svc.age_peers(svc.config.peer_timeout).await;
+        assert!(svc.get_peers().await.is_empty());
+
+        let stats = svc.network_stats().await;
+        assert_eq!((stats.peers, stats.joined, stats.left), (0, 1, 1));
+    }
+
+    #[tokio::test]
+    /// own echoes and repeated identical announcements are suppressed
+    async fn duplicate_announcements_suppressed() {
+        let svc = loopback_service().await;
+        let other = test_peer_info();
+
+        assert!(!svc.record_announcement(svc.get_peer_info()).await);
+        assert!(svc.record_announcement(other.clone()).await);
+        assert!(!svc.record_announcement(other.clone()).await);
+
+        let mut changed = other.clone();
+        changed.spare_mbs += 1;
+        assert!(svc.record_announcement(changed).await);
+
+        let stats = svc.stats();
+        assert_eq!(stats.received, 4);
+        assert_eq!(stats.suppressed, 2);
+        assert_eq!(svc.get_peers().await.len(), 1);
+    }
+
+    #[tokio::test]
+    /// subscribers hear joins, changes and timeouts, but not refreshes
+    async fn subscribe_to_peer_events() {
+        let svc = loopback_service().await;
+        let mut events = svc.subscribe();
+        let peer = test_peer_info();
+
+        svc.record_announcement(peer.clone()).await;
+        svc.refresh_peer(peer.peer_id).await;
+        let mut changed = peer.clone();
+        changed.price += 1.0;
+        svc.record_announcement(changed.clone()).await;
+        svc.age_peers(svc.config.peer_timeout * 2).await;
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            DiscoveryEvent::PeerDiscovered(peer.clone())
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            DiscoveryEvent::PeerUpdated(changed)
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            DiscoveryEvent::PeerExpired(peer.peer_id)
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    /// a snapshot survives a JSON roundtrip and seeds another service
+    async fn snapshot_export_import() {
+        let src = loopback_service().await;
+        let dst = loopback_service().await;
+
+        let known = test_peer_info();
+        src.record_announcement(known.clone()).await;
+        src.record_announcement(dst.get_peer_info()).await;
+
+        let json = serde_json::to_string(&src.export_snapshot().await).unwrap();
+        let snapshot: PeerSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.peers.len(), 2);
+
+        // dst's own entry is skipped
+        assert_eq!(dst.import_snapshot(snapshot).await, 1);
+        let peers = dst.get_peers().await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0], known);
+    }
+
     #[tokio::test]
     /// sweep stale peer
     async fn sweep_stale_peer() {
         time::pause();
-        let svc = Arc::new(DiscoveryService::new(test_peer_info(9000)).await.unwrap());
-        // run in block to drop reference and unlock the peers map
-        {
-            let mut map = svc.peers.lock().await;
-            let pi = test_peer_info(9001);
-            map.insert(
-                pi.peer_id,
-                (
-                    pi,
-                    Instant::now() - Duration::from_secs(PEER_TIMEOUT.as_secs() + 1),
-                ),
-            );
-        }
+        let svc = Arc::new(loopback_service().await);
+        let stale = Instant::now() - svc.config.peer_timeout - Duration::from_secs(1);
+        svc.peers.insert(&test_peer_info(), stale, |_, _| false);
         svc.sweep_once().await;
         assert!(svc.get_peers().await.is_empty());
     }
 
-    fn test_peer_info(port: u16) -> PeerInfo {
-        PeerInfo {
-            addr: format!("127.0.0.1:{}", port).parse().unwrap(),
-            peer_id: PeerId::random(),
-            spare_mbs: 11,
-            price: 11.0,
-        }
+    #[tokio::test]
+    /// a configured timeout and sweep interval expire peers on that
+    /// schedule rather than the default one
+    async fn configured_timing() {
+        let config = DiscoveryConfig {
+            announce_interval: Duration::from_millis(50),
+            peer_timeout: Duration::from_millis(200),
+            sweep_interval: Duration::from_millis(20),
+        };
+        let svc = Arc::new(loopback_service().await.with_config(config).unwrap());
+        let peer = test_peer_info();
+        svc.record_announcement(peer.clone()).await;
+        tokio::spawn(svc.clone().start());
+
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(svc.get_peers().await, vec![peer]);
+        time::sleep(Duration::from_millis(250)).await;
+        assert!(svc.get_peers().await.is_empty());
+        // our own echo every 50ms, so several sent in 350ms
+        assert!(svc.stats().sent >= 4);
+    }
+
+    /// service on an ephemeral loopback port whose multicast destination is
+    /// its own socket (our own echo is suppressed)
+    async fn loopback_service() -> DiscoveryService {
+        let mut svc =
+            DiscoveryService::test_with_addr(test_peer_info(), "127.0.0.1:0", "127.0.0.1:9")
+                .await
+                .unwrap();
+        svc.dest = svc.local_addr().unwrap();
+        svc
+    }
+
+    #[tokio::test]
+    /// a repeated announcement only refreshes the peer; a changed one from
+    /// the same source is parsed and recorded
+    async fn unchanged_announcements_skip_parsing() {
+        let svc = DiscoveryService::test_with_addr(test_peer_info(), "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+        let src: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let mut peer = test_peer_info();
+        let mut digests = HashMap::new();
+        let encode = |peer: &PeerInfo| encode_announcement(&PeerInfoWire::from(peer.clone()));
+
+        svc.handle_announcement(src, &encode(&peer), &mut digests)
+            .await;
+        svc.handle_announcement(src, &encode(&peer), &mut digests)
+            .await;
+        assert_eq!(svc.stats().unchanged, 1);
+
+        peer.spare_mbs += 1;
+        svc.handle_announcement(src, &encode(&peer), &mut digests)
+            .await;
+        assert_eq!(svc.stats().unchanged, 1);
+        assert_eq!(svc.get_peers().await, vec![peer]);
     }
 }
@@ -0,0 +1,990 @@
+//! Discovery over standard mDNS/DNS-SD (RFC 6762, RFC 6763) instead of the
+//! built-in bincode announcements. Each agent advertises a
+//! `<peer id>._sparenet._udp.local.` instance of [`SERVICE_TYPE`] whose SRV
+//! record carries the deal port and whose TXT record carries the peer id,
+//! capacity and prices, so it shares port 5353 with Avahi or Bonjour and
+//! shows up in `avahi-browse _sparenet._udp` or `dns-sd -B _sparenet._udp`.
+//! Storage offers and metadata don't fit the TXT record and are not
+//! carried.
+
+use futures::future::BoxFuture;
+use hickory_proto::{
+    op::{Message, MessageType, Query},
+    rr::{
+        rdata::{A, AAAA, PTR, SRV, TXT},
+        Name, RData, Record, RecordType,
+    },
+    ProtoError,
+};
+use libp2p::PeerId;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use thiserror::Error as ThisError;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, Notify},
+    time,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    discovery::{
+        bind_reusable, ConfigError, Discovery, DiscoveryConfig, DiscoveryEvent, DiscoveryStats,
+        PeerTable, MAX_SOCKET_ERRORS,
+    },
+    metrics::{ErrorCounters, ErrorCounts, ErrorKind, Rejection, RejectionCounts, RejectionLog},
+    peer_info::{Capabilities, Features, PeerInfo, PeerInfoError, PeerInfoWire},
+};
+
+/// DNS-SD service type spare-net agents advertise.
+pub const SERVICE_TYPE: &str = "_sparenet._udp.local.";
+/// Name browsers query to enumerate every service type on the link.
+const SERVICES_ENUMERATION: &str = "_services._dns-sd._udp.local.";
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// Largest mDNS message, per RFC 6762 section 17.
+const MAX_MESSAGE_BYTES: usize = 9000;
+/// Version of the TXT keys below, sent first as RFC 6763 suggests.
+const TXT_VERSION: &str = "txtvers=1";
+/// Unsolicited announcements sent at startup and whenever our info changes,
+/// one second apart and then twice as far each time (RFC 6762 section 8.3).
+const ANNOUNCEMENTS: u32 = 3;
+/// Cap on the doubling interval between browse queries (RFC 6762 section
+/// 5.2).
+const MAX_BROWSE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Answers to queries wait a random 20-120ms, so responders sharing the
+/// link don't all answer at once (RFC 6762 section 6).
+const ANSWER_DELAY_MS: std::ops::RangeInclusive<u64> = 20..=120;
+
+/// TTLs of the records in an [`announcement`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ttls {
+    /// SRV and A/AAAA, the records naming our host
+    pub host: u32,
+    /// PTR and TXT
+    pub other: u32,
+}
+
+impl Ttls {
+    /// RFC 6762 section 10: two minutes for host records, 75 minutes for the
+    /// rest.
+    pub const STANDARD: Ttls = Ttls {
+        host: 120,
+        other: 75 * 60,
+    };
+    /// Withdraws the records.
+    pub const GOODBYE: Ttls = Ttls { host: 0, other: 0 };
+}
+
+/// Why a `_sparenet._udp` instance couldn't be turned into a peer.
+#[derive(Debug, ThisError)]
+pub enum DnsSdError {
+    #[error("no {0} record for {1}")]
+    MissingRecord(RecordType, Name),
+    #[error("TXT key `{0}` is missing")]
+    MissingKey(&'static str),
+    #[error("TXT key `{0}` has an invalid value")]
+    BadValue(&'static str),
+    #[error(transparent)]
+    PeerInfo(#[from] PeerInfoError),
+}
+
+/// An instance of [`SERVICE_TYPE`] read from an mDNS response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Announced {
+    Peer(PeerInfo),
+    /// the peer withdrew its records (TTL 0)
+    Goodbye(PeerId),
+}
+
+fn name(ascii: &str) -> Name {
+    Name::from_ascii(ascii).expect("valid DNS name")
+}
+
+/// peer ids are case-sensitive, so they are added as raw bytes; string
+/// labels are lowercased
+fn instance_name(peer_id: &PeerId) -> Result<Name, ProtoError> {
+    name(SERVICE_TYPE).prepend_label(peer_id.to_base58().into_bytes())
+}
+
+fn host_name(peer_id: &PeerId) -> Result<Name, ProtoError> {
+    name("local.").prepend_label(peer_id.to_base58().into_bytes())
+}
+
+/// `key=value` TXT strings for `peer_info`.
+fn txt_entries(peer_info: &PeerInfo) -> Vec<String> {
+    let mut entries = vec![
+        TXT_VERSION.to_string(),
+        format!("peer_id={}", peer_info.peer_id),
+        format!("spare_mbs={}", peer_info.spare_mbs),
+        format!("price={}", peer_info.price),
+        format!("egress_price_per_gib={}", peer_info.egress_price_per_gib),
+        format!("burst_mbs={}", peer_info.burst_mbs),
+        format!("burst_price={}", peer_info.burst_price),
+        format!("features={}", peer_info.features.0),
+        format!("capabilities={}", peer_info.capabilities.0),
+    ];
+    if let Some(region) = &peer_info.region {
+        entries.push(format!("region={region}"));
+    }
+    if let Some(since) = peer_info.online_since {
+        entries.push(format!("online_since={since}"));
+    }
+    entries
+}
+
+/// The PTR record listing `peer_id`'s instance under [`SERVICE_TYPE`].
+fn service_ptr(peer_id: &PeerId, ttl: u32) -> Result<Record, ProtoError> {
+    Ok(Record::from_rdata(
+        name(SERVICE_TYPE),
+        ttl,
+        RData::PTR(PTR(instance_name(peer_id)?)),
+    ))
+}
+
+/// Unsolicited mDNS response advertising `peer_info`: PTR, SRV and TXT
+/// answers plus the host's address. [`Ttls::GOODBYE`] withdraws them.
+pub fn announcement(peer_info: &PeerInfo, ttls: Ttls) -> Result<Message, ProtoError> {
+    let instance = instance_name(&peer_info.peer_id)?;
+    let host = host_name(&peer_info.peer_id)?;
+    let mut srv = Record::from_rdata(
+        instance.clone(),
+        ttls.host,
+        RData::SRV(SRV::new(0, 0, peer_info.addr.port(), host.clone())),
+    );
+    let mut txt = Record::from_rdata(
+        instance.clone(),
+        ttls.other,
+        RData::TXT(TXT::new(txt_entries(peer_info))),
+    );
+    let address = match peer_info.addr.ip() {
+        IpAddr::V4(ip) => RData::A(A(ip)),
+        IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+    };
+    let mut host_record = Record::from_rdata(host, ttls.host, address);
+    // records unique to this peer replace cached copies; the PTR is shared
+    srv.set_mdns_cache_flush(true);
+    txt.set_mdns_cache_flush(true);
+    host_record.set_mdns_cache_flush(true);
+
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Response)
+        .set_authoritative(true)
+        .add_answer(service_ptr(&peer_info.peer_id, ttls.other)?)
+        .add_answers([srv, txt])
+        .add_additional(host_record);
+    Ok(message)
+}
+
+/// Whether `query` already lists `record` among its known answers with at
+/// least half its TTL left, so answering would tell the asker nothing new
+/// (known-answer suppression, RFC 6762 section 7.1).
+fn known_answer(query: &Message, record: &Record) -> bool {
+    query.answers().iter().any(|known| {
+        known.name() == record.name()
+            && known.data() == record.data()
+            && u64::from(known.ttl()) * 2 >= u64::from(record.ttl())
+    })
+}
+
+/// Whether `query` asks for our service's instances.
+fn asks_for_service(query: &Query) -> bool {
+    matches!(query.query_type(), RecordType::PTR | RecordType::ANY)
+        && *query.name() == name(SERVICE_TYPE)
+}
+
+/// Whether `query` asks for the SRV or TXT record of `instance`, as a
+/// browser resolving it, or refreshing its cache, does.
+fn asks_for_instance(query: &Query, instance: &Name) -> bool {
+    matches!(
+        query.query_type(),
+        RecordType::SRV | RecordType::TXT | RecordType::ANY
+    ) && query.name() == instance
+}
+
+/// Whether `query` enumerates the service types on the link.
+fn asks_for_services(query: &Query) -> bool {
+    matches!(query.query_type(), RecordType::PTR | RecordType::ANY)
+        && *query.name() == name(SERVICES_ENUMERATION)
+}
+
+/// Every `_sparenet._udp` instance announced in `message`. Other services'
+/// records are ignored.
+pub fn parse_announcements(message: &Message) -> Vec<Result<Announced, DnsSdError>> {
+    let records: Vec<&Record> = message
+        .answers()
+        .iter()
+        .chain(message.additionals())
+        .collect();
+    let service = name(SERVICE_TYPE);
+    records
+        .iter()
+        .filter(|record| *record.name() == service)
+        .filter_map(|record| match record.data() {
+            RData::PTR(PTR(instance)) => Some(parse_instance(&records, instance, record.ttl())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `instance` only links the records together; the peer id is taken from
+/// the TXT record, which goodbyes carry too.
+fn parse_instance(records: &[&Record], instance: &Name, ttl: u32) -> Result<Announced, DnsSdError> {
+    let find = |owner: &Name, record_type: RecordType| {
+        records
+            .iter()
+            .find(|r| r.name() == owner && r.record_type() == record_type)
+            .map(|r| r.data())
+            .ok_or_else(|| DnsSdError::MissingRecord(record_type, owner.clone()))
+    };
+    let RData::TXT(txt) = find(instance, RecordType::TXT)? else {
+        unreachable!("record type checked");
+    };
+    let fields = TxtFields(
+        txt.txt_data()
+            .iter()
+            .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='))
+            .collect(),
+    );
+    let peer_id = fields.required("peer_id")?;
+    if ttl == 0 {
+        return Ok(Announced::Goodbye(peer_id));
+    }
+    let RData::SRV(srv) = find(instance, RecordType::SRV)? else {
+        unreachable!("record type checked");
+    };
+    let ip = match find(srv.target(), RecordType::A) {
+        Ok(RData::A(A(ip))) => IpAddr::V4(*ip),
+        _ => match find(srv.target(), RecordType::AAAA)? {
+            RData::AAAA(AAAA(ip)) => IpAddr::V6(*ip),
+            _ => unreachable!("record type checked"),
+        },
+    };
+    let peer_info = PeerInfo {
+        addr: SocketAddr::new(ip, srv.port()),
+        peer_id,
+        spare_mbs: fields.required("spare_mbs")?,
+        price: fields.required("price")?,
+        egress_price_per_gib: fields.optional("egress_price_per_gib")?.unwrap_or(0.0),
+        storage_offers: vec![],
+        burst_mbs: fields.optional("burst_mbs")?.unwrap_or(0),
+        burst_price: fields.optional("burst_price")?.unwrap_or(0.0),
+        features: Features(fields.optional("features")?.unwrap_or(0)),
+        metadata: Default::default(),
+        region: fields.optional("region")?,
+        online_since: fields.optional("online_since")?,
+        capabilities: Capabilities(fields.optional("capabilities")?.unwrap_or(0)),
+    };
+    // apply the same limits as the bincode announcements
+    Ok(Announced::Peer(PeerInfo::try_from(PeerInfoWire::from(
+        peer_info,
+    ))?))
+}
+
+/// `key=value` pairs of a TXT record.
+struct TxtFields<'a>(HashMap<&'a str, &'a str>);
+
+impl TxtFields<'_> {
+    fn optional<T: FromStr>(&self, key: &'static str) -> Result<Option<T>, DnsSdError> {
+        self.0
+            .get(key)
+            .map(|value| value.parse().map_err(|_| DnsSdError::BadValue(key)))
+            .transpose()
+    }
+
+    fn required<T: FromStr>(&self, key: &'static str) -> Result<T, DnsSdError> {
+        self.optional(key)?.ok_or(DnsSdError::MissingKey(key))
+    }
+}
+
+/// A UDP socket bound to `bind` with address reuse, so it can share the mDNS
+/// port with the system responder, joined to the mDNS group if `multicast`.
+fn bind_socket(bind: SocketAddrV4, multicast: bool) -> io::Result<UdpSocket> {
+    let socket = bind_reusable(bind.into())?;
+    if multicast {
+        socket.join_multicast_v4(&MDNS_GROUP, bind.ip())?;
+        // RFC 6762 section 11: receivers drop mDNS packets with any other TTL
+        socket.set_multicast_ttl_v4(255)?;
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    received: AtomicU64,
+    suppressed: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// [`Discovery`] backend speaking mDNS/DNS-SD on RFC 6762's schedule rather
+/// than [`DiscoveryConfig`]'s announce interval and peer timeout. It
+/// announces a few times at startup and when our info changes, answers
+/// queries for [`SERVICE_TYPE`], our instance and the service type
+/// enumeration after a short random delay unless the asker already knows
+/// the answer, and browses with a backing-off interval. Peers are kept for
+/// the host records' TTL ([`Ttls::STANDARD`]) and queried for fresh records
+/// from 80% of it, as a cache would.
+#[derive(Debug)]
+pub struct DnsSdDiscovery {
+    peers: PeerTable,
+    socket: RwLock<Arc<UdpSocket>>,
+    bind_addr: SocketAddrV4,
+    /// join the mDNS group on bind; off for loopback tests
+    multicast: bool,
+    /// where announcements and answers go
+    dest: SocketAddr,
+    peer_info: RwLock<PeerInfo>,
+    peer_id: PeerId,
+    config: DiscoveryConfig,
+    counters: Counters,
+    errors: ErrorCounters,
+    rejections: RejectionLog,
+    /// wakes the announcer ahead of its interval
+    announce_now: Notify,
+}
+
+impl DnsSdDiscovery {
+    /// bind the mDNS port alongside any system responder and join the group
+    pub async fn new(peer_info: PeerInfo) -> Result<Self, Box<dyn Error>> {
+        let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT);
+        let socket = bind_socket(bind_addr, true)?;
+        Ok(Self::from_socket(
+            peer_info,
+            socket,
+            bind_addr,
+            true,
+            (MDNS_GROUP, MDNS_PORT).into(),
+        ))
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    /// [TEST ONLY] bound to `bind_addr` and sending to `dest_addr`, without
+    /// joining the mDNS group
+    pub async fn test_with_addr(
+        peer_info: PeerInfo,
+        bind_addr: &str,
+        dest_addr: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let SocketAddr::V4(bind) = bind_addr.parse()? else {
+            return Err("bind address must be IPv4".into());
+        };
+        let socket = bind_socket(bind, false)?;
+        let bind = match socket.local_addr()? {
+            SocketAddr::V4(bound) => bound,
+            SocketAddr::V6(_) => unreachable!("bound an IPv4 socket"),
+        };
+        Ok(Self::from_socket(
+            peer_info,
+            socket,
+            bind,
+            false,
+            dest_addr.parse()?,
+        ))
+    }
+
+    fn from_socket(
+        peer_info: PeerInfo,
+        socket: UdpSocket,
+        bind_addr: SocketAddrV4,
+        multicast: bool,
+        dest: SocketAddr,
+    ) -> Self {
+        DnsSdDiscovery {
+            peers: PeerTable::default(),
+            socket: RwLock::new(Arc::new(socket)),
+            bind_addr,
+            multicast,
+            dest,
+            peer_id: peer_info.peer_id,
+            peer_info: RwLock::new(peer_info),
+            config: DiscoveryConfig::default(),
+            counters: Counters::default(),
+            errors: ErrorCounters::default(),
+            rejections: RejectionLog::default(),
+            announce_now: Notify::new(),
+        }
+    }
+
+    /// announce and expire peers on `config`'s schedule instead of the
+    /// defaults. only takes effect if set before `start`; fails on zero
+    /// durations
+    pub fn with_config(mut self, config: DiscoveryConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        self.config = config;
+        Ok(self)
+    }
+
+    /// address the socket is actually bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+
+    fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.read().expect("socket lock poisoned").clone()
+    }
+
+    /// how long a peer is kept after its records were last heard
+    fn peer_ttl() -> Duration {
+        Duration::from_secs(Ttls::STANDARD.host.into())
+    }
+
+    async fn send(&self, message: &Message) -> io::Result<()> {
+        let bytes = message.to_vec().map_err(io::Error::other)?;
+        self.socket().send_to(&bytes, self.dest).await?;
+        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// announce [`ANNOUNCEMENTS`] times at startup, backing off, and again
+    /// whenever our info changes; answers to queries keep peers' caches
+    /// fresh in between
+    async fn announce_presence(&self) {
+        let mut errors = 0;
+        'burst: loop {
+            for i in 0..ANNOUNCEMENTS {
+                if i > 0 {
+                    tokio::select! {
+                        _ = time::sleep(Duration::from_secs(1 << (i - 1))) => {}
+                        _ = self.announce_now.notified() => continue 'burst,
+                    }
+                }
+                let sent =
+                    announcement(&self.get_peer_info(), Ttls::STANDARD).map_err(io::Error::other);
+                let sent = match sent {
+                    Ok(message) => self.send(&message).await,
+                    Err(e) => Err(e),
+                };
+                match sent {
+                    Ok(()) => errors = 0,
+                    Err(e) => {
+                        self.errors.record(ErrorKind::Socket);
+                        warn!("failed to send DNS-SD announcement: {e}");
+                        errors += 1;
+                        if errors >= MAX_SOCKET_ERRORS {
+                            warn!("mDNS socket keeps failing, stopping announcer");
+                            return;
+                        }
+                    }
+                }
+            }
+            self.announce_now.notified().await;
+        }
+    }
+
+    /// PTR records of the peers we know, as known answers for a browse:
+    /// only those with more than half their TTL left, so peers refresh the
+    /// rest
+    fn known_peers(&self, now: Instant) -> Vec<Record> {
+        let ttl = Ttls::STANDARD.other;
+        self.peers.with(|peers| {
+            peers
+                .iter()
+                .filter_map(|(peer_id, (_, seen))| {
+                    let age = now.saturating_duration_since(*seen).as_secs();
+                    let left = u64::from(ttl).checked_sub(age)?;
+                    (left * 2 > u64::from(ttl))
+                        .then(|| service_ptr(peer_id, left as u32).ok())
+                        .flatten()
+                })
+                .collect()
+        })
+    }
+
+    /// browse for the service with a doubling interval (RFC 6762 section
+    /// 5.2), and ask each known peer for its records at 80%, 85%, 90% and
+    /// 95% of their TTL (section 5.2 again) so live peers never lapse
+    async fn query_peers(&self) {
+        let peer_ttl = Self::peer_ttl();
+        let mut browse_in = Duration::from_secs(1);
+        let mut next_browse = Instant::now();
+        // last refresh query per peer: when it was last heard, and in which
+        // twentieth of its TTL we asked
+        let mut refreshed: HashMap<PeerId, (Instant, u64)> = HashMap::new();
+        let mut tick = time::interval(Duration::from_secs(1));
+        let mut errors = 0;
+        loop {
+            tick.tick().await;
+            let now = Instant::now();
+            let mut query = Message::new();
+            query.set_message_type(MessageType::Query);
+            if now >= next_browse {
+                query
+                    .add_query(Query::query(name(SERVICE_TYPE), RecordType::PTR))
+                    .add_answers(self.known_peers(now));
+                next_browse = now + browse_in;
+                browse_in = (browse_in * 2).min(MAX_BROWSE_INTERVAL);
+            }
+            let due: Vec<_> = self.peers.with(|peers| {
+                peers
+                    .iter()
+                    .filter_map(|(peer_id, (_, seen))| {
+                        let twentieth = now.saturating_duration_since(*seen).as_millis() * 20
+                            / peer_ttl.as_millis();
+                        let twentieth = twentieth as u64;
+                        (16..20)
+                            .contains(&twentieth)
+                            .then_some((*peer_id, *seen, twentieth))
+                    })
+                    .collect()
+            });
+            refreshed.retain(|peer_id, _| due.iter().any(|(id, ..)| id == peer_id));
+            for (peer_id, seen, twentieth) in due {
+                if refreshed.insert(peer_id, (seen, twentieth)) != Some((seen, twentieth)) {
+                    if let Ok(instance) = instance_name(&peer_id) {
+                        query.add_query(Query::query(instance, RecordType::SRV));
+                    }
+                }
+            }
+            if query.queries().is_empty() {
+                continue;
+            }
+            match self.send(&query).await {
+                Ok(()) => errors = 0,
+                Err(e) => {
+                    self.errors.record(ErrorKind::Socket);
+                    warn!("failed to send DNS-SD query: {e}");
+                    errors += 1;
+                    if errors >= MAX_SOCKET_ERRORS {
+                        warn!("mDNS socket keeps failing, stopping queries");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn listen_to_peers(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_MESSAGE_BYTES];
+        let mut errors = 0;
+        loop {
+            let (len, src) = match self.socket().recv_from(&mut buf).await {
+                Ok(pair) => {
+                    errors = 0;
+                    pair
+                }
+                Err(e) => {
+                    self.errors.record(ErrorKind::Socket);
+                    warn!("error reading from mDNS socket: {e}");
+                    errors += 1;
+                    if errors >= MAX_SOCKET_ERRORS {
+                        warn!("mDNS socket keeps failing, stopping listener");
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let datagram = &buf[..len];
+            let message = match Message::from_vec(datagram) {
+                Ok(message) => message,
+                Err(e) => {
+                    self.errors.record(ErrorKind::Deserialize);
+                    self.rejections
+                        .record(Rejection::Malformed, src, datagram, e);
+                    continue;
+                }
+            };
+            match message.message_type() {
+                MessageType::Query => {
+                    if let Some(response) = self.response_to(&message) {
+                        tokio::spawn(self.clone().answer(response));
+                    }
+                }
+                MessageType::Response => self.handle_response(src, datagram, &message),
+            }
+        }
+    }
+
+    /// our records in reply to a browse for our service or a query for our
+    /// instance, or our service type in reply to an enumeration; `None` if
+    /// nothing is asked of us or the asker already knows the answer
+    fn response_to(&self, query: &Message) -> Option<Message> {
+        let questions = query.queries();
+        let ours = announcement(&self.get_peer_info(), Ttls::STANDARD).ok()?;
+        let record = |record_type| {
+            ours.answers()
+                .iter()
+                .find(|r| r.record_type() == record_type)
+        };
+        let (ptr, srv) = (record(RecordType::PTR)?, record(RecordType::SRV)?);
+        let browsed = questions.iter().any(asks_for_service) && !known_answer(query, ptr);
+        let resolved =
+            questions.iter().any(|q| asks_for_instance(q, srv.name())) && !known_answer(query, srv);
+        if browsed || resolved {
+            return Some(ours);
+        }
+        let enumeration = Record::from_rdata(
+            name(SERVICES_ENUMERATION),
+            Ttls::STANDARD.other,
+            RData::PTR(PTR(name(SERVICE_TYPE))),
+        );
+        if questions.iter().any(asks_for_services) && !known_answer(query, &enumeration) {
+            let mut message = Message::new();
+            message
+                .set_message_type(MessageType::Response)
+                .set_authoritative(true)
+                .add_answer(enumeration);
+            return Some(message);
+        }
+        None
+    }
+
+    async fn answer(self: Arc<Self>, response: Message) {
+        let delay = rand::thread_rng().gen_range(ANSWER_DELAY_MS);
+        time::sleep(Duration::from_millis(delay)).await;
+        if let Err(e) = self.send(&response).await {
+            self.errors.record(ErrorKind::Socket);
+            warn!("failed to answer mDNS query: {e}");
+        }
+    }
+
+    fn handle_response(&self, src: SocketAddr, datagram: &[u8], message: &Message) {
+        let announced = parse_announcements(message);
+        if announced.is_empty() {
+            self.rejections.record(
+                Rejection::Foreign,
+                src,
+                datagram,
+                "no _sparenet._udp records",
+            );
+            return;
+        }
+        for result in announced {
+            match result {
+                Ok(Announced::Peer(peer_info)) => self.record_announcement(peer_info),
+                Ok(Announced::Goodbye(peer_id)) => self.forget(peer_id),
+                Err(e) => {
+                    self.errors.record(ErrorKind::Deserialize);
+                    self.rejections.record(Rejection::Invalid, src, datagram, e);
+                }
+            }
+        }
+    }
+
+    fn record_announcement(&self, peer_info: PeerInfo) {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        if peer_info.peer_id == self.peer_id {
+            self.counters.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.peers.insert(&peer_info, Instant::now(), |_, _| false);
+    }
+
+    fn forget(&self, peer_id: PeerId) {
+        if self.peers.forget(peer_id) {
+            debug!("peer {peer_id} said goodbye");
+        }
+    }
+
+    /// drop peers whose host records have expired
+    pub fn sweep_once(&self) {
+        self.peers.sweep(Self::peer_ttl());
+    }
+
+    async fn sweep_timeout_peers(&self) {
+        let mut interval = time::interval(self.config.sweep_interval);
+        loop {
+            interval.tick().await;
+            self.sweep_once();
+        }
+    }
+
+    /// withdraw our records so other responders and agents drop us now
+    /// rather than when the TTL runs out
+    pub async fn goodbye(&self) -> io::Result<()> {
+        let message =
+            announcement(&self.get_peer_info(), Ttls::GOODBYE).map_err(io::Error::other)?;
+        self.send(&message).await
+    }
+}
+
+impl Discovery for DnsSdDiscovery {
+    fn get_peers(&self) -> BoxFuture<'_, Vec<PeerInfo>> {
+        Box::pin(async { self.peers.get_peers() })
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.peers.subscribe()
+    }
+
+    fn get_peer_info(&self) -> PeerInfo {
+        self.peer_info
+            .read()
+            .expect("peer info lock poisoned")
+            .clone()
+    }
+
+    fn announce(&self, peer_info: PeerInfo) {
+        if peer_info.peer_id != self.peer_id {
+            warn!(
+                "not announcing as {}, this service is {}",
+                peer_info.peer_id, self.peer_id
+            );
+            return;
+        }
+        *self.peer_info.write().expect("peer info lock poisoned") = peer_info;
+        self.announce_now.notify_one();
+    }
+
+    /// returns only if the socket keeps failing
+    fn start(self: Arc<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            tokio::select!(
+                _ = self.clone().listen_to_peers() => {}
+                _ = self.announce_presence() => {}
+                _ = self.query_peers() => {}
+                _ = self.sweep_timeout_peers() => {}
+            );
+        })
+    }
+
+    fn restart(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async {
+            let socket = bind_socket(self.bind_addr, self.multicast)?;
+            *self.socket.write().expect("socket lock poisoned") = Arc::new(socket);
+            Ok(())
+        })
+    }
+
+    fn learn_peer(&self, peer_info: PeerInfo) -> BoxFuture<'_, bool> {
+        Box::pin(async move {
+            peer_info.peer_id != self.peer_id
+                && self.peers.insert(&peer_info, Instant::now(), |_, _| true)
+        })
+    }
+
+    fn age_peers(&self, by: Duration) -> BoxFuture<'_, ()> {
+        Box::pin(async move { self.peers.age(by, Self::peer_ttl()) })
+    }
+
+    fn stats(&self) -> DiscoveryStats {
+        let (joined, left) = self.peers.churn();
+        DiscoveryStats {
+            received: self.counters.received.load(Ordering::Relaxed),
+            suppressed: self.counters.suppressed.load(Ordering::Relaxed),
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            joined,
+            left,
+            ..DiscoveryStats::default()
+        }
+    }
+
+    fn rejections(&self) -> RejectionCounts {
+        self.rejections.snapshot()
+    }
+
+    fn errors(&self) -> ErrorCounts {
+        self.errors.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_peer_info;
+
+    #[test]
+    /// an announcement survives the DNS wire format, and a TTL of 0 reads
+    /// as a goodbye
+    fn announcement_round_trip() {
+        let peer = PeerInfo {
+            addr: "192.168.1.20:5334".parse().unwrap(),
+            region: Some("eu-west".into()),
+            online_since: Some(1_700_000_000),
+            ..test_peer_info()
+        };
+        let bytes = announcement(&peer, Ttls::STANDARD)
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let message = Message::from_vec(&bytes).unwrap();
+        let announced: Vec<_> = parse_announcements(&message)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(announced, vec![Announced::Peer(peer.clone())]);
+
+        let goodbye = announcement(&peer, Ttls::GOODBYE).unwrap();
+        assert!(matches!(
+            parse_announcements(&goodbye).as_slice(),
+            [Ok(Announced::Goodbye(id))] if *id == peer.peer_id
+        ));
+    }
+
+    #[test]
+    /// instances without capacity in their TXT record, or with a garbled
+    /// price, are rejected
+    fn incomplete_txt_rejected() {
+        let peer = test_peer_info();
+        let mut message = announcement(&peer, Ttls::STANDARD).unwrap();
+        let txt = message
+            .answers_mut()
+            .iter_mut()
+            .find(|r| r.record_type() == RecordType::TXT)
+            .unwrap();
+        let mut entries = txt_entries(&peer);
+        entries.retain(|entry| !entry.starts_with("spare_mbs="));
+        *txt.data_mut() = RData::TXT(TXT::new(entries.clone()));
+        assert!(matches!(
+            parse_announcements(&message).as_slice(),
+            [Err(DnsSdError::MissingKey("spare_mbs"))]
+        ));
+
+        entries.push("spare_mbs=10".into());
+        entries.retain(|entry| !entry.starts_with("price="));
+        entries.push("price=cheap".into());
+        let txt = message
+            .answers_mut()
+            .iter_mut()
+            .find(|r| r.record_type() == RecordType::TXT)
+            .unwrap();
+        *txt.data_mut() = RData::TXT(TXT::new(entries));
+        assert!(matches!(
+            parse_announcements(&message).as_slice(),
+            [Err(DnsSdError::BadValue("price"))]
+        ));
+    }
+
+    #[tokio::test]
+    /// two agents on loopback find each other: a browse query is answered
+    /// with the other's records, and a goodbye removes it right away
+    async fn agents_discover_each_other() {
+        let a = DnsSdDiscovery::test_with_addr(test_peer_info(), "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+        let mut b = DnsSdDiscovery::test_with_addr(test_peer_info(), "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+        b.dest = a.local_addr().unwrap();
+        let mut a = a;
+        a.dest = b.local_addr().unwrap();
+        let (a, b) = (Arc::new(a), Arc::new(b));
+        let mut events = a.subscribe();
+        tokio::spawn(a.clone().start());
+        tokio::spawn(b.clone().start());
+
+        let DiscoveryEvent::PeerDiscovered(found) = events.recv().await.unwrap() else {
+            panic!("expected a discovered peer");
+        };
+        assert_eq!(found, b.get_peer_info());
+
+        b.goodbye().await.unwrap();
+        loop {
+            if let DiscoveryEvent::PeerExpired(id) = events.recv().await.unwrap() {
+                assert_eq!(id, b.get_peer_info().peer_id);
+                break;
+            }
+        }
+        assert!(a.stats().sent > 0);
+    }
+
+    #[test]
+    /// host records get two minutes and the rest 75, per RFC 6762
+    fn standard_ttls() {
+        let message = announcement(&test_peer_info(), Ttls::STANDARD).unwrap();
+        for record in message.answers().iter().chain(message.additionals()) {
+            let expected = match record.record_type() {
+                RecordType::SRV | RecordType::A | RecordType::AAAA => 120,
+                _ => 75 * 60,
+            };
+            assert_eq!(record.ttl(), expected, "{}", record.record_type());
+        }
+    }
+
+    #[tokio::test]
+    /// browses and lookups of our instance are answered unless the asker
+    /// lists our record with at least half its TTL left
+    async fn known_answers_suppress_responses() {
+        let us = DnsSdDiscovery::test_with_addr(test_peer_info(), "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+        let ptr = service_ptr(&us.peer_id, Ttls::STANDARD.other).unwrap();
+        let browse = |known: Vec<Record>| {
+            let mut query = Message::new();
+            query
+                .set_message_type(MessageType::Query)
+                .add_query(Query::query(name(SERVICE_TYPE), RecordType::PTR))
+                .add_answers(known);
+            query
+        };
+        assert!(us.response_to(&browse(vec![])).is_some());
+        assert!(us.response_to(&browse(vec![ptr.clone()])).is_none());
+        let mut stale = ptr.clone();
+        stale.set_ttl(Ttls::STANDARD.other / 2 - 1);
+        assert!(us.response_to(&browse(vec![stale])).is_some());
+        let other = service_ptr(&PeerId::random(), Ttls::STANDARD.other).unwrap();
+        assert!(us.response_to(&browse(vec![other])).is_some());
+
+        let mut lookup = Message::new();
+        lookup.add_query(Query::query(
+            instance_name(&us.peer_id).unwrap(),
+            RecordType::SRV,
+        ));
+        assert!(us.response_to(&lookup).is_some());
+        let mut unrelated = Message::new();
+        unrelated.add_query(Query::query(name("_http._tcp.local."), RecordType::PTR));
+        assert!(us.response_to(&unrelated).is_none());
+    }
+
+    #[tokio::test]
+    /// a started agent announces three times, one then two seconds apart,
+    /// browses listing the peers it knows, and asks a peer nearing the end
+    /// of its TTL for fresh records
+    async fn startup_announcements_back_off() {
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut us = DnsSdDiscovery::test_with_addr(test_peer_info(), "127.0.0.1:0", "127.0.0.1:9")
+            .await
+            .unwrap();
+        us.dest = sink.local_addr().unwrap();
+        let us = Arc::new(us);
+        let fading = test_peer_info();
+        assert!(us.learn_peer(fading.clone()).await);
+        us.age_peers(Duration::from_secs(100)).await;
+        let start = Instant::now();
+        let task = tokio::spawn(us.clone().start());
+
+        let mut announced = vec![];
+        let (mut browsed, mut refreshed) = (false, false);
+        let mut buf = vec![0; MAX_MESSAGE_BYTES];
+        let fading_instance = instance_name(&fading.peer_id).unwrap();
+        while let Ok(Ok(len)) = time::timeout(
+            Duration::from_millis(3500).saturating_sub(start.elapsed()),
+            sink.recv(&mut buf),
+        )
+        .await
+        {
+            let message = Message::from_vec(&buf[..len]).unwrap();
+            match message.message_type() {
+                MessageType::Response => announced.push(start.elapsed()),
+                MessageType::Query => {
+                    for query in message.queries() {
+                        if asks_for_service(query) {
+                            browsed = true;
+                            assert_eq!(
+                                message.answers(),
+                                [service_ptr(&fading.peer_id, 75 * 60 - 100).unwrap()]
+                            );
+                        }
+                        refreshed |= asks_for_instance(query, &fading_instance);
+                    }
+                }
+            }
+        }
+        task.abort();
+        assert_eq!(announced.len(), 3, "{announced:?}");
+        assert!(announced[1] - announced[0] >= Duration::from_millis(900));
+        assert!(announced[2] - announced[1] >= Duration::from_millis(1900));
+        assert!(browsed && refreshed);
+    }
+}
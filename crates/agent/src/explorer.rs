@@ -0,0 +1,133 @@
+//! Network-wide statistics aggregated from a peer table, for explorers and
+//! dashboards (see [`DiscoveryService::network_stats`]).
+//!
+//! [`DiscoveryService::network_stats`]: crate::discovery::DiscoveryService::network_stats
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::peer_info::PeerInfo;
+
+/// Summary of the peers one agent or observer can see.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetworkStats {
+    /// peers currently in the table
+    pub peers: usize,
+    /// peers offering any spare or burst capacity
+    pub providers: usize,
+    /// committed capacity advertised by all peers, in MiB
+    pub spare_mbs: u64,
+    /// reclaimable capacity advertised by all peers, in MiB
+    pub burst_mbs: u64,
+    /// base price per MiB across providers; `None` without providers
+    pub price: Option<PricePercentiles>,
+    /// peers added to the table since the service started
+    pub joined: u64,
+    /// peers that timed out of the table since the service started
+    pub left: u64,
+    pub uptime_secs: u64,
+    /// joins plus departures per hour of uptime
+    pub churn_per_hour: f64,
+}
+
+/// Nearest-rank percentiles of a price distribution.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PricePercentiles {
+    pub min: f32,
+    pub p50: f32,
+    pub p90: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl PricePercentiles {
+    pub(crate) fn of(mut prices: Vec<f32>) -> Option<Self> {
+        if prices.is_empty() {
+            return None;
+        }
+        prices.sort_by(f32::total_cmp);
+        let rank = |p: f64| {
+            let idx = (p / 100.0 * prices.len() as f64).ceil() as usize;
+            prices[idx.clamp(1, prices.len()) - 1]
+        };
+        Some(PricePercentiles {
+            min: prices[0],
+            p50: rank(50.0),
+            p90: rank(90.0),
+            p99: rank(99.0),
+            max: prices[prices.len() - 1],
+        })
+    }
+}
+
+/// Aggregate `peers` along with the churn seen over `uptime`.
+pub fn network_stats(peers: &[PeerInfo], joined: u64, left: u64, uptime: Duration) -> NetworkStats {
+    let providers: Vec<_> = peers
+        .iter()
+        .filter(|p| p.spare_mbs > 0 || p.burst_mbs > 0)
+        .collect();
+    let hours = uptime.as_secs_f64() / 3600.0;
+    NetworkStats {
+        peers: peers.len(),
+        providers: providers.len(),
+        spare_mbs: peers.iter().map(|p| p.spare_mbs).sum(),
+        burst_mbs: peers.iter().map(|p| p.burst_mbs).sum(),
+        price: PricePercentiles::of(providers.iter().map(|p| p.price).collect()),
+        joined,
+        left,
+        uptime_secs: uptime.as_secs(),
+        churn_per_hour: if hours > 0.0 {
+            (joined + left) as f64 / hours
+        } else {
+            0.0
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::test_peer_info;
+
+    #[test]
+    /// capacity is summed over all peers, prices only over providers
+    fn aggregates_capacity_prices_and_churn() {
+        let mut peers: Vec<_> = (1..=10)
+            .map(|i| PeerInfo {
+                spare_mbs: 100,
+                price: i as f32,
+                ..test_peer_info()
+            })
+            .collect();
+        peers.push(PeerInfo {
+            spare_mbs: 0,
+            burst_mbs: 50,
+            price: 100.0,
+            ..test_peer_info()
+        });
+        peers.push(PeerInfo {
+            spare_mbs: 0,
+            price: 0.0,
+            ..test_peer_info()
+        });
+
+        let stats = network_stats(&peers, 15, 3, Duration::from_secs(1800));
+        assert_eq!(stats.peers, 12);
+        assert_eq!(stats.providers, 11);
+        assert_eq!(stats.spare_mbs, 1000);
+        assert_eq!(stats.burst_mbs, 50);
+        assert_eq!(
+            stats.price,
+            Some(PricePercentiles {
+                min: 1.0,
+                p50: 6.0,
+                p90: 10.0,
+                p99: 100.0,
+                max: 100.0,
+            })
+        );
+        assert_eq!(stats.churn_per_hour, 36.0);
+
+        assert_eq!(network_stats(&[], 0, 0, Duration::ZERO).price, None);
+    }
+}
@@ -1,5 +1,23 @@
 pub mod agent;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod composite;
 pub mod connection;
 pub mod deal;
 pub mod discovery;
+pub mod dnssd;
+pub mod explorer;
+pub mod market;
+pub mod metrics;
+pub mod notify;
+pub mod overrides;
 pub mod peer_info;
+pub mod placement;
+pub mod plugin;
+pub mod pricing;
+pub mod recorder;
+pub mod schema;
+pub mod test_vectors;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transfer;
@@ -0,0 +1,222 @@
+//! Price history: advertised and deal prices bucketed by hour, kept for a
+//! week, so pricing strategies can read the going rate and its trend. Each
+//! bucket also counts the proposals received, as a demand signal for
+//! capacity planning.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{deal::Deal, explorer::PricePercentiles};
+
+/// Width of one history bucket.
+pub const BUCKET: Duration = Duration::from_secs(60 * 60);
+/// How far back history is kept.
+pub const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Deal prices kept per bucket; later deals in a busy hour are not sampled.
+const MAX_DEALS_PER_BUCKET: usize = 1024;
+
+/// Prices seen during one [`BUCKET`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceBucket {
+    /// unix time the bucket starts, in seconds
+    pub start_secs: u64,
+    /// base price per MiB across providers, as last sampled in the bucket
+    pub advertised: Option<PricePercentiles>,
+    /// price per MiB of deals received during the bucket
+    pub deal_prices: Vec<f32>,
+    /// deals received during the bucket, including unsampled ones
+    #[serde(default)]
+    pub proposals: u64,
+    /// bytes those deals asked to store
+    #[serde(default)]
+    pub requested_bytes: u64,
+    /// deals too large for, or of a class missing from, our offer
+    #[serde(default)]
+    pub over_capacity: u64,
+}
+
+/// Proposals received over a window, see [`PriceHistory::demand`]. Counts
+/// only; who proposed is not kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Demand {
+    pub proposals: u64,
+    pub requested_bytes: u64,
+    /// proposals we couldn't have stored with the capacity offered then
+    pub over_capacity: u64,
+}
+
+/// Hourly price buckets, oldest first. Serializable so it can be saved and
+/// restored across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceHistory {
+    buckets: VecDeque<PriceBucket>,
+}
+
+impl PriceHistory {
+    pub fn buckets(&self) -> impl Iterator<Item = &PriceBucket> {
+        self.buckets.iter()
+    }
+
+    /// Replace the advertised price distribution for the bucket covering
+    /// `now_secs` with one sampled from `prices`.
+    pub fn record_advertised(&mut self, now_secs: u64, prices: Vec<f32>) {
+        self.bucket(now_secs).advertised = PricePercentiles::of(prices);
+    }
+
+    /// Count a received `deal`; `fits` says whether our offer had room for
+    /// it.
+    pub fn record_deal(&mut self, now_secs: u64, deal: &Deal, fits: bool) {
+        let bucket = self.bucket(now_secs);
+        if bucket.deal_prices.len() < MAX_DEALS_PER_BUCKET {
+            bucket.deal_prices.push(deal.price_per_mb);
+        }
+        bucket.proposals += 1;
+        // file lengths are the sender's claim, so sums must not overflow
+        bucket.requested_bytes = bucket.requested_bytes.saturating_add(deal.file_len);
+        if !fits {
+            bucket.over_capacity += 1;
+        }
+    }
+
+    /// Proposals received in buckets overlapping the `window` before
+    /// `now_secs`.
+    pub fn demand(&self, now_secs: u64, window: Duration) -> Demand {
+        let since = now_secs.saturating_sub(window.as_secs());
+        self.buckets
+            .iter()
+            .filter(|b| b.start_secs + BUCKET.as_secs() > since)
+            .fold(Demand::default(), |demand, b| Demand {
+                proposals: demand.proposals + b.proposals,
+                requested_bytes: demand.requested_bytes.saturating_add(b.requested_bytes),
+                over_capacity: demand.over_capacity + b.over_capacity,
+            })
+    }
+
+    /// Most recently sampled median advertised price per MiB.
+    pub fn median_price(&self) -> Option<f32> {
+        self.buckets
+            .iter()
+            .rev()
+            .find_map(|b| b.advertised.map(|p| p.p50))
+    }
+
+    /// Relative change of the median advertised price over the `window`
+    /// before `now_secs`, e.g. `-0.1` for a 10% drop; `None` without two
+    /// samples in the window.
+    pub fn trend(&self, now_secs: u64, window: Duration) -> Option<f32> {
+        let since = now_secs.saturating_sub(window.as_secs());
+        let mut medians = self
+            .buckets
+            .iter()
+            .filter(|b| b.start_secs + BUCKET.as_secs() > since)
+            .filter_map(|b| b.advertised.map(|p| p.p50));
+        let first = medians.next()?;
+        let last = medians.next_back()?;
+        (first > 0.0).then(|| (last - first) / first)
+    }
+
+    /// The bucket covering `now_secs`, opening it (and dropping buckets past
+    /// [`RETENTION`]) if needed. Samples older than the newest bucket go to
+    /// the newest one.
+    fn bucket(&mut self, now_secs: u64) -> &mut PriceBucket {
+        let start_secs = now_secs - now_secs % BUCKET.as_secs();
+        if self
+            .buckets
+            .back()
+            .is_none_or(|b| b.start_secs < start_secs)
+        {
+            self.buckets.push_back(PriceBucket {
+                start_secs,
+                ..PriceBucket::default()
+            });
+            while self
+                .buckets
+                .front()
+                .is_some_and(|b| b.start_secs + RETENTION.as_secs() <= start_secs)
+            {
+                self.buckets.pop_front();
+            }
+        }
+        self.buckets.back_mut().expect("bucket just ensured")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deal::BYTES_PER_MEBIBYTE,
+        testing::{deal_from, test_peer_info},
+    };
+
+    const HOUR: u64 = 60 * 60;
+
+    fn deal(price_per_mb: f32) -> Deal {
+        Deal {
+            price_per_mb,
+            ..deal_from(test_peer_info(), 1)
+        }
+    }
+
+    #[test]
+    /// medians are read from the newest bucket and trended across the window
+    fn median_and_trend() {
+        let mut history = PriceHistory::default();
+        history.record_advertised(0, vec![2.0, 3.0, 4.0]);
+        history.record_advertised(HOUR, vec![2.0, 2.0, 9.0]);
+        history.record_advertised(2 * HOUR + 5, vec![1.0, 1.5, 2.0]);
+        history.record_deal(2 * HOUR + 10, &deal(1.25), true);
+
+        assert_eq!(history.median_price(), Some(1.5));
+        assert_eq!(history.trend(2 * HOUR + 10, RETENTION), Some(-0.5));
+        assert_eq!(history.trend(2 * HOUR + 10, BUCKET), Some(-0.25));
+        assert_eq!(history.trend(2 * HOUR + 10, Duration::from_secs(5)), None);
+        assert_eq!(history.buckets().last().unwrap().deal_prices, vec![1.25]);
+    }
+
+    #[test]
+    /// buckets older than the retention period are dropped
+    fn retention() {
+        let mut history = PriceHistory::default();
+        history.record_advertised(0, vec![1.0]);
+        history.record_advertised(RETENTION.as_secs() + HOUR, vec![2.0]);
+        assert_eq!(history.buckets().count(), 1);
+        assert_eq!(history.median_price(), Some(2.0));
+    }
+
+    #[test]
+    /// proposals are counted per bucket and summed over the window
+    fn demand_over_window() {
+        let mut history = PriceHistory::default();
+        history.record_deal(0, &deal(1.0), true);
+        history.record_deal(HOUR, &deal(1.0), false);
+        history.record_deal(HOUR + 5, &deal(2.0), true);
+
+        assert_eq!(
+            history.demand(HOUR + 10, RETENTION),
+            Demand {
+                proposals: 3,
+                requested_bytes: 3 * BYTES_PER_MEBIBYTE,
+                over_capacity: 1,
+            }
+        );
+        assert_eq!(
+            history.demand(HOUR + 10, Duration::from_secs(10)).proposals,
+            2
+        );
+    }
+
+    #[test]
+    /// absurd file lengths saturate the byte count instead of overflowing
+    fn demand_saturates() {
+        let mut history = PriceHistory::default();
+        let huge = Deal {
+            file_len: u64::MAX,
+            ..deal(1.0)
+        };
+        history.record_deal(0, &huge, false);
+        history.record_deal(0, &huge, false);
+        history.record_deal(HOUR, &huge, false);
+        assert_eq!(history.demand(HOUR, RETENTION).requested_bytes, u64::MAX);
+    }
+}
@@ -0,0 +1,289 @@
+use quinn::Connection;
+use serde::Serialize;
+use std::{
+    fmt,
+    net::SocketAddr,
+    ops::Add,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// Minimum time between log lines for one [`Rejection`] category; the
+/// rejections in between are counted into the next line.
+pub const REJECTION_LOG_INTERVAL: Duration = Duration::from_secs(30);
+/// Leading bytes of an offending datagram logged in sample mode.
+const SAMPLE_BYTES: usize = 64;
+
+/// Category of a counted error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum ErrorKind {
+    /// A datagram or stream payload failed to decode.
+    Deserialize,
+    /// A QUIC connection could not be established.
+    Handshake,
+    /// An incoming stream could not be accepted or read.
+    Stream,
+    /// An outgoing deal could not be delivered.
+    Send,
+    /// A UDP socket read or write failed.
+    Socket,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::Deserialize => "deserialize",
+            ErrorKind::Handshake => "handshake",
+            ErrorKind::Stream => "stream",
+            ErrorKind::Send => "send",
+            ErrorKind::Socket => "socket",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Lock-free error counters, one per [`ErrorKind`].
+#[derive(Debug, Default)]
+pub struct ErrorCounters {
+    deserialize: AtomicU64,
+    handshake: AtomicU64,
+    stream: AtomicU64,
+    send: AtomicU64,
+    socket: AtomicU64,
+}
+
+impl ErrorCounters {
+    pub fn record(&self, kind: ErrorKind) {
+        let counter = match kind {
+            ErrorKind::Deserialize => &self.deserialize,
+            ErrorKind::Handshake => &self.handshake,
+            ErrorKind::Stream => &self.stream,
+            ErrorKind::Send => &self.send,
+            ErrorKind::Socket => &self.socket,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ErrorCounts {
+        ErrorCounts {
+            deserialize: self.deserialize.load(Ordering::Relaxed),
+            handshake: self.handshake.load(Ordering::Relaxed),
+            stream: self.stream.load(Ordering::Relaxed),
+            send: self.send.load(Ordering::Relaxed),
+            socket: self.socket.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of [`ErrorCounters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ErrorCounts {
+    pub deserialize: u64,
+    pub handshake: u64,
+    pub stream: u64,
+    pub send: u64,
+    pub socket: u64,
+}
+
+impl ErrorCounts {
+    pub fn total(&self) -> u64 {
+        self.deserialize + self.handshake + self.stream + self.send + self.socket
+    }
+}
+
+impl Add for ErrorCounts {
+    type Output = ErrorCounts;
+
+    fn add(self, other: ErrorCounts) -> ErrorCounts {
+        ErrorCounts {
+            deserialize: self.deserialize + other.deserialize,
+            handshake: self.handshake + other.handshake,
+            stream: self.stream + other.stream,
+            send: self.send + other.send,
+            socket: self.socket + other.socket,
+        }
+    }
+}
+
+/// QUIC path statistics from the most recent connection to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TransportStats {
+    /// smoothed round-trip time, in milliseconds
+    pub rtt_ms: f64,
+    /// congestion window, in bytes
+    pub cwnd: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    /// current path MTU, in bytes
+    pub path_mtu: u16,
+}
+
+impl TransportStats {
+    pub fn from_connection(connection: &Connection) -> Self {
+        let path = connection.stats().path;
+        TransportStats {
+            rtt_ms: path.rtt.as_secs_f64() * 1000.0,
+            cwnd: path.cwnd,
+            sent_packets: path.sent_packets,
+            lost_packets: path.lost_packets,
+            path_mtu: path.current_mtu,
+        }
+    }
+}
+
+/// Outcomes of the deals sent to a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeliveryRecord {
+    pub delivered: u64,
+    pub failed: u64,
+}
+
+impl DeliveryRecord {
+    pub fn record(&mut self, delivered: bool) {
+        if delivered {
+            self.delivered += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+
+    /// Share of deals delivered, or `None` before the first attempt.
+    pub fn reliability(&self) -> Option<f64> {
+        let attempts = self.delivered + self.failed;
+        (attempts > 0).then(|| self.delivered as f64 / attempts as f64)
+    }
+}
+
+/// Why a datagram heard on the discovery socket was not taken as an
+/// announcement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Rejection {
+    /// No spare-net header, e.g. a real mDNS packet on the shared group.
+    Foreign,
+    /// Over the announcement size limit.
+    Oversized,
+    /// The payload did not decode as an announcement.
+    Malformed,
+    /// Decoded, but a field was invalid (e.g. the peer id).
+    Invalid,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Rejection::Foreign => "foreign",
+            Rejection::Oversized => "oversized",
+            Rejection::Malformed => "malformed",
+            Rejection::Invalid => "invalid",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Default)]
+struct RejectionCounter {
+    total: AtomicU64,
+    /// rejections since the last log line
+    unlogged: AtomicU64,
+    last_logged: Mutex<Option<Instant>>,
+}
+
+/// Counts rejected datagrams per [`Rejection`] and logs each category at
+/// most once per [`REJECTION_LOG_INTERVAL`], so a busy mDNS group can't
+/// flood the log. Foreign traffic is expected and logged at debug level,
+/// the rest as warnings. With samples on, each line carries the start of
+/// the datagram that triggered it, hex-encoded.
+#[derive(Debug, Default)]
+pub struct RejectionLog {
+    foreign: RejectionCounter,
+    oversized: RejectionCounter,
+    malformed: RejectionCounter,
+    invalid: RejectionCounter,
+    samples: AtomicBool,
+}
+
+impl RejectionLog {
+    pub fn set_samples(&self, on: bool) {
+        self.samples.store(on, Ordering::Relaxed);
+    }
+
+    pub fn record(
+        &self,
+        reason: Rejection,
+        src: SocketAddr,
+        datagram: &[u8],
+        detail: impl fmt::Display,
+    ) {
+        let counter = match reason {
+            Rejection::Foreign => &self.foreign,
+            Rejection::Oversized => &self.oversized,
+            Rejection::Malformed => &self.malformed,
+            Rejection::Invalid => &self.invalid,
+        };
+        counter.total.fetch_add(1, Ordering::Relaxed);
+        counter.unlogged.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut last = counter.last_logged.lock().expect("rejection lock poisoned");
+            let now = Instant::now();
+            if last.is_some_and(|at| now.duration_since(at) < REJECTION_LOG_INTERVAL) {
+                return;
+            }
+            *last = Some(now);
+        }
+        let count = counter.unlogged.swap(0, Ordering::Relaxed);
+        let sample = if self.samples.load(Ordering::Relaxed) {
+            let hex: String = datagram
+                .iter()
+                .take(SAMPLE_BYTES)
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            format!(" ({} bytes: {hex})", datagram.len())
+        } else {
+            String::new()
+        };
+        match reason {
+            Rejection::Foreign => {
+                debug!("rejected {count} {reason} datagram(s), latest from {src}: {detail}{sample}")
+            }
+            _ => warn!(
+                "rejected {count} {reason} announcement(s), latest from {src}: {detail}{sample}"
+            ),
+        }
+    }
+
+    pub fn snapshot(&self) -> RejectionCounts {
+        let total = |counter: &RejectionCounter| counter.total.load(Ordering::Relaxed);
+        RejectionCounts {
+            foreign: total(&self.foreign),
+            oversized: total(&self.oversized),
+            malformed: total(&self.malformed),
+            invalid: total(&self.invalid),
+        }
+    }
+}
+
+/// Point-in-time copy of the [`RejectionLog`] counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RejectionCounts {
+    pub foreign: u64,
+    pub oversized: u64,
+    pub malformed: u64,
+    pub invalid: u64,
+}
+
+impl Add for RejectionCounts {
+    type Output = RejectionCounts;
+
+    fn add(self, other: RejectionCounts) -> RejectionCounts {
+        RejectionCounts {
+            foreign: self.foreign + other.foreign,
+            oversized: self.oversized + other.oversized,
+            malformed: self.malformed + other.malformed,
+            invalid: self.invalid + other.invalid,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Operator notifications, delivered through pluggable [`Notifier`]s (mail,
+//! chat webhooks, a shell command, ...).
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::plugin::{Event, EventSink};
+
+/// A short message for a human.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.title, self.body)
+    }
+}
+
+/// Delivers notifications. Called from the agent's tasks, so slow
+/// deliveries should be handed off rather than block.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification);
+}
+
+/// Notifies when a received deal offers at least `min_cost` for the whole
+/// file. Register it with `Agent::with_event_sink`. At most one
+/// notification goes out per `min_interval`; deals qualifying in between are
+/// counted and mentioned in the next one, so a burst of proposals doesn't
+/// turn into a burst of mail.
+pub struct DealAlerts<N> {
+    min_cost: f32,
+    min_interval: Duration,
+    notifier: N,
+    state: Mutex<AlertState>,
+}
+
+#[derive(Default)]
+struct AlertState {
+    last: Option<Instant>,
+    coalesced: usize,
+}
+
+impl<N: Notifier> DealAlerts<N> {
+    pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(min_cost: f32, notifier: N) -> Self {
+        Self {
+            min_cost,
+            min_interval: Self::DEFAULT_MIN_INTERVAL,
+            notifier,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    fn event_at(&self, event: &Event, now: Instant) {
+        let Event::DealReceived {
+            deal_id,
+            remote,
+            cost,
+        } = event
+        else {
+            return;
+        };
+        if *cost < self.min_cost {
+            return;
+        }
+        let mut state = self.state.lock().expect("alert state lock poisoned");
+        if state
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.min_interval)
+        {
+            state.coalesced += 1;
+            return;
+        }
+        let mut body = format!("received from {remote}, waiting for review");
+        if state.coalesced > 0 {
+            body += &format!(
+                "; {} more worth at least {} since the last alert",
+                state.coalesced, self.min_cost
+            );
+        }
+        state.last = Some(now);
+        state.coalesced = 0;
+        drop(state);
+        self.notifier.notify(&Notification {
+            title: format!("deal {deal_id} worth {cost}"),
+            body,
+        });
+    }
+}
+
+impl<N: Notifier> EventSink for DealAlerts<N> {
+    fn event(&self, event: &Event) {
+        self.event_at(event, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::deal::DealId;
+
+    #[derive(Clone, Default)]
+    struct Inbox(Arc<Mutex<Vec<Notification>>>);
+
+    impl Notifier for Inbox {
+        fn notify(&self, notification: &Notification) {
+            self.0.lock().unwrap().push(notification.clone());
+        }
+    }
+
+    fn received(cost: f32) -> Event {
+        Event::DealReceived {
+            deal_id: DealId(1),
+            remote: "10.0.0.5:4000".parse().unwrap(),
+            cost,
+        }
+    }
+
+    #[test]
+    /// only deals at or above the threshold notify
+    fn alerts_above_threshold() {
+        let inbox = Inbox::default();
+        let alerts = DealAlerts::new(10.0, inbox.clone()).with_min_interval(Duration::ZERO);
+        for cost in [9.5, 10.0, 25.0] {
+            alerts.event(&received(cost));
+        }
+        alerts.event(&Event::DealExpired { deal_id: DealId(1) });
+        let titles: Vec<_> = inbox
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|n| n.title.clone())
+            .collect();
+        assert_eq!(titles.len(), 2);
+        assert!(titles[1].ends_with("worth 25"));
+    }
+
+    #[test]
+    /// alerts within the interval are held back and counted in the next one
+    fn alerts_coalesce_within_interval() {
+        let inbox = Inbox::default();
+        let alerts = DealAlerts::new(10.0, inbox.clone());
+        let start = Instant::now();
+        for secs in [0, 1, 2, 59] {
+            alerts.event_at(&received(20.0), start + Duration::from_secs(secs));
+        }
+        alerts.event_at(&received(5.0), start + Duration::from_secs(60));
+        assert_eq!(inbox.0.lock().unwrap().len(), 1);
+
+        alerts.event_at(&received(30.0), start + Duration::from_secs(61));
+        let sent = inbox.0.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1]
+            .body
+            .ends_with("3 more worth at least 10 since the last alert"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Manual per-peer settings an operator pins on top of what the agent works
+//! out for itself: a note, a trust level, a deal size cap and a price.
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+};
+
+use crate::peer_info::PeerInfo;
+
+/// Trust an operator pins on a peer, ahead of delivery history and
+/// approval hooks.
+///
+/// Peer ids in received deals are not authenticated (they aren't bound to
+/// the connection's certificate), so a deal only gets the claimed peer's
+/// trust when it arrives from the IP discovery has for that peer, and a
+/// block also covers any other id sent from a blocked peer's IP. Anyone who
+/// can send from that IP can still claim the id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trust {
+    /// never sent deals, and deals from it are refused
+    Blocked,
+    /// deals from it (sent from its discovered IP) skip approval hooks, and
+    /// placement doesn't hold it to a minimum reliability
+    Trusted,
+}
+
+/// Settings for one peer; unset fields leave the agent's own judgement.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerOverride {
+    /// free-form operator note, e.g. who runs the peer
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub note: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trust: Option<Trust>,
+    /// largest deal share, in bytes, sent to or accepted from the peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_deal_bytes: Option<u64>,
+    /// price per MiB matched and costed for the peer in place of every
+    /// price it advertises (base, storage class and burst)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<f32>,
+}
+
+impl PeerOverride {
+    /// `peer` as matching should see it, with the price override applied
+    pub fn apply(&self, peer: &PeerInfo) -> PeerInfo {
+        let mut peer = peer.clone();
+        if let Some(price) = self.price {
+            peer.price = price;
+            peer.burst_price = price;
+            for offer in &mut peer.storage_offers {
+                offer.price = price;
+            }
+        }
+        peer
+    }
+
+    pub fn blocked(&self) -> bool {
+        self.trust == Some(Trust::Blocked)
+    }
+
+    pub fn trusted(&self) -> bool {
+        self.trust == Some(Trust::Trusted)
+    }
+}
+
+/// Overrides by peer. Serializes as a JSON object keyed by base58 peer id,
+/// so it can be kept in a file operators edit.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(
+    try_from = "BTreeMap<String, PeerOverride>",
+    into = "BTreeMap<String, PeerOverride>"
+)]
+pub struct PeerOverrides {
+    peers: HashMap<PeerId, PeerOverride>,
+}
+
+impl PeerOverrides {
+    /// read overrides saved with [`PeerOverrides::save`]; a missing file is
+    /// empty
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// write to `path` as pretty JSON, via a temporary file so a crash
+    /// never leaves it half-written
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&PeerOverride> {
+        self.peers.get(peer_id)
+    }
+
+    /// replace the override for `peer_id`; an empty one removes it
+    pub fn set(&mut self, peer_id: PeerId, settings: PeerOverride) {
+        if settings == PeerOverride::default() {
+            self.peers.remove(&peer_id);
+        } else {
+            self.peers.insert(peer_id, settings);
+        }
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<PeerOverride> {
+        self.peers.remove(peer_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerOverride)> {
+        self.peers.iter()
+    }
+}
+
+impl TryFrom<BTreeMap<String, PeerOverride>> for PeerOverrides {
+    type Error = libp2p::identity::ParseError;
+
+    fn try_from(peers: BTreeMap<String, PeerOverride>) -> Result<Self, Self::Error> {
+        let peers = peers
+            .into_iter()
+            .map(|(id, settings)| Ok((id.parse()?, settings)))
+            .collect::<Result<_, Self::Error>>()?;
+        Ok(Self { peers })
+    }
+}
+
+impl From<PeerOverrides> for BTreeMap<String, PeerOverride> {
+    fn from(overrides: PeerOverrides) -> Self {
+        overrides
+            .peers
+            .into_iter()
+            .map(|(id, settings)| (id.to_string(), settings))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// overrides survive a save and load, and empty settings are dropped
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("sparenet-overrides-{}", std::process::id()));
+        assert_eq!(
+            PeerOverrides::load(&path).unwrap(),
+            PeerOverrides::default()
+        );
+
+        let (trusted, cleared) = (PeerId::random(), PeerId::random());
+        let mut overrides = PeerOverrides::default();
+        overrides.set(
+            trusted,
+            PeerOverride {
+                note: "office NAS".into(),
+                trust: Some(Trust::Trusted),
+                max_deal_bytes: Some(1 << 30),
+                price: Some(0.5),
+            },
+        );
+        overrides.set(
+            cleared,
+            PeerOverride {
+                trust: Some(Trust::Blocked),
+                ..Default::default()
+            },
+        );
+        overrides.set(cleared, PeerOverride::default());
+        overrides.save(&path).unwrap();
+
+        let loaded = PeerOverrides::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, overrides);
+        assert!(loaded.get(&trusted).unwrap().trusted());
+        assert!(loaded.get(&cleared).is_none());
+    }
+}
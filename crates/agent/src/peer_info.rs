@@ -1,24 +1,325 @@
 use libp2p::PeerId;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
-use std::net::SocketAddr;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::BitOr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+use crate::{
+    deal::{Deal, BYTES_PER_GIBIBYTE},
+    plugin::MatchPolicy,
+};
+
+/// Upper bound on the summed length of metadata keys and values, in bytes.
+pub const MAX_METADATA_BYTES: usize = 512;
+/// Upper bound on the length of an announced region, in bytes.
+pub const MAX_REGION_BYTES: usize = 64;
+
+/// Why an announced `PeerInfoWire` was rejected.
+#[derive(Debug, Error)]
+pub enum PeerInfoError {
+    #[error("invalid peer id: {0}")]
+    PeerId(#[from] libp2p::identity::ParseError),
+    #[error("metadata is {size} bytes, limit is {MAX_METADATA_BYTES}")]
+    MetadataTooLarge { size: usize },
+    #[error("region is {size} bytes, limit is {MAX_REGION_BYTES}")]
+    RegionTooLong { size: usize },
+    #[error("{field} is {price}, must be finite and not negative")]
+    InvalidPrice { field: &'static str, price: f32 },
+}
+
+fn check_price(field: &'static str, price: f32) -> Result<(), PeerInfoError> {
+    if price.is_finite() && price >= 0.0 {
+        Ok(())
+    } else {
+        Err(PeerInfoError::InvalidPrice { field, price })
+    }
+}
+
+/// Summed length of every key and value in `metadata`.
+pub fn metadata_size(metadata: &BTreeMap<String, String>) -> usize {
+    metadata.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
+/// Protocol capabilities a peer announces, as a bitmap. Bits this build
+/// does not know are carried along and otherwise ignored, so newer peers can
+/// announce features older ones have never heard of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub struct Features(pub u64);
+
+impl Features {
+    pub const ENCRYPTION: Features = Features(1 << 0);
+    pub const ERASURE_CODING: Features = Features(1 << 1);
+    pub const RELAY: Features = Features(1 << 2);
+    pub const BANDWIDTH_DEALS: Features = Features(1 << 3);
+    pub const AUDIT_V2: Features = Features(1 << 4);
+    /// Features implemented by this build. Deals can ask for erasure coding
+    /// but nothing encodes shards yet, so none are announced.
+    pub const SUPPORTED: Features = Features(0);
+
+    /// Whether every feature in `other` is set.
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// Services a peer offers, as a bitmap; unlike [`Features`], which say what
+/// protocol a peer speaks, these say what it is willing to do for others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    /// takes storage deals
+    pub const STORAGE: Capabilities = Capabilities(1 << 0);
+    /// forwards traffic for peers that can't reach each other directly
+    pub const RELAY: Capabilities = Capabilities(1 << 1);
+    /// sells bandwidth on top of storage
+    pub const BANDWIDTH: Capabilities = Capabilities(1 << 2);
+
+    /// Whether every capability in `other` is set.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// Storage tier a provider can offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub enum StorageClass {
+    /// Fast media (e.g. SSD), low retrieval latency.
+    Hot,
+    /// Bulk media (e.g. HDD), cheaper but slower to retrieve.
+    Cold,
+}
+
+/// A storage class advertised by a provider with its own price and latency.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StorageOffer {
+    pub class: StorageClass,
+    pub price: f32,
+    /// Expected time to first byte on retrieval, in milliseconds.
+    pub retrieval_latency_ms: u32,
+}
 
 /// In-memory representation of a peer.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PeerInfo {
     pub addr: SocketAddr,
     pub peer_id: PeerId,
     pub spare_mbs: u64,
     pub price: f32,
+    /// Price charged per GiB served back to the consumer on retrieval.
+    pub egress_price_per_gib: f32,
+    /// Per-class offers; `price` applies when a deal requests no class.
+    pub storage_offers: Vec<StorageOffer>,
+    /// Reclaimable space on top of the committed `spare_mbs`, in MiB. Deals
+    /// placed here are cheaper but may be evicted with notice.
+    pub burst_mbs: u64,
+    /// Price per MiB for burst space.
+    pub burst_price: f32,
+    pub features: Features,
+    /// Free-form operator details (name, contact, hardware class), capped at
+    /// `MAX_METADATA_BYTES`.
+    pub metadata: BTreeMap<String, String>,
+    /// Operator-chosen location, e.g. `eu-west`; capped at
+    /// `MAX_REGION_BYTES`.
+    pub region: Option<String>,
+    /// When the peer came online, in seconds since the Unix epoch. Announced
+    /// as a start time rather than an uptime so repeated announcements stay
+    /// identical.
+    pub online_since: Option<u64>,
+    pub capabilities: Capabilities,
+}
+
+impl PeerInfo {
+    /// Price this peer asks for `class`, or `None` if the class is not offered.
+    pub fn price_for(&self, class: Option<StorageClass>) -> Option<f32> {
+        match class {
+            None => Some(self.price),
+            Some(class) => self
+                .storage_offers
+                .iter()
+                .find(|offer| offer.class == class)
+                .map(|offer| offer.price),
+        }
+    }
+
+    /// Capacity and price for a deal on committed (`burst == false`) or
+    /// burst space; the price is `None` if `class` is not offered. Burst
+    /// space is priced at `burst_price` whatever the class.
+    pub fn capacity_for(&self, class: Option<StorageClass>, burst: bool) -> (u64, Option<f32>) {
+        let price = self.price_for(class);
+        if burst {
+            (self.burst_mbs, price.map(|_| self.burst_price))
+        } else {
+            (self.spare_mbs, price)
+        }
+    }
+
+    /// Egress charge for serving `bytes` at this peer's advertised rate.
+    pub fn egress_cost(&self, bytes: u64) -> f32 {
+        (bytes as f64 / BYTES_PER_GIBIBYTE as f64) as f32 * self.egress_price_per_gib
+    }
+
+    /// Seconds the peer has been online as of `now` (seconds since the Unix
+    /// epoch), or `None` if it doesn't announce a start time. Relies on the
+    /// two clocks roughly agreeing.
+    pub fn uptime_secs(&self, now: u64) -> Option<u64> {
+        self.online_since.map(|since| now.saturating_sub(since))
+    }
 }
 
 /// Wire representation used for serialization.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct PeerInfoWire {
     pub addr: SocketAddr,
+    /// Multihash-encoded libp2p `PeerId`.
+    #[schemars(with = "Vec<u8>")]
     pub peer_id_bytes: ByteBuf,
     pub spare_mbs: u64,
     pub price: f32,
+    pub egress_price_per_gib: f32,
+    pub storage_offers: Vec<StorageOffer>,
+    pub burst_mbs: u64,
+    pub burst_price: f32,
+    pub features: Features,
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub online_since: Option<u64>,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+/// The fields released agents announce and send, in order. Announcements
+/// still start with exactly these, so released agents, which ignore
+/// trailing bytes, keep reading ours.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BaselinePeerInfoWire {
+    addr: SocketAddr,
+    peer_id_bytes: ByteBuf,
+    spare_mbs: u64,
+    price: f32,
+}
+
+impl From<BaselinePeerInfoWire> for PeerInfoWire {
+    fn from(w: BaselinePeerInfoWire) -> Self {
+        Self {
+            addr: w.addr,
+            peer_id_bytes: w.peer_id_bytes,
+            spare_mbs: w.spare_mbs,
+            price: w.price,
+            egress_price_per_gib: 0.0,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: BTreeMap::new(),
+            region: None,
+            online_since: None,
+            capabilities: Capabilities::default(),
+        }
+    }
+}
+
+impl From<&PeerInfoWire> for BaselinePeerInfoWire {
+    fn from(w: &PeerInfoWire) -> Self {
+        Self {
+            addr: w.addr,
+            peer_id_bytes: w.peer_id_bytes.clone(),
+            spare_mbs: w.spare_mbs,
+            price: w.price,
+        }
+    }
+}
+
+/// Version byte between the baseline fields of an announcement and the
+/// ones added since. Versions only ever append fields, so a payload of a
+/// newer version is read as the newest one known and the rest ignored.
+pub const ANNOUNCEMENT_VERSION: u8 = 1;
+
+/// Announced after the baseline fields, from version 1.
+#[derive(Serialize, Deserialize)]
+struct AnnouncementV1 {
+    egress_price_per_gib: f32,
+    storage_offers: Vec<StorageOffer>,
+    burst_mbs: u64,
+    burst_price: f32,
+    features: Features,
+    metadata: BTreeMap<String, String>,
+    region: Option<String>,
+    online_since: Option<u64>,
+    capabilities: Capabilities,
+}
+
+/// Encode `wire` as an announcement payload (after the header): the
+/// baseline fields, [`ANNOUNCEMENT_VERSION`], then the fields added since.
+pub fn encode_announcement(wire: &PeerInfoWire) -> Vec<u8> {
+    let mut payload = bincode::serialize(&BaselinePeerInfoWire::from(wire)).unwrap();
+    payload.push(ANNOUNCEMENT_VERSION);
+    let extension = AnnouncementV1 {
+        egress_price_per_gib: wire.egress_price_per_gib,
+        storage_offers: wire.storage_offers.clone(),
+        burst_mbs: wire.burst_mbs,
+        burst_price: wire.burst_price,
+        features: wire.features,
+        metadata: wire.metadata.clone(),
+        region: wire.region.clone(),
+        online_since: wire.online_since,
+        capabilities: wire.capabilities,
+    };
+    payload.extend_from_slice(&bincode::serialize(&extension).unwrap());
+    payload
+}
+
+/// Decode an announcement payload. One that ends after the baseline fields
+/// comes from a released agent and leaves the newer fields unset; otherwise
+/// the version byte says how to read the rest.
+pub fn decode_announcement(payload: &[u8]) -> bincode::Result<PeerInfoWire> {
+    let baseline: BaselinePeerInfoWire = bincode::deserialize(payload)?;
+    // fixed-width integers make the encoding length-exact
+    let used = bincode::serialized_size(&baseline)? as usize;
+    let mut wire = PeerInfoWire::from(baseline);
+    let Some((&version, rest)) = payload[used..].split_first() else {
+        return Ok(wire);
+    };
+    if version == 0 {
+        return Err(Box::new(bincode::ErrorKind::Custom(
+            "announcement version 0".into(),
+        )));
+    }
+    let extension: AnnouncementV1 = bincode::deserialize(rest)?;
+    wire.egress_price_per_gib = extension.egress_price_per_gib;
+    wire.storage_offers = extension.storage_offers;
+    wire.burst_mbs = extension.burst_mbs;
+    wire.burst_price = extension.burst_price;
+    wire.features = extension.features;
+    wire.metadata = extension.metadata;
+    wire.region = extension.region;
+    wire.online_since = extension.online_since;
+    wire.capabilities = extension.capabilities;
+    Ok(wire)
 }
 
 impl From<PeerInfo> for PeerInfoWire {
@@ -28,19 +329,294 @@ impl From<PeerInfo> for PeerInfoWire {
             peer_id_bytes: ByteBuf::from(pi.peer_id.to_bytes()),
             spare_mbs: pi.spare_mbs,
             price: pi.price,
+            egress_price_per_gib: pi.egress_price_per_gib,
+            storage_offers: pi.storage_offers,
+            burst_mbs: pi.burst_mbs,
+            burst_price: pi.burst_price,
+            features: pi.features,
+            metadata: pi.metadata,
+            region: pi.region,
+            online_since: pi.online_since,
+            capabilities: pi.capabilities,
         }
     }
 }
 
 impl TryFrom<PeerInfoWire> for PeerInfo {
-    type Error = libp2p::identity::ParseError;
+    type Error = PeerInfoError;
 
     fn try_from(w: PeerInfoWire) -> Result<Self, Self::Error> {
+        let size = metadata_size(&w.metadata);
+        if size > MAX_METADATA_BYTES {
+            return Err(PeerInfoError::MetadataTooLarge { size });
+        }
+        if let Some(size) = w.region.as_ref().map(String::len) {
+            if size > MAX_REGION_BYTES {
+                return Err(PeerInfoError::RegionTooLong { size });
+            }
+        }
+        check_price("price", w.price)?;
+        check_price("egress_price_per_gib", w.egress_price_per_gib)?;
+        check_price("burst_price", w.burst_price)?;
+        for offer in &w.storage_offers {
+            check_price("storage_offer.price", offer.price)?;
+        }
         Ok(Self {
             addr: w.addr,
             peer_id: PeerId::from_bytes(&w.peer_id_bytes)?,
             spare_mbs: w.spare_mbs,
             price: w.price,
+            egress_price_per_gib: w.egress_price_per_gib,
+            storage_offers: w.storage_offers,
+            burst_mbs: w.burst_mbs,
+            burst_price: w.burst_price,
+            features: w.features,
+            metadata: w.metadata,
+            region: w.region,
+            online_since: w.online_since,
+            capabilities: w.capabilities,
         })
     }
 }
+
+/// Built-in [`MatchPolicy`] that only lets deals go to peers announcing a
+/// given region, uptime or capabilities. Peers that don't announce a field
+/// fail any requirement on it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerRequirements {
+    /// regions accepted; empty accepts any, or none announced
+    pub regions: Vec<String>,
+    pub min_uptime_secs: Option<u64>,
+    pub capabilities: Capabilities,
+}
+
+impl PeerRequirements {
+    /// Why `peer` falls short as of `now` (seconds since the Unix epoch), or
+    /// `None` if it meets every requirement.
+    pub fn unmet(&self, peer: &PeerInfo, now: u64) -> Option<String> {
+        if !self.regions.is_empty() {
+            match &peer.region {
+                None => return Some("no region announced".into()),
+                Some(region) if !self.regions.contains(region) => {
+                    return Some(format!("region {region} is not accepted"))
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(min) = self.min_uptime_secs {
+            match peer.uptime_secs(now) {
+                None => return Some("no uptime announced".into()),
+                Some(uptime) if uptime < min => return Some(format!("up {uptime}s, needs {min}s")),
+                Some(_) => {}
+            }
+        }
+        if !peer.capabilities.contains(self.capabilities) {
+            let missing = self.capabilities.0 & !peer.capabilities.0;
+            return Some(format!("missing capabilities {missing:#x}"));
+        }
+        None
+    }
+}
+
+impl MatchPolicy for PeerRequirements {
+    fn reject(&self, peer: &PeerInfo, _deal: &Deal) -> Option<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.unmet(peer, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// deals without a class use the base price, classed deals need an offer
+    fn price_for_storage_class() {
+        let pi = PeerInfo {
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            peer_id: PeerId::random(),
+            spare_mbs: 10,
+            price: 5.0,
+            egress_price_per_gib: 1.0,
+            storage_offers: vec![StorageOffer {
+                class: StorageClass::Cold,
+                price: 2.0,
+                retrieval_latency_ms: 30_000,
+            }],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: BTreeMap::new(),
+            region: None,
+            online_since: None,
+            capabilities: Capabilities::default(),
+        };
+        assert_eq!(pi.price_for(None), Some(5.0));
+        assert_eq!(pi.price_for(Some(StorageClass::Cold)), Some(2.0));
+        assert_eq!(pi.price_for(Some(StorageClass::Hot)), None);
+    }
+
+    #[test]
+    /// announcements with oversized metadata are rejected
+    fn metadata_size_cap() {
+        let mut wire = PeerInfoWire::from(PeerInfo {
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            peer_id: PeerId::random(),
+            spare_mbs: 10,
+            price: 1.0,
+            egress_price_per_gib: 1.0,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: BTreeMap::from([("operator".into(), "alice".into())]),
+            region: None,
+            online_since: None,
+            capabilities: Capabilities::default(),
+        });
+        assert!(PeerInfo::try_from(wire.clone()).is_ok());
+
+        wire.metadata
+            .insert("contact".into(), "x".repeat(MAX_METADATA_BYTES));
+        assert!(matches!(
+            PeerInfo::try_from(wire),
+            Err(PeerInfoError::MetadataTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    /// announcements asking a NaN, infinite or negative price are rejected
+    fn invalid_prices_rejected() {
+        let wire = PeerInfoWire::from(crate::testing::test_peer_info());
+        for price in [f32::NAN, f32::INFINITY, -1.0] {
+            let mut bad = wire.clone();
+            bad.price = price;
+            assert!(matches!(
+                PeerInfo::try_from(bad),
+                Err(PeerInfoError::InvalidPrice { field: "price", .. })
+            ));
+        }
+        let mut bad = wire.clone();
+        bad.storage_offers = vec![StorageOffer {
+            class: StorageClass::Cold,
+            price: -0.5,
+            retrieval_latency_ms: 0,
+        }];
+        assert!(matches!(
+            PeerInfo::try_from(bad),
+            Err(PeerInfoError::InvalidPrice {
+                field: "storage_offer.price",
+                ..
+            })
+        ));
+        assert!(PeerInfo::try_from(wire).is_ok());
+    }
+
+    #[test]
+    /// unknown feature bits survive decoding and don't affect known checks
+    fn unknown_feature_bits_ignored() {
+        let future = Features(1 << 40) | Features::ERASURE_CODING;
+        assert!(future.contains(Features::ERASURE_CODING));
+        assert!(!future.contains(Features::RELAY));
+        let decoded: Features =
+            bincode::deserialize(&bincode::serialize(&future).unwrap()).unwrap();
+        assert_eq!(decoded, future);
+    }
+
+    #[test]
+    /// announcements round-trip; a released agent's payload, which ends
+    /// after the baseline fields, decodes with the newer ones unset; and a
+    /// released agent still reads the baseline fields of ours
+    fn baseline_announcement_compatible() {
+        let wire = PeerInfoWire::from(PeerInfo {
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            peer_id: PeerId::random(),
+            spare_mbs: 10,
+            price: 1.0,
+            egress_price_per_gib: 1.0,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: BTreeMap::new(),
+            region: Some("eu-west".into()),
+            online_since: Some(1_700_000_000),
+            capabilities: Capabilities::STORAGE,
+        });
+        let bytes = encode_announcement(&wire);
+        assert_eq!(decode_announcement(&bytes).unwrap(), wire);
+
+        let baseline = bincode::serialize(&BaselinePeerInfoWire::from(&wire)).unwrap();
+        let decoded = decode_announcement(&baseline).unwrap();
+        assert_eq!(
+            decoded,
+            PeerInfoWire::from(BaselinePeerInfoWire::from(&wire))
+        );
+        assert_eq!(decoded.spare_mbs, 10);
+        assert_eq!(decoded.region, None);
+
+        let heard: BaselinePeerInfoWire = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(PeerInfoWire::from(heard), decoded);
+
+        // a version this build doesn't know yet only appends to v1
+        let mut newer = bytes.clone();
+        newer[baseline.len()] = ANNOUNCEMENT_VERSION + 1;
+        newer.extend_from_slice(b"future");
+        assert_eq!(decode_announcement(&newer).unwrap(), wire);
+
+        let mut unversioned = bytes.clone();
+        unversioned[baseline.len()] = 0;
+        assert!(decode_announcement(&unversioned).is_err());
+        assert!(decode_announcement(&bytes[..baseline.len() + 5]).is_err());
+        assert!(decode_announcement(&bytes[..20]).is_err());
+    }
+
+    #[test]
+    /// requirements reject peers in other regions, up too briefly, or
+    /// lacking a capability, and peers that don't announce the field
+    fn peer_requirements() {
+        let peer = PeerInfo {
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            peer_id: PeerId::random(),
+            spare_mbs: 10,
+            price: 1.0,
+            egress_price_per_gib: 1.0,
+            storage_offers: vec![],
+            burst_mbs: 0,
+            burst_price: 0.0,
+            features: Features::default(),
+            metadata: BTreeMap::new(),
+            region: Some("eu-west".into()),
+            online_since: Some(1_000),
+            capabilities: Capabilities::STORAGE | Capabilities::RELAY,
+        };
+        let reqs = PeerRequirements {
+            regions: vec!["eu-west".into(), "eu-north".into()],
+            min_uptime_secs: Some(3_600),
+            capabilities: Capabilities::STORAGE,
+        };
+        assert_eq!(reqs.unmet(&peer, 10_000), None);
+        assert!(reqs.unmet(&peer, 2_000).is_some());
+
+        let elsewhere = PeerInfo {
+            region: Some("us-east".into()),
+            ..peer.clone()
+        };
+        assert!(reqs.unmet(&elsewhere, 10_000).is_some());
+        let unannounced = PeerInfo {
+            region: None,
+            online_since: None,
+            ..peer.clone()
+        };
+        assert!(reqs.unmet(&unannounced, 10_000).is_some());
+
+        let needs_bandwidth = PeerRequirements {
+            capabilities: Capabilities::BANDWIDTH,
+            ..PeerRequirements::default()
+        };
+        assert!(needs_bandwidth.unmet(&peer, 10_000).is_some());
+        assert_eq!(PeerRequirements::default().unmet(&unannounced, 0), None);
+    }
+}
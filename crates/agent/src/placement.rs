@@ -0,0 +1,379 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use thiserror::Error;
+
+use crate::{
+    deal::{Deal, Redundancy, BYTES_PER_MEBIBYTE},
+    peer_info::{Features, PeerInfo},
+};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PlacementError {
+    #[error("redundancy needs {needed} providers but only {available} match")]
+    NotEnoughPeers { needed: usize, available: usize },
+    #[error("cheapest placement costs {cost} but the budget is {budget}")]
+    OverBudget { cost: f32, budget: f32 },
+    /// e.g. an erasure-coded deal, which no build can encode yet
+    #[error("deal needs features {:#x} this build doesn't implement", missing.0)]
+    Unsupported { missing: Features },
+}
+
+/// Refuse a deal needing features this build can't carry out before any
+/// provider is looked for, rather than letting it fail to match.
+fn check_supported(deal: &Deal) -> Result<(), PlacementError> {
+    let missing = Features(deal.required_features().0 & !Features::SUPPORTED.0);
+    if missing == Features::default() {
+        Ok(())
+    } else {
+        Err(PlacementError::Unsupported { missing })
+    }
+}
+
+/// Where each piece of a deal goes. `assignments[i]` holds shard `i` for
+/// erasure-coded deals and a full replica otherwise; restore needs the
+/// scheme and this order.
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub scheme: Option<Redundancy>,
+    pub assignments: Vec<(PeerInfo, Deal)>,
+}
+
+/// The deal a single provider is asked to take: for erasure coding the file
+/// length shrinks to one shard, otherwise it is the deal itself.
+pub fn unit_deal(deal: &Deal) -> Deal {
+    let mut unit = deal.clone();
+    if let Some(scheme) = deal.redundancy {
+        unit.file_len = scheme.bytes_per_provider(deal.file_len);
+    }
+    unit
+}
+
+/// Assign `deal` to providers from `candidates`, which must already match
+/// [`unit_deal`] and be ordered by preference. Without a redundancy scheme
+/// every candidate is offered the deal.
+pub fn plan(deal: &Deal, candidates: Vec<PeerInfo>) -> Result<Placement, PlacementError> {
+    check_supported(deal)?;
+    let unit = unit_deal(deal);
+    let needed = match deal.redundancy {
+        None => candidates.len(),
+        Some(scheme) => scheme.providers(),
+    };
+    if candidates.len() < needed {
+        return Err(PlacementError::NotEnoughPeers {
+            needed,
+            available: candidates.len(),
+        });
+    }
+    Ok(Placement {
+        scheme: deal.redundancy,
+        assignments: candidates
+            .into_iter()
+            .take(needed)
+            .map(|peer| (peer, unit.clone()))
+            .collect(),
+    })
+}
+
+/// Limits on an optimized placement, see [`optimize`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Constraints {
+    /// most the whole placement may cost at the providers' asking prices
+    pub budget: Option<f32>,
+    /// most providers to use from one network, a /24 for IPv4 or a /48 for
+    /// IPv6, so a single site going down loses few pieces
+    pub max_per_network: Option<usize>,
+    /// skip providers whose observed delivery rate is below this; providers
+    /// never sent to are not held to it
+    pub min_reliability: Option<f64>,
+}
+
+/// A placement with what it costs, to preview before sending.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub placement: Placement,
+    /// asking price of each assignment, in the same order
+    pub costs: Vec<f32>,
+    pub total_cost: f32,
+}
+
+/// What `peer` asks for storing `unit`, or `None` if it has no offer for it.
+fn asking_cost(peer: &PeerInfo, unit: &Deal) -> Option<f32> {
+    let (_, price) = peer.capacity_for(unit.storage_class, unit.burst);
+    price.map(|price| (unit.file_len as f64 / BYTES_PER_MEBIBYTE as f64) as f32 * price)
+}
+
+/// `placement` with what each assignment costs at its provider's asking
+/// price.
+pub fn priced(placement: Placement) -> Plan {
+    let costs: Vec<f32> = placement
+        .assignments
+        .iter()
+        .map(|(peer, unit)| asking_cost(peer, unit).unwrap_or_default())
+        .collect();
+    Plan {
+        total_cost: costs.iter().sum(),
+        placement,
+        costs,
+    }
+}
+
+/// Network a provider shares failure risk with, for
+/// [`Constraints::max_per_network`].
+fn network(peer: &PeerInfo) -> IpAddr {
+    match peer.addr.ip() {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & 0xffff_ff00).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !0u128 << 80).into()),
+    }
+}
+
+/// Cheapest placement of `deal` on `candidates`, which must already match
+/// [`unit_deal`], each paired with its observed delivery rate if known.
+/// Without a redundancy scheme a single provider is chosen. Providers are
+/// taken cheapest first, skipping any that would exceed
+/// [`Constraints::max_per_network`]; since every piece costs the same to
+/// place this is the cheapest placement that meets the constraints.
+pub fn optimize(
+    deal: &Deal,
+    candidates: Vec<(PeerInfo, Option<f64>)>,
+    constraints: &Constraints,
+) -> Result<Plan, PlacementError> {
+    check_supported(deal)?;
+    let unit = unit_deal(deal);
+    let needed = deal.redundancy.map_or(1, |scheme| scheme.providers());
+    let mut priced: Vec<_> = candidates
+        .into_iter()
+        .filter(
+            |(_, reliability)| match (constraints.min_reliability, reliability) {
+                (Some(min), Some(reliability)) => *reliability >= min,
+                _ => true,
+            },
+        )
+        .filter_map(|(peer, _)| asking_cost(&peer, &unit).map(|cost| (peer, cost)))
+        // a NaN cost would slip past the budget, a negative one pay for itself
+        .filter(|(_, cost)| cost.is_finite() && *cost >= 0.0)
+        .collect();
+    // stable, so equally priced providers keep the caller's preference order
+    priced.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let mut per_network: HashMap<IpAddr, usize> = HashMap::new();
+    let mut chosen = Vec::with_capacity(needed);
+    for (peer, cost) in priced {
+        if chosen.len() == needed {
+            break;
+        }
+        let used = per_network.entry(network(&peer)).or_default();
+        if constraints.max_per_network.is_some_and(|max| *used >= max) {
+            continue;
+        }
+        *used += 1;
+        chosen.push((peer, cost));
+    }
+    if chosen.len() < needed {
+        return Err(PlacementError::NotEnoughPeers {
+            needed,
+            available: chosen.len(),
+        });
+    }
+
+    let total_cost = chosen.iter().map(|(_, cost)| cost).sum();
+    if let Some(budget) = constraints.budget {
+        if total_cost > budget {
+            return Err(PlacementError::OverBudget {
+                cost: total_cost,
+                budget,
+            });
+        }
+    }
+    let (assignments, costs) = chosen
+        .into_iter()
+        .map(|(peer, cost)| ((peer, unit.clone()), cost))
+        .unzip();
+    Ok(Plan {
+        placement: Placement {
+            scheme: deal.redundancy,
+            assignments,
+        },
+        costs,
+        total_cost,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deal::BYTES_PER_MEBIBYTE,
+        testing::{deal_from, test_peer_info},
+    };
+
+    fn deal(redundancy: Option<Redundancy>) -> Deal {
+        Deal {
+            redundancy,
+            ..deal_from(test_peer_info(), 10)
+        }
+    }
+
+    #[test]
+    /// replicas go to the first `n` candidates at full size
+    fn replicas_take_preferred_peers() {
+        let peers: Vec<_> = (0..4).map(|_| test_peer_info()).collect();
+        let placement = plan(&deal(Some(Redundancy::Replicas(2))), peers.clone()).unwrap();
+        assert_eq!(placement.assignments.len(), 2);
+        assert_eq!(placement.assignments[0].0, peers[0]);
+        assert_eq!(placement.assignments[1].0, peers[1]);
+        assert!(placement
+            .assignments
+            .iter()
+            .all(|(_, d)| d.file_len == 10 * BYTES_PER_MEBIBYTE));
+        let plan = priced(placement);
+        assert_eq!(plan.costs, [10.0; 2]);
+        assert_eq!(plan.total_cost, 20.0);
+    }
+
+    #[test]
+    /// an erasure-coded deal would put `file_len / k` bytes on each
+    /// provider, but no build encodes shards yet, so it is refused up front
+    /// even when providers claim support
+    fn erasure_coding_refused() {
+        let peers: Vec<_> = (0..3)
+            .map(|_| PeerInfo {
+                features: Features::ERASURE_CODING,
+                ..test_peer_info()
+            })
+            .collect();
+        let d = deal(Some(Redundancy::ErasureCoded { k: 2, n: 3 }));
+        assert_eq!(unit_deal(&d).file_len, 5 * BYTES_PER_MEBIBYTE);
+        let refused = PlacementError::Unsupported {
+            missing: Features::ERASURE_CODING,
+        };
+        assert_eq!(plan(&d, peers.clone()).unwrap_err(), refused);
+        let candidates = peers.into_iter().map(|peer| (peer, None)).collect();
+        assert_eq!(
+            optimize(&d, candidates, &Constraints::default()).unwrap_err(),
+            refused
+        );
+    }
+
+    #[test]
+    fn not_enough_peers() {
+        let peers = vec![test_peer_info()];
+        assert_eq!(
+            plan(&deal(Some(Redundancy::Replicas(3))), peers).unwrap_err(),
+            PlacementError::NotEnoughPeers {
+                needed: 3,
+                available: 1
+            }
+        );
+        assert_eq!(plan(&deal(None), vec![]).unwrap().assignments.len(), 0);
+    }
+
+    fn provider(addr: &str, price: f32) -> PeerInfo {
+        PeerInfo {
+            addr: addr.parse().unwrap(),
+            price,
+            ..test_peer_info()
+        }
+    }
+
+    #[test]
+    /// the cheapest providers win, at most `max_per_network` from one /24,
+    /// and flaky providers are skipped
+    fn optimize_cheapest_within_constraints() {
+        let candidates = vec![
+            (provider("10.0.0.1:1", 3.0), None),
+            (provider("10.0.0.2:1", 1.0), Some(1.0)),
+            (provider("10.0.0.3:1", 1.5), None),
+            (provider("10.0.1.1:1", 2.0), Some(0.2)),
+            (provider("10.0.2.1:1", 2.5), None),
+        ];
+        let constraints = Constraints {
+            budget: Some(100.0),
+            max_per_network: Some(1),
+            min_reliability: Some(0.9),
+        };
+        let plan = optimize(
+            &deal(Some(Redundancy::Replicas(2))),
+            candidates.clone(),
+            &constraints,
+        )
+        .unwrap();
+        let addrs: Vec<_> = plan
+            .placement
+            .assignments
+            .iter()
+            .map(|(peer, _)| peer.addr.to_string())
+            .collect();
+        assert_eq!(addrs, ["10.0.0.2:1", "10.0.2.1:1"]);
+        assert_eq!(plan.costs, [10.0, 25.0]);
+        assert_eq!(plan.total_cost, 35.0);
+
+        // a third network is needed for three replicas
+        assert_eq!(
+            optimize(
+                &deal(Some(Redundancy::Replicas(3))),
+                candidates.clone(),
+                &constraints,
+            )
+            .unwrap_err(),
+            PlacementError::NotEnoughPeers {
+                needed: 3,
+                available: 2
+            }
+        );
+        let tight = Constraints {
+            budget: Some(20.0),
+            ..constraints
+        };
+        assert_eq!(
+            optimize(
+                &deal(Some(Redundancy::Replicas(2))),
+                candidates.clone(),
+                &tight
+            )
+            .unwrap_err(),
+            PlacementError::OverBudget {
+                cost: 35.0,
+                budget: 20.0
+            }
+        );
+        // without redundancy the single cheapest provider is used
+        let plan = optimize(&deal(None), candidates, &Constraints::default()).unwrap();
+        assert_eq!(
+            plan.placement.assignments[0].0.addr.to_string(),
+            "10.0.0.2:1"
+        );
+        assert_eq!(plan.total_cost, 10.0);
+    }
+
+    #[test]
+    /// providers asking a NaN, infinite or negative price are never chosen
+    fn optimize_skips_invalid_prices() {
+        let candidates = vec![
+            (provider("10.0.0.1:1", f32::NAN), None),
+            (provider("10.0.1.1:1", -5.0), None),
+            (provider("10.0.2.1:1", f32::INFINITY), None),
+            (provider("10.0.3.1:1", 2.0), None),
+        ];
+        let constraints = Constraints {
+            budget: Some(100.0),
+            ..Constraints::default()
+        };
+        let plan = optimize(&deal(None), candidates.clone(), &constraints).unwrap();
+        assert_eq!(
+            plan.placement.assignments[0].0.addr.to_string(),
+            "10.0.3.1:1"
+        );
+        assert_eq!(
+            optimize(
+                &deal(Some(Redundancy::Replicas(2))),
+                candidates,
+                &constraints
+            )
+            .unwrap_err(),
+            PlacementError::NotEnoughPeers {
+                needed: 2,
+                available: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,67 @@
+//! Extension points for integrators. Implementations are registered on the
+//! agent at build time (`Agent::with_match_policy`, `with_approval_hook`,
+//! `with_event_sink`, and `with_pricing` for a
+//! [`PricingStrategy`](crate::pricing::PricingStrategy)).
+
+use libp2p::PeerId;
+use std::net::SocketAddr;
+
+use crate::{
+    deal::{Deal, DealId},
+    peer_info::PeerInfo,
+};
+
+pub use crate::pricing::PricingStrategy;
+
+/// Extra conditions a provider must meet, checked after the built-in ones
+/// when matching outgoing deals.
+pub trait MatchPolicy: Send + Sync {
+    /// Why `peer` shouldn't take `deal`, or `None` if it may.
+    fn reject(&self, peer: &PeerInfo, deal: &Deal) -> Option<String>;
+}
+
+/// Decides whether a received deal is kept as a proposal.
+pub trait ApprovalHook: Send + Sync {
+    /// `false` drops `deal`, received from `remote`.
+    fn approve(&self, deal: &Deal, remote: SocketAddr) -> bool;
+}
+
+/// Something that happened in the agent, passed to every [`EventSink`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// a received deal was approved and stored; `cost` is what it offers
+    /// for the whole file
+    DealReceived {
+        deal_id: DealId,
+        remote: SocketAddr,
+        cost: f32,
+    },
+    /// a received deal was refused by an [`ApprovalHook`]
+    DealRejected {
+        deal_id: DealId,
+        remote: SocketAddr,
+    },
+    /// a stored proposal outlived the proposal TTL
+    DealExpired {
+        deal_id: DealId,
+    },
+    DealSent {
+        deal_id: DealId,
+        peer_id: PeerId,
+    },
+    SendFailed {
+        deal_id: DealId,
+        peer_id: PeerId,
+    },
+    /// the pricing strategy changed our advertised price per MiB
+    Repriced {
+        from: f32,
+        to: f32,
+    },
+}
+
+/// Receives agent [`Event`]s, e.g. to export them. Called inline, so
+/// implementations should hand slow work off rather than block.
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &Event);
+}
@@ -0,0 +1,50 @@
+//! Strategies that reprice a provider from what competitors advertise.
+
+use crate::explorer::PricePercentiles;
+
+/// Picks the base price per MiB a provider advertises. Run periodically by
+/// the agent (see `Agent::with_pricing`).
+pub trait PricingStrategy: Send + Sync {
+    /// The price to advertise next, given our `current` price and the base
+    /// prices competing providers advertise.
+    fn price(&self, current: f32, competitors: &[f32]) -> f32;
+}
+
+/// Advertise `percent` below the competitors' median price, kept within
+/// `floor..=ceiling`. Without competitors the current price is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Undercut {
+    pub percent: f32,
+    pub floor: f32,
+    pub ceiling: f32,
+}
+
+impl PricingStrategy for Undercut {
+    fn price(&self, current: f32, competitors: &[f32]) -> f32 {
+        let Some(market) = PricePercentiles::of(competitors.to_vec()) else {
+            return current;
+        };
+        (market.p50 * (1.0 - self.percent / 100.0))
+            .max(self.floor)
+            .min(self.ceiling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// undercuts the median, but never past the floor or ceiling
+    fn undercut_within_bounds() {
+        let undercut = Undercut {
+            percent: 10.0,
+            floor: 1.0,
+            ceiling: 5.0,
+        };
+        assert_eq!(undercut.price(3.0, &[2.0, 4.0, 9.0]), 3.6);
+        assert_eq!(undercut.price(3.0, &[0.5, 1.0]), 1.0);
+        assert_eq!(undercut.price(3.0, &[10.0]), 5.0);
+        assert_eq!(undercut.price(3.0, &[]), 3.0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Record control-plane traffic (announcements and deals, never file data)
+//! to a JSON-lines file, and read recordings back for
+//! [`Agent::replay`](crate::agent::Agent::replay).
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+use tracing::warn;
+
+use crate::deal::Deal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Message {
+    /// discovery datagram payload, after the magic header
+    Announcement {
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+    },
+    /// a decoded deal, as sent or as received
+    Deal { deal: Box<Deal> },
+}
+
+/// One recorded message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// unix time, in milliseconds
+    pub at_ms: u64,
+    pub direction: Direction,
+    /// where an inbound message came from, or an outbound one went
+    pub peer: SocketAddr,
+    pub message: Message,
+}
+
+/// Appends a [`Record`] per control message to a file, one JSON object per
+/// line. Write failures are logged and otherwise ignored so recording never
+/// disturbs the agent.
+#[derive(Debug)]
+pub struct TrafficRecorder {
+    out: Mutex<LineWriter<File>>,
+}
+
+impl TrafficRecorder {
+    /// Record to `path`, appending if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(TrafficRecorder {
+            out: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, peer: SocketAddr, message: Message) {
+        let at_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let record = Record {
+            at_ms,
+            direction,
+            peer,
+            message,
+        };
+        let mut line = serde_json::to_vec(&record).expect("records serialize");
+        line.push(b'\n');
+        let mut out = self.out.lock().expect("recorder lock poisoned");
+        if let Err(e) = out.write_all(&line) {
+            warn!("failed to record traffic: {e}");
+        }
+    }
+}
+
+/// A recording read back by [`read_recording`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub records: Vec<Record>,
+    /// lines that didn't parse, such as one cut short by a crash, skipped
+    pub unreadable: usize,
+}
+
+/// Read every record in a recording made by [`TrafficRecorder`]. Lines that
+/// don't parse are logged, counted and skipped, so one bad record doesn't
+/// cost the rest of the file.
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Recording> {
+    let mut recording = Recording::default();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => recording.records.push(record),
+            Err(e) => {
+                warn!("skipping unreadable record on line {}: {e}", i + 1);
+                recording.unreadable += 1;
+            }
+        }
+    }
+    Ok(recording)
+}
+
+/// What [`Agent::replay`](crate::agent::Agent::replay) fed back in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ReplayStats {
+    pub announcements: usize,
+    pub deals: usize,
+    /// outbound records, which have no handler to feed
+    pub skipped: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{deal_from, test_peer_info};
+
+    #[test]
+    /// records written by the recorder read back unchanged, in order
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("sparenet-rec-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let deal = deal_from(test_peer_info(), 1);
+        let peer: SocketAddr = "10.0.0.5:5334".parse().unwrap();
+        let recorder = TrafficRecorder::create(&path).unwrap();
+        recorder.record(
+            Direction::In,
+            peer,
+            Message::Announcement {
+                payload: vec![1, 2, 3],
+            },
+        );
+        recorder.record(
+            Direction::Out,
+            peer,
+            Message::Deal {
+                deal: Box::new(deal.clone()),
+            },
+        );
+        drop(recorder);
+
+        let records = read_recording(&path).unwrap().records;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::In);
+        assert_eq!(
+            records[0].message,
+            Message::Announcement {
+                payload: vec![1, 2, 3]
+            }
+        );
+        assert_eq!(
+            records[1].message,
+            Message::Deal {
+                deal: Box::new(deal)
+            }
+        );
+        assert!(records[0].at_ms <= records[1].at_ms);
+    }
+
+    #[test]
+    /// a bad line is skipped and counted, and the records around it survive
+    fn unreadable_lines_are_skipped() {
+        let path = std::env::temp_dir().join(format!("sparenet-bad-rec-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let peer: SocketAddr = "10.0.0.5:5334".parse().unwrap();
+        let recorder = TrafficRecorder::create(&path).unwrap();
+        recorder.record(
+            Direction::In,
+            peer,
+            Message::Announcement { payload: vec![1] },
+        );
+        drop(recorder);
+        // a deal whose price was NaN was written with a null price
+        let mut deal = serde_json::to_value(deal_from(test_peer_info(), 1)).unwrap();
+        deal["price_per_mb"] = serde_json::Value::Null;
+        let line = serde_json::json!({
+            "at_ms": 0,
+            "direction": "in",
+            "peer": peer,
+            "message": { "kind": "deal", "deal": deal },
+        });
+        let mut file = File::options().append(true).open(&path).unwrap();
+        writeln!(file, "{line}\n{{\"at_ms\":").unwrap();
+        drop(file);
+        let recorder = TrafficRecorder::create(&path).unwrap();
+        recorder.record(
+            Direction::In,
+            peer,
+            Message::Announcement { payload: vec![2] },
+        );
+        drop(recorder);
+
+        let recording = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recording.unreadable, 2);
+        assert_eq!(recording.records.len(), 2);
+        assert_eq!(
+            recording.records[1].message,
+            Message::Announcement { payload: vec![2] }
+        );
+    }
+}
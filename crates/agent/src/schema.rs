@@ -0,0 +1,52 @@
+use schemars::{schema::RootSchema, schema_for};
+use std::collections::BTreeMap;
+
+use crate::{
+    deal::{Deal, Priority, Redundancy},
+    peer_info::{Capabilities, Features, PeerInfoWire, StorageClass, StorageOffer},
+};
+
+/// JSON Schema for every type sent over the wire, keyed by type name.
+///
+/// Properties are listed in declaration order, which is also the order
+/// `bincode` encodes fields in, so the schema doubles as a layout reference
+/// for non-Rust implementations.
+pub fn wire_schemas() -> BTreeMap<&'static str, RootSchema> {
+    BTreeMap::from([
+        ("PeerInfoWire", schema_for!(PeerInfoWire)),
+        ("Features", schema_for!(Features)),
+        ("Capabilities", schema_for!(Capabilities)),
+        ("StorageClass", schema_for!(StorageClass)),
+        ("StorageOffer", schema_for!(StorageOffer)),
+        ("Redundancy", schema_for!(Redundancy)),
+        ("Priority", schema_for!(Priority)),
+        ("Deal", schema_for!(Deal)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// deal schema lists its fields in encoding order
+    fn deal_schema_field_order() {
+        let schemas = wire_schemas();
+        let deal = schemas["Deal"].schema.object.as_ref().unwrap();
+        let fields: Vec<_> = deal.properties.keys().map(String::as_str).collect();
+        assert_eq!(
+            fields,
+            [
+                "peer_info_wire",
+                "file_len",
+                "price_per_mb",
+                "storage_class",
+                "deadline_secs",
+                "redundancy",
+                "labels",
+                "burst",
+                "priority"
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Canonical encodings of the wire types.
+//!
+//! Each vector pairs a value with the exact `bincode` bytes this crate
+//! produces for it. The crate's own tests check both directions, so a change
+//! that alters the wire format fails here first; other implementations can
+//! validate their codecs against the same vectors (see `spare-net test-vectors`).
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+use crate::{
+    deal::{Deal, Priority, Redundancy, BYTES_PER_MEBIBYTE},
+    peer_info::{Capabilities, Features, PeerInfoWire, StorageClass, StorageOffer},
+};
+
+/// A named value and its canonical hex-encoded `bincode` bytes.
+#[derive(Debug, Clone, Serialize)]
+pub struct TestVector<T> {
+    pub name: &'static str,
+    pub value: T,
+    pub hex: &'static str,
+}
+
+impl<T> TestVector<T> {
+    /// Decoded canonical bytes.
+    pub fn bytes(&self) -> Vec<u8> {
+        (0..self.hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&self.hex[i..i + 2], 16).expect("vector hex is valid"))
+            .collect()
+    }
+}
+
+/// SHA-256 multihash PeerId with a fixed digest, so vectors are stable.
+fn fixed_peer_id_bytes() -> ByteBuf {
+    let mut bytes = vec![0x12, 0x20];
+    bytes.extend_from_slice(&[0x07; 32]);
+    ByteBuf::from(bytes)
+}
+
+fn basic_peer() -> PeerInfoWire {
+    PeerInfoWire {
+        addr: "127.0.0.1:6100".parse().unwrap(),
+        peer_id_bytes: fixed_peer_id_bytes(),
+        spare_mbs: 64,
+        price: 1.5,
+        egress_price_per_gib: 0.25,
+        storage_offers: vec![],
+        burst_mbs: 0,
+        burst_price: 0.0,
+        features: Features::default(),
+        metadata: Default::default(),
+        region: None,
+        online_since: None,
+        capabilities: Capabilities::default(),
+    }
+}
+
+fn peer_with_offers() -> PeerInfoWire {
+    PeerInfoWire {
+        addr: "[::1]:7000".parse().unwrap(),
+        peer_id_bytes: fixed_peer_id_bytes(),
+        spare_mbs: 2048,
+        price: 3.0,
+        egress_price_per_gib: 1.0,
+        storage_offers: vec![
+            StorageOffer {
+                class: StorageClass::Hot,
+                price: 4.0,
+                retrieval_latency_ms: 20,
+            },
+            StorageOffer {
+                class: StorageClass::Cold,
+                price: 0.5,
+                retrieval_latency_ms: 60_000,
+            },
+        ],
+        burst_mbs: 512,
+        burst_price: 0.75,
+        features: Features(0b11),
+        metadata: BTreeMap::from([
+            ("hardware".into(), "nas".into()),
+            ("operator".into(), "example".into()),
+        ]),
+        region: Some("eu-west".into()),
+        online_since: Some(1_700_000_000),
+        capabilities: Capabilities::STORAGE | Capabilities::RELAY,
+    }
+}
+
+pub fn peer_info_wire_vectors() -> Vec<TestVector<PeerInfoWire>> {
+    vec![
+        TestVector {
+            name: "peer_info_wire/basic",
+            value: basic_peer(),
+            hex: "000000007f000001d41722000000000000001220070707070707070707070707070707070707070707070707070707070707070740000000000000000000c03f0000803e00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        },
+        TestVector {
+            name: "peer_info_wire/with_offers",
+            value: peer_with_offers(),
+            hex: "0100000000000000000000000000000000000001581b2200000000000000122007070707070707070707070707070707070707070707070707070707070707070008000000000000000040400000803f0200000000000000000000000000804014000000010000000000003f60ea000000020000000000000000403f030000000000000002000000000000000800000000000000686172647761726503000000000000006e617308000000000000006f70657261746f7207000000000000006578616d706c6501070000000000000065752d776573740100f15365000000000300000000000000",
+        },
+    ]
+}
+
+/// Announcement payloads as they follow the `SPAR` header: the baseline
+/// fields, then the version byte and the versioned extension.
+pub fn announcement_vectors() -> Vec<TestVector<PeerInfoWire>> {
+    vec![
+        TestVector {
+            name: "announcement/basic",
+            value: basic_peer(),
+            hex: "000000007f000001d41722000000000000001220070707070707070707070707070707070707070707070707070707070707070740000000000000000000c03f010000803e00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        },
+        TestVector {
+            name: "announcement/with_offers",
+            value: peer_with_offers(),
+            hex: "0100000000000000000000000000000000000001581b220000000000000012200707070707070707070707070707070707070707070707070707070707070707000800000000000000004040010000803f0200000000000000000000000000804014000000010000000000003f60ea000000020000000000000000403f030000000000000002000000000000000800000000000000686172647761726503000000000000006e617308000000000000006f70657261746f7207000000000000006578616d706c6501070000000000000065752d776573740100f15365000000000300000000000000",
+        },
+    ]
+}
+
+pub fn deal_vectors() -> Vec<TestVector<Deal>> {
+    vec![
+        TestVector {
+            name: "deal/unclassed",
+            value: Deal {
+                peer_info_wire: basic_peer(),
+                file_len: 10 * BYTES_PER_MEBIBYTE,
+                price_per_mb: 2.0,
+                storage_class: None,
+                deadline_secs: None,
+                redundancy: None,
+                labels: vec![],
+                burst: false,
+                priority: Priority::Normal,
+            },
+            hex: "000000007f000001d41722000000000000001220070707070707070707070707070707070707070707070707070707070707070740000000000000000000c03f0000803e000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000a000000000000000004000000000000000000000000001000000",
+        },
+        TestVector {
+            name: "deal/cold",
+            value: Deal {
+                peer_info_wire: peer_with_offers(),
+                file_len: 512 * BYTES_PER_MEBIBYTE,
+                price_per_mb: 0.75,
+                storage_class: Some(StorageClass::Cold),
+                deadline_secs: Some(3600),
+                redundancy: Some(Redundancy::ErasureCoded { k: 4, n: 6 }),
+                labels: vec!["backup:photos".into()],
+                burst: true,
+                priority: Priority::High,
+            },
+            hex: "0100000000000000000000000000000000000001581b2200000000000000122007070707070707070707070707070707070707070707070707070707070707070008000000000000000040400000803f0200000000000000000000000000804014000000010000000000003f60ea000000020000000000000000403f030000000000000002000000000000000800000000000000686172647761726503000000000000006e617308000000000000006f70657261746f7207000000000000006578616d706c6501070000000000000065752d776573740100f1536500000000030000000000000000000020000000000000403f010100000001100e0000000000000102000000040601000000000000000d000000000000006261636b75703a70686f746f730102000000",
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::peer_info::{decode_announcement, encode_announcement, PeerInfo};
+
+    fn check<T: Serialize + DeserializeOwned + PartialEq + Debug>(vectors: Vec<TestVector<T>>) {
+        for v in vectors {
+            let encoded = bincode::serialize(&v.value).unwrap();
+            assert_eq!(encoded, v.bytes(), "{} encoding changed", v.name);
+            let decoded: T = bincode::deserialize(&v.bytes()).unwrap();
+            assert_eq!(decoded, v.value, "{} decoding changed", v.name);
+        }
+    }
+
+    #[test]
+    fn peer_info_wire_vectors_match() {
+        check(peer_info_wire_vectors());
+    }
+
+    #[test]
+    fn deal_vectors_match() {
+        check(deal_vectors());
+    }
+
+    #[test]
+    fn announcement_vectors_match() {
+        for v in announcement_vectors() {
+            assert_eq!(
+                encode_announcement(&v.value),
+                v.bytes(),
+                "{} encoding changed",
+                v.name
+            );
+            let decoded = decode_announcement(&v.bytes()).unwrap();
+            assert_eq!(decoded, v.value, "{} decoding changed", v.name);
+        }
+    }
+
+    #[test]
+    /// the fixed peer id must stay a valid libp2p PeerId
+    fn fixed_peer_id_parses() {
+        assert!(PeerInfo::try_from(basic_peer()).is_ok());
+    }
+}
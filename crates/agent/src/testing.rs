@@ -0,0 +1,118 @@
+//! Helpers for spinning up fully wired agents on loopback.
+//!
+//! Available to the crate's own tests and, behind the `testing` feature, to
+//! downstream crates that want to exercise spare-net end to end. Every agent
+//! binds ephemeral loopback ports, so parallel tests don't collide.
+
+use libp2p::PeerId;
+use std::{error::Error, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::time::{self, Instant};
+
+use crate::{
+    agent::Agent,
+    deal::{Deal, Priority, BYTES_PER_MEBIBYTE},
+    discovery::DiscoveryService,
+    peer_info::{Capabilities, Features, PeerInfo, PeerInfoWire},
+};
+
+/// A provider-ish peer with a fresh identity; `addr` is assigned by the mesh.
+pub fn test_peer_info() -> PeerInfo {
+    PeerInfo {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        peer_id: PeerId::random(),
+        spare_mbs: 100,
+        price: 1.0,
+        egress_price_per_gib: 1.0,
+        storage_offers: vec![],
+        burst_mbs: 0,
+        burst_price: 0.0,
+        features: Features::SUPPORTED,
+        metadata: Default::default(),
+        region: None,
+        online_since: None,
+        capabilities: Capabilities::STORAGE,
+    }
+}
+
+/// A plain deal from `peer` for `file_mbs` MiB at 1.0 per MiB; override
+/// fields with struct update syntax.
+pub fn deal_from(peer: PeerInfo, file_mbs: u64) -> Deal {
+    Deal {
+        peer_info_wire: PeerInfoWire::from(peer),
+        file_len: file_mbs * BYTES_PER_MEBIBYTE,
+        price_per_mb: 1.0,
+        storage_class: None,
+        deadline_secs: None,
+        redundancy: None,
+        labels: vec![],
+        burst: false,
+        priority: Priority::Normal,
+    }
+}
+
+/// Build `n` agents with [`test_peer_info`] that announce to each other.
+pub async fn agent_mesh(n: usize) -> Result<Vec<Arc<Agent>>, Box<dyn Error>> {
+    agent_mesh_with((0..n).map(|_| test_peer_info()).collect()).await
+}
+
+/// Build one agent per `PeerInfo`, binding discovery and QUIC to ephemeral
+/// loopback ports (each `addr` is replaced with the port actually bound).
+/// Discovery announces directly to every other agent, so no multicast is
+/// needed. Agents are returned in input order and not started; call
+/// [`Agent::run`] or [`start_all`].
+pub async fn agent_mesh_with(peer_infos: Vec<PeerInfo>) -> Result<Vec<Arc<Agent>>, Box<dyn Error>> {
+    let mut services = Vec::with_capacity(peer_infos.len());
+    for mut peer_info in peer_infos {
+        peer_info.addr = "127.0.0.1:0".parse()?;
+        // the multicast destination is unused here; point it at a discard
+        // port and reach the other agents by unicast
+        services
+            .push(DiscoveryService::test_with_addr(peer_info, "127.0.0.1:0", "127.0.0.1:9").await?);
+    }
+    let discovery_addrs = services
+        .iter()
+        .map(DiscoveryService::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut agents = Vec::with_capacity(services.len());
+    for (i, dsvc) in services.into_iter().enumerate() {
+        let others: Vec<SocketAddr> = discovery_addrs
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, addr)| *addr)
+            .collect();
+        let dsvc = dsvc.with_unicast_targets(others);
+        agents.push(Arc::new(Agent::with_discovery(dsvc).await?));
+    }
+    Ok(agents)
+}
+
+/// Start every agent in the background.
+pub async fn start_all(agents: &[Arc<Agent>]) {
+    for agent in agents {
+        agent.clone().run().await;
+    }
+}
+
+/// Wait until every agent sees all others, or `timeout` elapses.
+/// Returns whether the mesh converged.
+pub async fn wait_for_discovery(agents: &[Arc<Agent>], timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut converged = true;
+        for agent in agents {
+            if agent.discovery().get_peers().await.len() < agents.len() - 1 {
+                converged = false;
+                break;
+            }
+        }
+        if converged {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
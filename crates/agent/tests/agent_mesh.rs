@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use sparenet_agent::{
+    deal::Deal,
+    testing::{agent_mesh, deal_from, start_all, wait_for_discovery},
+};
+use tokio::time;
+
+#[tokio::test]
+/// three agents on ephemeral loopback ports discover each other and a
+/// matched deal reaches every other agent
+async fn three_agents_exchange_deals() {
+    let agents = agent_mesh(3).await.unwrap();
+    start_all(&agents).await;
+    assert!(
+        wait_for_discovery(&agents, Duration::from_secs(5)).await,
+        "agents should discover each other"
+    );
+
+    let sender = &agents[0];
+    let deal = Deal {
+        price_per_mb: 2.0,
+        ..deal_from(sender.get_peer_info(), 10)
+    };
+    sender.send_matched_deals(deal.clone()).await.unwrap();
+    time::sleep(Duration::from_millis(500)).await;
+
+    let key = sender.get_peer_info().addr.to_string();
+    for receiver in &agents[1..] {
+        let received = receiver.incoming_deals().await;
+        assert_eq!(received.get(&key), Some(&deal));
+    }
+}
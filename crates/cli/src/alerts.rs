@@ -0,0 +1,54 @@
+//! Operator alerts delivered by running a shell command, e.g. `mail` or a
+//! `curl` to a Slack or Matrix webhook.
+
+use sparenet_agent::notify::{Notification, Notifier};
+use std::{
+    process::Command,
+    sync::mpsc::{self, SyncSender, TrySendError},
+};
+use tracing::warn;
+
+/// Notifications waiting for the worker; more are dropped, with a warning.
+const QUEUE: usize = 16;
+
+/// Runs `sh -c <command>` per notification with `SPARENET_NOTIFY_TITLE` and
+/// `SPARENET_NOTIFY_BODY` set. Notifications queue for a single worker
+/// thread, so the agent never waits and a slow command never runs more than
+/// once at a time.
+pub struct CommandNotifier {
+    queue: SyncSender<Notification>,
+}
+
+impl CommandNotifier {
+    pub fn new(command: String) -> Self {
+        let (queue, pending) = mpsc::sync_channel::<Notification>(QUEUE);
+        std::thread::spawn(move || {
+            for notification in pending {
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .env("SPARENET_NOTIFY_TITLE", &notification.title)
+                    .env("SPARENET_NOTIFY_BODY", &notification.body)
+                    .status();
+                match status {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => warn!("notify command exited with {status}"),
+                    Err(e) => warn!("failed to run notify command: {e}"),
+                }
+            }
+        });
+        Self { queue }
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, notification: &Notification) {
+        match self.queue.try_send(notification.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("notify command is behind, dropping \"{notification}\"")
+            }
+            Err(TrySendError::Disconnected(_)) => warn!("notify worker is gone"),
+        }
+    }
+}
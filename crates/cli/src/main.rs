@@ -1,3 +1,1108 @@
-fn main() {
-    println!("Hello, world!");
+mod alerts;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "upnp")]
+mod upnp;
+mod watch;
+
+use alerts::CommandNotifier;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use libp2p::PeerId;
+use serde::Serialize;
+use sparenet_agent::{
+    agent::{AcceptFilter, Agent, Health},
+    connection::{next_incoming, EndpointLimits},
+    deal::{Deal, BYTES_PER_MEBIBYTE},
+    discovery::{Discovery, DiscoveryConfig, DiscoveryEvent, DiscoveryService, PeerSnapshot},
+    dnssd::DnsSdDiscovery,
+    notify::DealAlerts,
+    overrides::{PeerOverride, PeerOverrides, Trust},
+    peer_info::{
+        metadata_size, Capabilities, Features, PeerInfo, PeerRequirements, StorageClass,
+        MAX_METADATA_BYTES, MAX_REGION_BYTES,
+    },
+    pricing::Undercut,
+    recorder::{read_recording, ReplayStats, TrafficRecorder},
+    schema::wire_schemas,
+    test_vectors::{announcement_vectors, deal_vectors, peer_info_wire_vectors},
+    transfer::{open_file_receiver, open_file_sender, receive_file, send_file, CertFingerprint},
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast::error::RecvError,
+};
+use tracing::{error, info};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use watch::DealTemplate;
+
+#[derive(Parser)]
+#[command(
+    name = "spare-net",
+    version,
+    about = "Share spare capacity with nearby peers"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run an agent until SIGTERM or Ctrl-C
+    Run(Box<RunArgs>),
+    /// Check that an agent with these options can start, then save them to
+    /// an environment file for `run`; prints the outcome as JSON
+    Init(Box<InitArgs>),
+    /// Listen to discovery without announcing or accepting deals, printing
+    /// the peer table as a JSON line every interval
+    Observe {
+        /// Seconds between peer table snapshots
+        #[arg(
+            long,
+            env = "SPARENET_OBSERVE_INTERVAL_SECS",
+            default_value_t = 5,
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        interval_secs: u64,
+        /// Print network-wide statistics (capacity, price percentiles,
+        /// churn) instead of the peer table
+        #[arg(long)]
+        stats: bool,
+    },
+    /// Pin a note, trust level, deal size cap or price on a peer in a peer
+    /// overrides file, printing the peer's resulting settings as JSON. A
+    /// running agent picks the change up on SIGHUP
+    Peer(Box<PeerArgs>),
+    /// Feed a recording made with `run --record` into an offline agent and
+    /// print the resulting proposals, peer table and health as JSON
+    Replay {
+        /// Recording to replay
+        file: PathBuf,
+    },
+    /// Send one file straight to a peer running `spare-net recv`, found
+    /// through discovery, without proposing a deal; prints the outcome as
+    /// JSON
+    Send {
+        /// File to send
+        file: PathBuf,
+        /// Peer id the receiver printed on startup, found through discovery
+        #[arg(long, required_unless_present = "addr", conflicts_with = "addr")]
+        to: Option<PeerId>,
+        /// Receiver's address, dialed directly instead of waiting for `--to`
+        /// to be discovered
+        #[arg(long)]
+        addr: Option<SocketAddr>,
+        /// Certificate fingerprint the receiver printed on startup; the
+        /// receiver's certificate is self-signed, so it is pinned rather
+        /// than checked against a trust store
+        #[arg(long)]
+        cert: CertFingerprint,
+        /// Seconds to wait for the receiver to be discovered
+        #[arg(long, default_value_t = 10)]
+        timeout_secs: u64,
+    },
+    /// Announce on discovery and save files sent with `spare-net send`,
+    /// printing each as a JSON line
+    Recv(Box<RecvArgs>),
+    /// Print the JSON Schema of every wire type
+    Schema,
+    /// Print canonical wire encodings for validating other implementations
+    TestVectors,
+}
+
+/// Every option can also be set through its `SPARENET_*` environment variable.
+#[derive(Args)]
+struct RunArgs {
+    /// Address peers dial for deals; also bound unless `--listen` is given
+    #[arg(long, env = "SPARENET_ADDR")]
+    addr: SocketAddr,
+    /// Local address the QUIC control endpoint binds, e.g. behind NAT
+    #[arg(long, env = "SPARENET_LISTEN")]
+    listen: Option<SocketAddr>,
+    /// Peer id to announce; a random one is used if unset
+    #[arg(long, env = "SPARENET_PEER_ID")]
+    peer_id: Option<PeerId>,
+    /// Forward the listen port on the local gateway via UPnP and advertise
+    /// the gateway's external address instead of `--addr`
+    #[cfg(feature = "upnp")]
+    #[arg(long, env = "SPARENET_UPNP")]
+    upnp: bool,
+    /// Spare capacity offered, in MiB
+    #[arg(long, env = "SPARENET_SPARE_MBS", default_value_t = 0)]
+    spare_mbs: u64,
+    /// Asking price per MiB stored
+    #[arg(long, env = "SPARENET_PRICE", default_value_t = 1.0)]
+    price: f32,
+    /// Price per GiB served on retrieval
+    #[arg(long, env = "SPARENET_EGRESS_PRICE_PER_GIB", default_value_t = 0.0)]
+    egress_price_per_gib: f32,
+    /// Reclaimable capacity offered on top of `--spare-mbs`, in MiB
+    #[arg(long, env = "SPARENET_BURST_MBS", default_value_t = 0)]
+    burst_mbs: u64,
+    /// Price per MiB on burst capacity
+    #[arg(long, env = "SPARENET_BURST_PRICE", default_value_t = 0.0)]
+    burst_price: f32,
+    /// Reprice to this percentage below the median price competing
+    /// providers advertise, checked every 30s
+    #[arg(long, env = "SPARENET_UNDERCUT_PERCENT")]
+    undercut_percent: Option<f32>,
+    /// Lowest price per MiB `--undercut-percent` will advertise
+    #[arg(long, env = "SPARENET_PRICE_FLOOR", default_value_t = 0.0)]
+    price_floor: f32,
+    /// Highest price per MiB `--undercut-percent` will advertise
+    #[arg(long, env = "SPARENET_PRICE_CEILING", default_value_t = f32::MAX)]
+    price_ceiling: f32,
+    /// Shell command run when a deal worth at least `--notify-min-cost`
+    /// arrives, with `SPARENET_NOTIFY_TITLE` and `SPARENET_NOTIFY_BODY` set
+    #[arg(long, env = "SPARENET_NOTIFY_COMMAND")]
+    notify_command: Option<String>,
+    /// Smallest whole-file deal value that triggers `--notify-command`
+    #[arg(long, env = "SPARENET_NOTIFY_MIN_COST", default_value_t = 0.0)]
+    notify_min_cost: f32,
+    /// Fewest seconds between two `--notify-command` runs; deals arriving in
+    /// between are summed up in the next one
+    #[arg(long, env = "SPARENET_NOTIFY_INTERVAL_SECS", default_value_t = 60)]
+    notify_interval_secs: u64,
+    /// Seconds a received deal is kept before it lapses
+    #[arg(long, env = "SPARENET_PROPOSAL_TTL_SECS", default_value_t = 600)]
+    proposal_ttl_secs: u64,
+    /// Seconds a QUIC connection may sit idle before it is closed
+    #[arg(long, env = "SPARENET_IDLE_TIMEOUT_SECS", default_value_t = 10)]
+    idle_timeout_secs: u64,
+    /// Seconds between discovery announcements; raise on metered links
+    #[arg(
+        long,
+        env = "SPARENET_ANNOUNCE_INTERVAL_SECS",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    announce_interval_secs: u64,
+    /// Seconds without an announcement before a peer is forgotten; keep it a
+    /// few announce intervals
+    #[arg(
+        long,
+        env = "SPARENET_PEER_TIMEOUT_SECS",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    peer_timeout_secs: u64,
+    /// Discovery addresses (UDP port 5333) of known agents to join through
+    /// when outside their multicast domain, comma-separated
+    #[arg(long, env = "SPARENET_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap: Vec<SocketAddr>,
+    /// Answer agents that join through this one with `--bootstrap`, at most
+    /// once per announce interval per source IP
+    #[arg(long, env = "SPARENET_BOOTSTRAP_SERVER")]
+    bootstrap_server: bool,
+    /// Discover and announce with standard DNS-SD records
+    /// (`_sparenet._udp.local`) on port 5353 instead of sparenet's own
+    /// announcements, so tools like `avahi-browse` see the agent
+    #[arg(long, env = "SPARENET_DNS_SD", conflicts_with_all = ["bootstrap", "bootstrap_server", "record"])]
+    dns_sd: bool,
+    /// JSON file of per-peer overrides (see `spare-net peer`), taking
+    /// precedence over the agent's own matching and approval; re-read on
+    /// SIGHUP
+    #[arg(long, env = "SPARENET_PEER_OVERRIDES")]
+    peer_overrides: Option<PathBuf>,
+    /// Only accept deal connections from hosts in the discovery table
+    #[arg(long, env = "SPARENET_ACCEPT_KNOWN_PEERS")]
+    accept_known_peers: bool,
+    /// Always accept deal connections from these IPs, comma-separated;
+    /// setting any restricts connections to the list (plus known peers)
+    #[arg(long, env = "SPARENET_ACCEPT_FROM", value_delimiter = ',')]
+    accept_from: Vec<IpAddr>,
+    /// Add proposers missing from discovery to the peer table, if their IP
+    /// is in `--accept-from` or a known peer's; their identity is taken
+    /// from the deal unverified
+    #[arg(long, env = "SPARENET_BACKFILL_PROPOSERS")]
+    backfill_proposers: bool,
+    /// Operator metadata to announce, as comma-separated `key=value` pairs
+    #[arg(long, env = "SPARENET_METADATA", value_delimiter = ',', value_parser = parse_key_value)]
+    metadata: Vec<(String, String)>,
+    /// Region to announce, e.g. `eu-west`
+    #[arg(long, env = "SPARENET_REGION")]
+    region: Option<String>,
+    /// Services to announce, comma-separated
+    #[arg(long, env = "SPARENET_CAPABILITIES", value_enum, value_delimiter = ',')]
+    capabilities: Vec<CapabilityArg>,
+    /// Only propose deals to peers announcing one of these regions,
+    /// comma-separated
+    #[arg(long, env = "SPARENET_REQUIRE_REGION", value_delimiter = ',')]
+    require_region: Vec<String>,
+    /// Only propose deals to peers announcing at least this uptime
+    #[arg(long, env = "SPARENET_REQUIRE_UPTIME_SECS")]
+    require_uptime_secs: Option<u64>,
+    /// Only propose deals to peers announcing all of these services,
+    /// comma-separated
+    #[arg(
+        long,
+        env = "SPARENET_REQUIRE_CAPABILITIES",
+        value_enum,
+        value_delimiter = ','
+    )]
+    require_capabilities: Vec<CapabilityArg>,
+    /// Propose a deal for every new or changed file under this directory
+    #[arg(long, env = "SPARENET_WATCH")]
+    watch: Option<PathBuf>,
+    /// Price per MiB offered for files found by `--watch`
+    #[arg(long, env = "SPARENET_WATCH_PRICE_PER_MB", default_value_t = 1.0)]
+    watch_price_per_mb: f32,
+    /// Storage class requested for files found by `--watch`
+    #[arg(long, env = "SPARENET_WATCH_STORAGE_CLASS", value_enum)]
+    watch_storage_class: Option<ClassArg>,
+    /// Labels attached to deals for files found by `--watch`, comma-separated
+    #[arg(long, env = "SPARENET_WATCH_LABELS", value_delimiter = ',')]
+    watch_labels: Vec<String>,
+    /// Place files found by `--watch` on cheaper, reclaimable burst capacity
+    #[arg(long, env = "SPARENET_WATCH_BURST")]
+    watch_burst: bool,
+    /// Log where files found by `--watch` would be placed, and the cost,
+    /// without proposing anything
+    #[arg(long, env = "SPARENET_WATCH_DRY_RUN")]
+    watch_dry_run: bool,
+    /// Append announcements and deals sent and received to this file as
+    /// JSON lines, for `spare-net replay`
+    #[arg(long, env = "SPARENET_RECORD")]
+    record: Option<PathBuf>,
+    /// Export tracing spans over OTLP/HTTP to `OTEL_EXPORTER_OTLP_ENDPOINT`
+    #[cfg(feature = "otel")]
+    #[arg(long, env = "SPARENET_OTLP")]
+    otlp: bool,
+    /// Include the first bytes of each rejected discovery datagram, in hex,
+    /// in the (rate-limited) rejection log lines
+    #[arg(long, env = "SPARENET_LOG_REJECTED_SAMPLES")]
+    log_rejected_samples: bool,
+    /// Log line format written to stdout
+    #[arg(long, env = "SPARENET_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(Args)]
+struct PeerArgs {
+    /// Peer to change
+    peer_id: PeerId,
+    /// Overrides file to update; created if missing
+    #[arg(long, env = "SPARENET_PEER_OVERRIDES")]
+    file: PathBuf,
+    /// Free-form note, e.g. who runs the peer; empty to remove it
+    #[arg(long)]
+    note: Option<String>,
+    /// Trust to pin; `auto` leaves it to the agent again
+    #[arg(long, value_enum)]
+    trust: Option<TrustArg>,
+    /// Largest deal share, in MiB, sent to or accepted from the peer
+    #[arg(long)]
+    max_deal_mbs: Option<u64>,
+    /// Price per MiB used for the peer instead of what it advertises
+    #[arg(long)]
+    price: Option<f32>,
+    /// Drop every setting for the peer first
+    #[arg(long)]
+    clear: bool,
+}
+
+#[derive(Args)]
+struct RecvArgs {
+    /// Address senders dial; also bound
+    #[arg(long, env = "SPARENET_ADDR")]
+    addr: SocketAddr,
+    /// Peer id to announce; a random one is used if unset
+    #[arg(long, env = "SPARENET_PEER_ID")]
+    peer_id: Option<PeerId>,
+    /// Directory received files are saved in
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+    /// Largest file accepted, in MiB
+    #[arg(long, default_value_t = 1024)]
+    max_mbs: u64,
+    /// Keep receiving until SIGTERM or Ctrl-C instead of exiting after the
+    /// first file
+    #[arg(long)]
+    keep: bool,
+}
+
+#[derive(Args)]
+struct InitArgs {
+    /// Environment file to write, e.g. for systemd `EnvironmentFile=` or
+    /// `docker run --env-file`; a peer id already in it is kept
+    #[arg(long, env = "SPARENET_CONFIG", default_value = "spare-net.env")]
+    config: PathBuf,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// Result of `init`, printed as JSON.
+#[derive(Serialize)]
+struct InitReport {
+    ok: bool,
+    peer_id: String,
+    config: PathBuf,
+    /// whether the environment file was written; only after all checks pass
+    written: bool,
+    checks: InitChecks,
+}
+
+/// `"ok"`, `"skipped"`, or the error message.
+#[derive(Serialize)]
+struct InitChecks {
+    metadata: String,
+    multicast: String,
+    quic_bind: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TrustArg {
+    Blocked,
+    Trusted,
+    Auto,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CapabilityArg {
+    Storage,
+    Relay,
+    Bandwidth,
+}
+
+fn capabilities(args: &[CapabilityArg]) -> Capabilities {
+    args.iter()
+        .map(|arg| match arg {
+            CapabilityArg::Storage => Capabilities::STORAGE,
+            CapabilityArg::Relay => Capabilities::RELAY,
+            CapabilityArg::Bandwidth => Capabilities::BANDWIDTH,
+        })
+        .fold(Capabilities::default(), |all, c| all | c)
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ClassArg {
+    Hot,
+    Cold,
+}
+
+impl From<ClassArg> for StorageClass {
+    fn from(class: ClassArg) -> Self {
+        match class {
+            ClassArg::Hot => StorageClass::Hot,
+            ClassArg::Cold => StorageClass::Cold,
+        }
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got `{s}`"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+impl RunArgs {
+    /// Announced peer info, online from now. Fails if the metadata or region
+    /// is over its size limit or the price bounds are inverted.
+    fn peer_info(&self) -> Result<PeerInfo, String> {
+        let metadata = self.metadata.iter().cloned().collect();
+        let size = metadata_size(&metadata);
+        if size > MAX_METADATA_BYTES {
+            return Err(format!(
+                "metadata is {size} bytes, limit is {MAX_METADATA_BYTES}"
+            ));
+        }
+        if let Some(size) = self.region.as_ref().map(String::len) {
+            if size > MAX_REGION_BYTES {
+                return Err(format!(
+                    "region is {size} bytes, limit is {MAX_REGION_BYTES}"
+                ));
+            }
+        }
+        if self.price_floor > self.price_ceiling {
+            return Err(format!(
+                "price floor {} is above price ceiling {}",
+                self.price_floor, self.price_ceiling
+            ));
+        }
+        Ok(PeerInfo {
+            addr: self.addr,
+            peer_id: self.peer_id.unwrap_or_else(PeerId::random),
+            spare_mbs: self.spare_mbs,
+            price: self.price,
+            egress_price_per_gib: self.egress_price_per_gib,
+            storage_offers: vec![],
+            burst_mbs: self.burst_mbs,
+            burst_price: self.burst_price,
+            features: Features::SUPPORTED,
+            metadata,
+            region: self.region.clone(),
+            online_since: Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            ),
+            capabilities: capabilities(&self.capabilities),
+        })
+    }
+
+    /// The options as `SPARENET_*` variables, omitting unset ones.
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        fn list<T: ToString>(items: &[T]) -> String {
+            items
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+        fn name<T: ValueEnum>(value: &T) -> String {
+            value
+                .to_possible_value()
+                .expect("no skipped variants")
+                .get_name()
+                .to_string()
+        }
+        let mut vars = vec![("SPARENET_ADDR", self.addr.to_string())];
+        if let Some(listen) = self.listen {
+            vars.push(("SPARENET_LISTEN", listen.to_string()));
+        }
+        if let Some(peer_id) = self.peer_id {
+            vars.push(("SPARENET_PEER_ID", peer_id.to_string()));
+        }
+        #[cfg(feature = "upnp")]
+        if self.upnp {
+            vars.push(("SPARENET_UPNP", "true".into()));
+        }
+        vars.extend([
+            ("SPARENET_SPARE_MBS", self.spare_mbs.to_string()),
+            ("SPARENET_PRICE", self.price.to_string()),
+            (
+                "SPARENET_EGRESS_PRICE_PER_GIB",
+                self.egress_price_per_gib.to_string(),
+            ),
+            ("SPARENET_BURST_MBS", self.burst_mbs.to_string()),
+            ("SPARENET_BURST_PRICE", self.burst_price.to_string()),
+            (
+                "SPARENET_PROPOSAL_TTL_SECS",
+                self.proposal_ttl_secs.to_string(),
+            ),
+            (
+                "SPARENET_IDLE_TIMEOUT_SECS",
+                self.idle_timeout_secs.to_string(),
+            ),
+            (
+                "SPARENET_ANNOUNCE_INTERVAL_SECS",
+                self.announce_interval_secs.to_string(),
+            ),
+            (
+                "SPARENET_PEER_TIMEOUT_SECS",
+                self.peer_timeout_secs.to_string(),
+            ),
+        ]);
+        if let Some(percent) = self.undercut_percent {
+            vars.extend([
+                ("SPARENET_UNDERCUT_PERCENT", percent.to_string()),
+                ("SPARENET_PRICE_FLOOR", self.price_floor.to_string()),
+                ("SPARENET_PRICE_CEILING", self.price_ceiling.to_string()),
+            ]);
+        }
+        if let Some(command) = &self.notify_command {
+            vars.extend([
+                ("SPARENET_NOTIFY_COMMAND", command.clone()),
+                ("SPARENET_NOTIFY_MIN_COST", self.notify_min_cost.to_string()),
+                (
+                    "SPARENET_NOTIFY_INTERVAL_SECS",
+                    self.notify_interval_secs.to_string(),
+                ),
+            ]);
+        }
+        if !self.bootstrap.is_empty() {
+            vars.push(("SPARENET_BOOTSTRAP", list(&self.bootstrap)));
+        }
+        if self.bootstrap_server {
+            vars.push(("SPARENET_BOOTSTRAP_SERVER", "true".into()));
+        }
+        if self.dns_sd {
+            vars.push(("SPARENET_DNS_SD", "true".into()));
+        }
+        if let Some(path) = &self.peer_overrides {
+            vars.push(("SPARENET_PEER_OVERRIDES", path.display().to_string()));
+        }
+        if self.accept_known_peers {
+            vars.push(("SPARENET_ACCEPT_KNOWN_PEERS", "true".into()));
+        }
+        if !self.accept_from.is_empty() {
+            vars.push(("SPARENET_ACCEPT_FROM", list(&self.accept_from)));
+        }
+        if self.backfill_proposers {
+            vars.push(("SPARENET_BACKFILL_PROPOSERS", "true".into()));
+        }
+        if !self.metadata.is_empty() {
+            let pairs: Vec<_> = self
+                .metadata
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            vars.push(("SPARENET_METADATA", list(&pairs)));
+        }
+        if let Some(region) = &self.region {
+            vars.push(("SPARENET_REGION", region.clone()));
+        }
+        if !self.capabilities.is_empty() {
+            let names: Vec<_> = self.capabilities.iter().map(name).collect();
+            vars.push(("SPARENET_CAPABILITIES", list(&names)));
+        }
+        if !self.require_region.is_empty() {
+            vars.push(("SPARENET_REQUIRE_REGION", list(&self.require_region)));
+        }
+        if let Some(secs) = self.require_uptime_secs {
+            vars.push(("SPARENET_REQUIRE_UPTIME_SECS", secs.to_string()));
+        }
+        if !self.require_capabilities.is_empty() {
+            let names: Vec<_> = self.require_capabilities.iter().map(name).collect();
+            vars.push(("SPARENET_REQUIRE_CAPABILITIES", list(&names)));
+        }
+        if let Some(dir) = &self.watch {
+            vars.extend([
+                ("SPARENET_WATCH", dir.display().to_string()),
+                (
+                    "SPARENET_WATCH_PRICE_PER_MB",
+                    self.watch_price_per_mb.to_string(),
+                ),
+            ]);
+            if let Some(class) = &self.watch_storage_class {
+                vars.push(("SPARENET_WATCH_STORAGE_CLASS", name(class)));
+            }
+            if !self.watch_labels.is_empty() {
+                vars.push(("SPARENET_WATCH_LABELS", list(&self.watch_labels)));
+            }
+            if self.watch_burst {
+                vars.push(("SPARENET_WATCH_BURST", "true".into()));
+            }
+            if self.watch_dry_run {
+                vars.push(("SPARENET_WATCH_DRY_RUN", "true".into()));
+            }
+        }
+        if let Some(path) = &self.record {
+            vars.push(("SPARENET_RECORD", path.display().to_string()));
+        }
+        #[cfg(feature = "otel")]
+        if self.otlp {
+            vars.push(("SPARENET_OTLP", "true".into()));
+        }
+        if self.log_rejected_samples {
+            vars.push(("SPARENET_LOG_REJECTED_SAMPLES", "true".into()));
+        }
+        vars.push(("SPARENET_LOG_FORMAT", name(&self.log_format)));
+        vars
+    }
+}
+
+/// `SPARENET_PEER_ID` from an existing environment file, so re-running
+/// `init` keeps the agent's identity.
+fn saved_peer_id(config: &Path) -> Option<PeerId> {
+    std::fs::read_to_string(config)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("SPARENET_PEER_ID="))
+        .and_then(|id| id.trim().parse().ok())
+}
+
+/// Bind everything `run` would (multicast discovery socket, QUIC endpoint)
+/// without starting the agent, and write the config if that works. Exits
+/// non-zero if any check fails.
+async fn init(mut args: InitArgs) -> Result<(), Box<dyn Error>> {
+    let run = &mut args.run;
+    run.peer_id = run
+        .peer_id
+        .or_else(|| saved_peer_id(&args.config))
+        .or_else(|| Some(PeerId::random()));
+    let skipped = || "skipped".to_string();
+    let mut checks = InitChecks {
+        metadata: "ok".into(),
+        multicast: skipped(),
+        quic_bind: skipped(),
+    };
+    match run.peer_info() {
+        Err(e) => checks.metadata = e,
+        Ok(peer_info) => {
+            let listen = run.listen.unwrap_or(run.addr);
+            let bound = if run.dns_sd {
+                match DnsSdDiscovery::new(peer_info).await {
+                    Ok(discovery) => Ok(quic_bind_check(discovery, listen).await),
+                    Err(e) => Err(e),
+                }
+            } else {
+                match DiscoveryService::new(peer_info).await {
+                    Ok(discovery) => Ok(quic_bind_check(discovery, listen).await),
+                    Err(e) => Err(e),
+                }
+            };
+            match bound {
+                Err(e) => checks.multicast = e.to_string(),
+                Ok(quic_bind) => {
+                    checks.multicast = "ok".into();
+                    checks.quic_bind = quic_bind;
+                }
+            }
+        }
+    }
+    let ok = [&checks.metadata, &checks.multicast, &checks.quic_bind]
+        .iter()
+        .all(|check| *check == "ok");
+    if ok {
+        let contents: String = run
+            .env_vars()
+            .into_iter()
+            .map(|(key, value)| format!("{key}={value}\n"))
+            .collect();
+        std::fs::write(&args.config, contents)?;
+    }
+    print_json(&InitReport {
+        ok,
+        peer_id: run.peer_id.expect("set above").to_string(),
+        config: args.config,
+        written: ok,
+        checks,
+    });
+    if !ok {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Outcome of binding the QUIC endpoint on `listen`, for the `init` report.
+async fn quic_bind_check<D: Discovery>(discovery: D, listen: SocketAddr) -> String {
+    match Agent::with_listen_addr(discovery, listen).await {
+        Ok(_) => "ok".into(),
+        Err(e) => e.to_string(),
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("output serializes to JSON")
+    );
+}
+
+/// Log to stdout, filtered by `RUST_LOG` (default `info`). Returns a hook to
+/// call on exit, which flushes exported spans when `--otlp` is on.
+fn init_logging(args: &RunArgs) -> Result<Box<dyn FnOnce()>, Box<dyn Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stdout);
+    let fmt = match args.log_format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(fmt);
+
+    #[cfg(feature = "otel")]
+    if args.otlp {
+        let (layer, provider) = otel::layer()?;
+        registry.with(layer).init();
+        return Ok(Box::new(move || {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("failed to flush spans: {e}");
+            }
+        }));
+    }
+    registry.init();
+    Ok(Box::new(|| {}))
+}
+
+async fn run(args: RunArgs) -> Result<(), Box<dyn Error>> {
+    let flush_telemetry = init_logging(&args)?;
+    #[allow(unused_mut)]
+    let mut peer_info = args.peer_info()?;
+    let listen = args.listen.unwrap_or(peer_info.addr);
+    #[cfg(feature = "upnp")]
+    let mapping = if args.upnp {
+        let mapping = Arc::new(upnp::PortMapping::create(listen).await?);
+        peer_info.addr = mapping.external_addr();
+        let renew = mapping.clone();
+        tokio::spawn(async move { renew.keep_alive().await });
+        Some(mapping)
+    } else {
+        None
+    };
+    let config = DiscoveryConfig {
+        announce_interval: Duration::from_secs(args.announce_interval_secs),
+        peer_timeout: Duration::from_secs(args.peer_timeout_secs),
+        ..DiscoveryConfig::default()
+    };
+    if args.dns_sd {
+        let discovery = DnsSdDiscovery::new(peer_info).await?.with_config(config)?;
+        let agent = serve(args, discovery, listen).await?;
+        // tell browsers we left instead of letting our records linger
+        if let Err(e) = agent.discovery().goodbye().await {
+            error!("failed to withdraw DNS-SD records: {e}");
+        }
+    } else {
+        let mut discovery = DiscoveryService::new(peer_info)
+            .await?
+            .with_config(config)?
+            .with_bootstrap(args.bootstrap.clone())
+            .with_bootstrap_server(args.bootstrap_server)
+            .with_rejection_samples(args.log_rejected_samples);
+        if let Some(path) = &args.record {
+            discovery = discovery.with_recorder(Arc::new(TrafficRecorder::create(path)?));
+        }
+        serve(args, discovery, listen).await?;
+    }
+    #[cfg(feature = "upnp")]
+    if let Some(mapping) = mapping {
+        mapping.remove().await;
+    }
+    flush_telemetry();
+    Ok(())
+}
+
+/// Run an agent on `discovery` until SIGTERM or Ctrl-C, returning it once
+/// it has shut down.
+async fn serve<D: Discovery>(
+    args: RunArgs,
+    discovery: D,
+    listen: SocketAddr,
+) -> Result<Arc<Agent<D>>, Box<dyn Error>> {
+    let mut agent = Agent::with_listen_addr(discovery, listen)
+        .await?
+        .with_proposal_ttl(Duration::from_secs(args.proposal_ttl_secs))
+        .with_proposer_backfill(args.backfill_proposers)
+        .with_endpoint_limits(EndpointLimits {
+            idle_timeout: Duration::from_secs(args.idle_timeout_secs),
+            ..EndpointLimits::default()
+        })?;
+    if let Some(percent) = args.undercut_percent {
+        agent = agent.with_pricing(Undercut {
+            percent,
+            floor: args.price_floor,
+            ceiling: args.price_ceiling,
+        });
+    }
+    if let Some(command) = args.notify_command {
+        agent = agent.with_event_sink(
+            DealAlerts::new(args.notify_min_cost, CommandNotifier::new(command))
+                .with_min_interval(Duration::from_secs(args.notify_interval_secs)),
+        );
+    }
+    let requirements = PeerRequirements {
+        regions: args.require_region,
+        min_uptime_secs: args.require_uptime_secs,
+        capabilities: capabilities(&args.require_capabilities),
+    };
+    if requirements != PeerRequirements::default() {
+        agent = agent.with_match_policy(requirements);
+    }
+    if let Some(path) = &args.peer_overrides {
+        agent.set_peer_overrides(PeerOverrides::load(path)?);
+    }
+    let agent = Arc::new(agent);
+    agent.set_accept_filter(AcceptFilter {
+        known_peers: args.accept_known_peers,
+        allowlist: args.accept_from.into_iter().collect(),
+    });
+    agent.clone().run().await;
+    info!(
+        "agent {} running on {}",
+        agent.get_peer_info().peer_id,
+        agent.get_peer_info().addr
+    );
+
+    if let Some(dir) = args.watch {
+        let template = DealTemplate {
+            price_per_mb: args.watch_price_per_mb,
+            storage_class: args.watch_storage_class.map(Into::into),
+            labels: args.watch_labels,
+            burst: args.watch_burst,
+            dry_run: args.watch_dry_run,
+        };
+        let agent = agent.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch::watch(agent, dir, template).await {
+                error!("directory watch stopped: {e}");
+            }
+        });
+    }
+
+    // container runtimes stop workloads with SIGTERM; Ctrl-C covers terminals
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                info!("received SIGTERM");
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("received Ctrl-C");
+                break;
+            }
+            _ = sighup.recv() => {
+                let Some(path) = &args.peer_overrides else {
+                    continue;
+                };
+                // keep the old overrides if the file was left broken
+                match PeerOverrides::load(path) {
+                    Ok(overrides) => {
+                        agent.set_peer_overrides(overrides);
+                        info!("reloaded peer overrides from {}", path.display());
+                    }
+                    Err(e) => error!("failed to reload peer overrides: {e}"),
+                }
+            }
+        }
+    }
+    agent.shutdown().await;
+    Ok(agent)
+}
+
+/// Apply `args` to the overrides file and print the peer's settings.
+fn set_peer(args: PeerArgs) -> Result<(), Box<dyn Error>> {
+    let mut overrides = PeerOverrides::load(&args.file)?;
+    let mut settings = if args.clear {
+        PeerOverride::default()
+    } else {
+        overrides.get(&args.peer_id).cloned().unwrap_or_default()
+    };
+    if let Some(note) = args.note {
+        settings.note = note;
+    }
+    if let Some(trust) = args.trust {
+        settings.trust = match trust {
+            TrustArg::Blocked => Some(Trust::Blocked),
+            TrustArg::Trusted => Some(Trust::Trusted),
+            TrustArg::Auto => None,
+        };
+    }
+    if let Some(mbs) = args.max_deal_mbs {
+        settings.max_deal_bytes = Some(mbs * BYTES_PER_MEBIBYTE);
+    }
+    if args.price.is_some() {
+        settings.price = args.price;
+    }
+    overrides.set(args.peer_id, settings.clone());
+    overrides.save(&args.file)?;
+    print_json(&settings);
+    Ok(())
+}
+
+/// Result of `send`, printed as JSON.
+#[derive(Serialize)]
+struct SendReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    addr: SocketAddr,
+    bytes: u64,
+    elapsed_ms: u64,
+}
+
+/// Stream `file` to the receiver at `addr`, or wherever `to` announces
+/// itself, trusting only the certificate with fingerprint `cert`.
+async fn send_file_to(
+    file: &Path,
+    to: Option<PeerId>,
+    addr: Option<SocketAddr>,
+    cert: CertFingerprint,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let addr = match (addr, to) {
+        (Some(addr), _) => addr,
+        (None, Some(to)) => discover_addr(to, timeout).await?,
+        (None, None) => return Err("either --to or --addr is required".into()),
+    };
+    let started = Instant::now();
+    let endpoint = open_file_sender(&EndpointLimits::default(), cert)?;
+    let bytes = send_file(&endpoint, addr, file).await?;
+    print_json(&SendReport {
+        to: to.map(|to| to.to_string()),
+        addr,
+        bytes,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    });
+    Ok(())
+}
+
+/// Wait up to `timeout` for `to` to announce itself, returning its address.
+async fn discover_addr(to: PeerId, timeout: Duration) -> Result<SocketAddr, Box<dyn Error>> {
+    let discovery = Arc::new(DiscoveryService::observer().await?);
+    let mut events = discovery.subscribe();
+    tokio::spawn(discovery.clone().start());
+    let found = tokio::time::timeout(timeout, async {
+        loop {
+            match events.recv().await {
+                Ok(DiscoveryEvent::PeerDiscovered(peer) | DiscoveryEvent::PeerUpdated(peer))
+                    if peer.peer_id == to =>
+                {
+                    return Some(peer.addr);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .await;
+    match found {
+        Ok(Some(addr)) => Ok(addr),
+        _ => Err(format!("peer {to} not discovered within {timeout:?}").into()),
+    }
+}
+
+/// Announce a peer with no capacity on offer, so agents never propose deals
+/// to it, and save incoming files into `args.dir`.
+async fn recv(args: RecvArgs) -> Result<(), Box<dyn Error>> {
+    let peer_info = PeerInfo {
+        addr: args.addr,
+        peer_id: args.peer_id.unwrap_or_else(PeerId::random),
+        spare_mbs: 0,
+        price: 0.0,
+        egress_price_per_gib: 0.0,
+        storage_offers: vec![],
+        burst_mbs: 0,
+        burst_price: 0.0,
+        features: Features::default(),
+        metadata: Default::default(),
+        region: None,
+        online_since: None,
+        capabilities: Capabilities::default(),
+    };
+    let (endpoint, cert) = open_file_receiver(args.addr, &EndpointLimits::default())?;
+    let discovery = Arc::new(DiscoveryService::new(peer_info.clone()).await?);
+    tokio::spawn(discovery.start());
+    eprintln!(
+        "receiving as {} on {} with certificate {cert}",
+        peer_info.peer_id,
+        endpoint.local_addr()?
+    );
+
+    let max_bytes = args.max_mbs * BYTES_PER_MEBIBYTE;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    loop {
+        let incoming = tokio::select! {
+            incoming = next_incoming(&endpoint) => incoming?,
+            _ = sigterm.recv() => return Ok(()),
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        match receive_file(incoming, &args.dir, max_bytes).await {
+            Ok(received) => {
+                println!("{}", serde_json::to_string(&received)?);
+                if !args.keep {
+                    endpoint.wait_idle().await;
+                    return Ok(());
+                }
+            }
+            Err(e) => eprintln!("transfer failed: {e:#}"),
+        }
+    }
+}
+
+/// Result of `replay`, printed as JSON.
+#[derive(Serialize)]
+struct ReplayReport {
+    stats: ReplayStats,
+    /// recording lines skipped because they didn't parse
+    unreadable: usize,
+    proposals: HashMap<String, Deal>,
+    peers: PeerSnapshot,
+    health: Health,
+}
+
+/// Replay `file` into an agent that neither announces nor accepts
+/// connections, so a recording can be inspected on any machine.
+async fn replay(file: &Path) -> Result<(), Box<dyn Error>> {
+    let recording = read_recording(file)?;
+    let addr = "127.0.0.1:0".parse()?;
+    let peer_info = PeerInfo {
+        addr,
+        peer_id: PeerId::random(),
+        spare_mbs: 0,
+        price: 0.0,
+        egress_price_per_gib: 0.0,
+        storage_offers: vec![],
+        burst_mbs: 0,
+        burst_price: 0.0,
+        features: Features::SUPPORTED,
+        metadata: Default::default(),
+        region: None,
+        online_since: None,
+        capabilities: Capabilities::default(),
+    };
+    let discovery = DiscoveryService::offline(peer_info).await?;
+    let agent = Agent::with_listen_addr(discovery, addr).await?;
+    let stats = agent.replay(recording.records).await;
+    print_json(&ReplayReport {
+        stats,
+        unreadable: recording.unreadable,
+        proposals: agent.incoming_deals().await,
+        peers: agent.discovery().export_snapshot().await,
+        health: agent.health().await,
+    });
+    Ok(())
+}
+
+/// Print a [`PeerSnapshot`] of
+/// the observed network, or its `NetworkStats` with `stats`, every `interval`
+/// until SIGTERM or Ctrl-C.
+async fn observe(interval: Duration, stats: bool) -> Result<(), Box<dyn Error>> {
+    let discovery = Arc::new(DiscoveryService::observer().await?);
+    tokio::spawn(discovery.clone().start());
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let line = if stats {
+                    serde_json::to_string(&discovery.network_stats().await)?
+                } else {
+                    serde_json::to_string(&discovery.export_snapshot().await)?
+                };
+                // the reader (e.g. `| head`) went away
+                if writeln!(std::io::stdout(), "{line}").is_err() {
+                    return Ok(());
+                }
+            }
+            _ = sigterm.recv() => return Ok(()),
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Run(args) => run(*args).await?,
+        Command::Init(args) => init(*args).await?,
+        Command::Observe {
+            interval_secs,
+            stats,
+        } => observe(Duration::from_secs(interval_secs), stats).await?,
+        Command::Peer(args) => set_peer(*args)?,
+        Command::Replay { file } => replay(&file).await?,
+        Command::Send {
+            file,
+            to,
+            addr,
+            cert,
+            timeout_secs,
+        } => send_file_to(&file, to, addr, cert, Duration::from_secs(timeout_secs)).await?,
+        Command::Recv(args) => recv(*args).await?,
+        Command::Schema => print_json(&wire_schemas()),
+        Command::TestVectors => print_json(&serde_json::json!({
+            "PeerInfoWire": peer_info_wire_vectors(),
+            "Deal": deal_vectors(),
+            "Announcement": announcement_vectors(),
+        })),
+    }
+    Ok(())
 }
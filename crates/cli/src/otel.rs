@@ -0,0 +1,24 @@
+//! OTLP export of tracing spans, behind the `otel` feature.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+/// A layer exporting spans to the collector named by the standard
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` (default `http://localhost:4318`), and the
+/// provider to shut down on exit so buffered spans are flushed.
+pub fn layer<S>(
+) -> Result<(impl Layer<S>, SdkTracerProvider), opentelemetry_otlp::ExporterBuildError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("spare-net").build())
+        .build();
+    let tracer = provider.tracer("spare-net");
+    Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+}
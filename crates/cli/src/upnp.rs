@@ -0,0 +1,115 @@
+//! UPnP IGD port mapping for providers behind home routers, behind the
+//! `upnp` feature.
+
+use igd_next::{
+    aio::{tokio::Tokio, Gateway},
+    PortMappingProtocol, SearchOptions,
+};
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Lease requested from the gateway; renewed at half this interval.
+const LEASE_SECS: u32 = 3600;
+const DESCRIPTION: &str = "spare-net";
+
+/// A UDP port forwarded by the local gateway to the QUIC listen port.
+pub struct PortMapping {
+    gateway: Gateway<Tokio>,
+    local: SocketAddr,
+    external: SocketAddr,
+}
+
+impl PortMapping {
+    /// Ask the gateway to forward `listen.port()` (UDP, for QUIC) to this
+    /// host. An unspecified listen IP maps to the interface facing the
+    /// gateway.
+    pub async fn create(listen: SocketAddr) -> Result<Self, Box<dyn Error>> {
+        if listen.port() == 0 {
+            return Err("port mapping needs a fixed listen port".into());
+        }
+        let gateway = igd_next::aio::tokio::search_gateway(SearchOptions::default()).await?;
+        let local_ip = if listen.ip().is_unspecified() {
+            ip_towards(gateway.addr)?
+        } else {
+            listen.ip()
+        };
+        let local = SocketAddr::new(local_ip, listen.port());
+        gateway
+            .add_port(
+                PortMappingProtocol::UDP,
+                listen.port(),
+                local,
+                LEASE_SECS,
+                DESCRIPTION,
+            )
+            .await?;
+        let external = SocketAddr::new(gateway.get_external_ip().await?, listen.port());
+        if !is_global(external.ip()) {
+            warn!(
+                "gateway reports private external address {external}; probably behind another NAT"
+            );
+        }
+        info!("gateway {} forwards {external} to {local}", gateway.addr);
+        Ok(PortMapping {
+            gateway,
+            local,
+            external,
+        })
+    }
+
+    /// Address to advertise to peers.
+    pub fn external_addr(&self) -> SocketAddr {
+        self.external
+    }
+
+    /// Renew the lease before it lapses; runs until the task is dropped.
+    pub async fn keep_alive(&self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(LEASE_SECS as u64 / 2));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self
+                .gateway
+                .add_port(
+                    PortMappingProtocol::UDP,
+                    self.external.port(),
+                    self.local,
+                    LEASE_SECS,
+                    DESCRIPTION,
+                )
+                .await
+            {
+                warn!("failed to renew port mapping: {e}");
+            }
+        }
+    }
+
+    /// Remove the mapping from the gateway.
+    pub async fn remove(&self) {
+        if let Err(e) = self
+            .gateway
+            .remove_port(PortMappingProtocol::UDP, self.external.port())
+            .await
+        {
+            warn!("failed to remove port mapping: {e}");
+        }
+    }
+}
+
+/// Local address the OS would use to reach `peer`.
+fn ip_towards(peer: SocketAddr) -> std::io::Result<IpAddr> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => !(v4.is_private() || v4.is_loopback() || v4.is_link_local()),
+        IpAddr::V6(v6) => !v6.is_loopback(),
+    }
+}
@@ -0,0 +1,122 @@
+//! Watch-directory mode: propose a deal for every new or changed file.
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use sparenet_agent::{
+    agent::Agent,
+    deal::{Deal, Priority},
+    discovery::Discovery,
+    peer_info::{PeerInfoWire, StorageClass},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+/// Terms applied to every deal proposed for a watched file.
+#[derive(Debug, Clone)]
+pub struct DealTemplate {
+    pub price_per_mb: f32,
+    pub storage_class: Option<StorageClass>,
+    pub labels: Vec<String>,
+    pub burst: bool,
+    /// log the placement and cost each file would get instead of queuing it
+    pub dry_run: bool,
+}
+
+impl DealTemplate {
+    fn deal_for<D: Discovery>(&self, agent: &Agent<D>, file_len: u64) -> Deal {
+        Deal {
+            peer_info_wire: PeerInfoWire::from(agent.get_peer_info()),
+            file_len,
+            price_per_mb: self.price_per_mb,
+            storage_class: self.storage_class,
+            deadline_secs: None,
+            redundancy: None,
+            labels: self.labels.clone(),
+            burst: self.burst,
+            // archiving is bulk work; let urgent deals go first
+            priority: Priority::Low,
+        }
+    }
+}
+
+/// Watch `dir` recursively and queue a deal for each file whose size or
+/// modification time changed since it was last proposed. Runs until the
+/// watcher fails.
+pub async fn watch<D: Discovery>(
+    agent: Arc<Agent<D>>,
+    dir: PathBuf,
+    template: DealTemplate,
+) -> notify::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+    info!("watching {} for new files", dir.display());
+
+    // last (len, mtime) proposed per path, so repeated modify events for one
+    // write produce a single deal
+    let mut proposed: HashMap<PathBuf, (u64, Option<SystemTime>)> = HashMap::new();
+    while let Some(res) = rx.recv().await {
+        let event = res?;
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            continue;
+        }
+        for path in event.paths {
+            propose(&agent, &template, &mut proposed, &path).await;
+        }
+    }
+    Ok(())
+}
+
+async fn propose<D: Discovery>(
+    agent: &Agent<D>,
+    template: &DealTemplate,
+    proposed: &mut HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    path: &Path,
+) {
+    let meta = match tokio::fs::metadata(path).await {
+        // a freshly created file is usually still empty; wait for its write
+        Ok(meta) if meta.is_file() && meta.len() > 0 => meta,
+        Ok(_) => return,
+        Err(e) => {
+            debug!("skipping {}: {e}", path.display());
+            return;
+        }
+    };
+    let version = (meta.len(), meta.modified().ok());
+    if proposed.get(path) == Some(&version) {
+        return;
+    }
+    proposed.insert(path.to_path_buf(), version);
+
+    let deal = template.deal_for(agent, meta.len());
+    if !template.dry_run {
+        agent.queue_deal(deal);
+        info!("queued {} ({} bytes)", path.display(), meta.len());
+        return;
+    }
+    match agent.simulate_deal(&deal).await {
+        Ok(plan) => {
+            let peers: Vec<_> = plan
+                .placement
+                .assignments
+                .iter()
+                .map(|(peer, _)| peer.peer_id.to_string())
+                .collect();
+            info!(
+                "dry run: {} ({} bytes) would go to [{}] for {}",
+                path.display(),
+                meta.len(),
+                peers.join(", "),
+                plan.total_cost
+            );
+        }
+        Err(e) => info!("dry run: {} would not be placed: {e}", path.display()),
+    }
+}
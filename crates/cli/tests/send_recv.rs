@@ -0,0 +1,86 @@
+//! `spare-net send` and `spare-net recv` against each other, as released:
+//! the sender trusts the receiver through the fingerprint `recv` prints,
+//! with no trust store or test-only certificate verifier involved.
+
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const BIN: &str = env!("CARGO_BIN_EXE_spare-net");
+
+fn send(file: &Path, addr: &str, cert: &str) -> Output {
+    Command::new(BIN)
+        .args([
+            "send",
+            file.to_str().unwrap(),
+            "--addr",
+            addr,
+            "--cert",
+            cert,
+        ])
+        .output()
+        .unwrap()
+}
+
+/// wait up to `timeout` for `child` to exit, killing it if it doesn't
+fn wait(child: &mut Child, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status.success();
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = child.kill();
+    false
+}
+
+#[test]
+/// a file sent to the printed address and fingerprint arrives; a sender
+/// pinning any other certificate is refused
+fn file_reaches_pinned_receiver() {
+    let dir = std::env::temp_dir().join(format!("spare-net-send-recv-{}", std::process::id()));
+    let inbox = dir.join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    let file = dir.join("notes.txt");
+    std::fs::write(&file, b"shared over QUIC").unwrap();
+
+    let mut recv = Command::new(BIN)
+        .args([
+            "recv",
+            "--addr",
+            "127.0.0.1:0",
+            "--dir",
+            inbox.to_str().unwrap(),
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(recv.stderr.take().unwrap());
+    let mut banner = String::new();
+    stderr.read_line(&mut banner).unwrap();
+    // keep draining so later diagnostics don't hit a closed pipe
+    thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+    // receiving as <peer id> on <addr> with certificate <fingerprint>
+    let words: Vec<_> = banner.split_whitespace().collect();
+    let (addr, cert) = match words.as_slice() {
+        ["receiving", "as", _, "on", addr, "with", "certificate", cert] => (*addr, *cert),
+        _ => panic!("unexpected banner {banner:?}"),
+    };
+
+    let wrong = "0".repeat(64);
+    assert!(!send(&file, addr, &wrong).status.success());
+    let sent = send(&file, addr, cert);
+    assert!(sent.status.success(), "{sent:?}");
+    assert!(wait(&mut recv, Duration::from_secs(10)));
+    assert_eq!(
+        std::fs::read(inbox.join("notes.txt")).unwrap(),
+        b"shared over QUIC"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}